@@ -1,7 +1,10 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 
+use crate::image_modifier::watermark::Watermark;
+
 #[derive(Deserialize)]
 pub enum StorageType {
   Local,
@@ -12,6 +15,12 @@ pub enum StorageType {
 pub struct Config {
   pub app: AppConfig,
   pub storage: StorageConfig,
+  pub jobs: Option<JobsConfig>,
+  pub callbacks: Option<CallbackConfig>,
+  pub batch: Option<BatchConfig>,
+  pub processing: Option<ProcessingConfig>,
+  /// Watermarks `/scale` can stamp with a `wm<name>` option, by name
+  pub watermarks: Option<HashMap<String, Watermark>>,
 }
 
 #[derive(Deserialize)]
@@ -22,6 +31,75 @@ pub struct AppConfig {
   pub api_key: String,
   pub max_body_size_mb: usize,
   pub enable_openapi: Option<bool>,
+  /// Reject process-image requests containing unknown fields
+  pub deny_unknown_fields: Option<bool>,
+  pub timeouts: Option<TimeoutConfig>,
+  /// Hosts process-image sources may be fetched from by URL, e.g.
+  /// `images.example.com` or `*.example.com`
+  pub allowed_source_hosts: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct TimeoutConfig {
+  /// Default timeout for a whole request, used for routes without an override
+  pub request_secs: Option<u64>,
+  /// Per-route request timeouts keyed by route path, e.g. `/api/v1/process-image`
+  pub routes: Option<HashMap<String, u64>>,
+  pub storage_download_secs: Option<u64>,
+  pub processing_secs: Option<u64>,
+  pub storage_upload_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum JobStoreType {
+  #[default]
+  Memory,
+}
+
+#[derive(Deserialize, Default)]
+pub struct JobsConfig {
+  pub store_type: Option<JobStoreType>,
+  /// How long finished jobs are kept around for polling
+  pub ttl_secs: Option<u64>,
+  /// Jobs processed at the same time, others wait queued
+  pub max_concurrent: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct CallbackConfig {
+  /// Attempts per callback, including the first
+  pub max_attempts: Option<u32>,
+  /// Delay before the first retry, doubled for every following retry
+  pub initial_backoff_ms: Option<u64>,
+  pub max_backoff_ms: Option<u64>,
+  /// Timeout of a single attempt
+  pub timeout_secs: Option<u64>,
+  /// Hosts callback URLs may point to, e.g. `hooks.example.com` or
+  /// `*.example.com` for any subdomain
+  pub allowed_hosts: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct BatchConfig {
+  /// Sources of a batch processed at the same time
+  pub concurrency: Option<usize>,
+  /// Maximum number of sources in a batch
+  pub max_sources: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ProcessingConfig {
+  /// Configurations of a request rendered at the same time
+  pub render_concurrency: Option<usize>,
+  /// Images of a request uploaded at the same time
+  pub upload_concurrency: Option<usize>,
+  /// Maximum size of the images returned inline by a dry run
+  pub dry_run_max_mb: Option<usize>,
+  /// Memory for decoded overlay images shared by every request
+  pub overlay_cache_mb: Option<usize>,
+  /// Skip uploads of identical objects at any path, not only at paths
+  /// including `{hash}` or `{hash8}`
+  pub deduplicate_uploads: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use axum::{Json, extract::State};
+use hmac::{Hmac, Mac};
+use reqwest::redirect;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{info, warn};
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::config::CallbackConfig;
+use crate::http::AppState;
+use crate::http::error::ErrorResponse;
+use crate::http::job_store::{JobStatus, unix_now};
+use crate::http::request_id;
+use crate::http::source::AllowedHosts;
+use crate::image_processing::ProcessedImage;
+use crate::image_processing::report::ConfigurationStatus;
+
+pub const X_SIGNATURE: &str = "X-Rusty-Pixel-Signature";
+/// Unix timestamp in seconds of the attempt, covered by the signature
+pub const X_TIMESTAMP: &str = "X-Rusty-Pixel-Timestamp";
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+/// Number of failed deliveries kept for inspection
+const MAX_FAILED_DELIVERIES: usize = 100;
+
+/// Body POSTed to the `callback_url` of a job once it has finished
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct CallbackPayload {
+  pub job_id: String,
+  /// Id of the processed source image
+  pub id: String,
+  pub status: JobStatus,
+  pub images: Option<Vec<ProcessedImage>>,
+  pub configurations: Option<Vec<ConfigurationStatus>>,
+  pub error: Option<ErrorResponse>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+  Pending,
+  Delivered,
+  Failed,
+}
+
+/// Delivery state of the callback of a job
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct CallbackDelivery {
+  pub url: String,
+  pub status: DeliveryStatus,
+  pub attempts: u32,
+  pub last_error: Option<String>,
+}
+
+impl CallbackDelivery {
+  pub fn pending(url: &str) -> Self {
+    Self {
+      url: url.to_owned(),
+      status: DeliveryStatus::Pending,
+      attempts: 0,
+      last_error: None,
+    }
+  }
+}
+
+/// A callback that couldn't be delivered within the allowed attempts
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct FailedDelivery {
+  pub url: String,
+  pub attempts: u32,
+  pub last_error: Option<String>,
+  /// Unix timestamp in seconds
+  pub failed_at: u64,
+  pub payload: CallbackPayload,
+}
+
+/// Where to deliver the outcome of a job, taken from the request
+pub struct Callback {
+  pub url: String,
+  pub secret: Option<String>,
+}
+
+/// Delivers callbacks, retrying with exponential backoff
+pub struct Dispatcher {
+  client: reqwest::Client,
+  allowed_hosts: AllowedHosts,
+  max_attempts: u32,
+  initial_backoff: Duration,
+  max_backoff: Duration,
+  failed: Mutex<VecDeque<FailedDelivery>>,
+}
+
+impl Dispatcher {
+  pub fn from_config(cfg: &CallbackConfig) -> Result<Self> {
+    let client = reqwest::Client::builder()
+      .timeout(Duration::from_secs(
+        cfg.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+      ))
+      // A redirect could lead to a host that isn't allowed
+      .redirect(redirect::Policy::none())
+      .user_agent(concat!("rusty-pixel/", env!("CARGO_PKG_VERSION")))
+      .build()?;
+
+    Ok(Self {
+      client,
+      allowed_hosts: AllowedHosts::new(cfg.allowed_hosts.clone().unwrap_or_default()),
+      max_attempts: cfg.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+      initial_backoff: Duration::from_millis(
+        cfg.initial_backoff_ms.unwrap_or(DEFAULT_INITIAL_BACKOFF_MS),
+      ),
+      max_backoff: Duration::from_millis(cfg.max_backoff_ms.unwrap_or(DEFAULT_MAX_BACKOFF_MS)),
+      failed: Mutex::new(VecDeque::new()),
+    })
+  }
+
+  /// Whether callbacks may be delivered to the URL
+  pub fn allows(&self, url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| self.allowed_hosts.allows(&url))
+  }
+
+  /// POST the payload to the callback, retrying any failure or non-2xx
+  /// response until `max_attempts` is reached
+  pub async fn deliver(&self, callback: &Callback, payload: CallbackPayload) -> CallbackDelivery {
+    let mut delivery = CallbackDelivery::pending(&callback.url);
+
+    let body = match serde_json::to_vec(&payload) {
+      Ok(body) => body,
+      Err(e) => {
+        delivery.status = DeliveryStatus::Failed;
+        delivery.last_error = Some(e.to_string());
+        self.record_failure(&delivery, payload);
+        return delivery;
+      }
+    };
+    while delivery.attempts < self.max_attempts {
+      if delivery.attempts > 0 {
+        tokio::time::sleep(self.backoff(delivery.attempts)).await;
+      }
+      delivery.attempts += 1;
+
+      match self
+        .send(&callback.url, &body, callback.secret.as_deref())
+        .await
+      {
+        Ok(()) => {
+          info!(
+            "delivered callback for job {} to {}",
+            payload.job_id, callback.url
+          );
+          delivery.status = DeliveryStatus::Delivered;
+          delivery.last_error = None;
+          return delivery;
+        }
+        Err(e) => {
+          warn!(
+            "callback attempt {} for job {} to {} failed: {:#}",
+            delivery.attempts, payload.job_id, callback.url, e
+          );
+          delivery.last_error = Some(e.to_string());
+        }
+      }
+    }
+
+    delivery.status = DeliveryStatus::Failed;
+    metrics::counter!("callback_failures_total").increment(1);
+    self.record_failure(&delivery, payload);
+
+    delivery
+  }
+
+  /// The most recent failed deliveries, oldest first
+  pub fn failed(&self) -> Vec<FailedDelivery> {
+    let failed = self.failed.lock().unwrap_or_else(|e| e.into_inner());
+    failed.iter().cloned().collect()
+  }
+
+  async fn send(&self, url: &str, body: &[u8], secret: Option<&str>) -> Result<()> {
+    // Every attempt is signed anew, so receivers can reject old callbacks
+    let timestamp = unix_now();
+    let mut request = self
+      .client
+      .post(url)
+      .header(reqwest::header::CONTENT_TYPE, "application/json")
+      .header(X_TIMESTAMP, timestamp)
+      .body(body.to_vec());
+    if let Some(secret) = secret {
+      request = request.header(X_SIGNATURE, sign(secret.as_bytes(), timestamp, body));
+    }
+    if let Some(id) = request_id::current() {
+      request = request.header(request_id::X_REQUEST_ID, id);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+      return Err(anyhow!("receiver responded with {}", response.status()));
+    }
+
+    Ok(())
+  }
+
+  /// Delay before the given retry, doubling from `initial_backoff`
+  fn backoff(&self, retry: u32) -> Duration {
+    let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+    self
+      .initial_backoff
+      .saturating_mul(factor)
+      .min(self.max_backoff)
+  }
+
+  fn record_failure(&self, delivery: &CallbackDelivery, payload: CallbackPayload) {
+    let mut failed = self.failed.lock().unwrap_or_else(|e| e.into_inner());
+    if failed.len() >= MAX_FAILED_DELIVERIES {
+      failed.pop_front();
+    }
+    failed.push_back(FailedDelivery {
+      url: delivery.url.clone(),
+      attempts: delivery.attempts,
+      last_error: delivery.last_error.clone(),
+      failed_at: unix_now(),
+      payload,
+    });
+  }
+}
+
+/// Hex encoded HMAC-SHA256 of the timestamp and the body joined by a `.`,
+/// prefixed with `sha256=`
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take a key of any size");
+  mac.update(format!("{}.", timestamp).as_bytes());
+  mac.update(body);
+
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/v1/callbacks/failed",
+  responses(
+    (status = 200, description = "Most recent callbacks that couldn't be delivered", body = [FailedDelivery]),
+    (status = 401, description = "Unauthorized - invalid API key", body = ErrorResponse)
+  ),
+  security(("api_key" = []))
+)]
+pub async fn failed_callbacks(State(state): State<AppState>) -> Json<Vec<FailedDelivery>> {
+  Json(state.callbacks.failed())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn payload() -> CallbackPayload {
+    CallbackPayload {
+      job_id: "job".to_owned(),
+      id: "source".to_owned(),
+      status: JobStatus::Completed,
+      images: Some(Vec::new()),
+      configurations: Some(Vec::new()),
+      error: None,
+    }
+  }
+
+  #[test]
+  fn sign_timestamp_and_body() {
+    assert_eq!(
+      sign(
+        b"key",
+        1_700_000_000,
+        b"The quick brown fox jumps over the lazy dog"
+      ),
+      "sha256=2f658d6aef4f246e91cd741bbcded7479e9605f9d41c9e248122a117e0e1765b"
+    );
+  }
+
+  #[test]
+  fn allow_only_configured_hosts() {
+    let dispatcher = Dispatcher::from_config(&CallbackConfig {
+      allowed_hosts: Some(vec!["hooks.example.com".to_owned()]),
+      ..CallbackConfig::default()
+    })
+    .unwrap();
+
+    assert!(dispatcher.allows("https://hooks.example.com/done"));
+    assert!(!dispatcher.allows("http://169.254.169.254/latest/meta-data"));
+    assert!(!dispatcher.allows("ftp://hooks.example.com/done"));
+    assert!(!dispatcher.allows("not a url"));
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_max() {
+    let dispatcher = Dispatcher::from_config(&CallbackConfig {
+      initial_backoff_ms: Some(100),
+      max_backoff_ms: Some(500),
+      ..CallbackConfig::default()
+    })
+    .unwrap();
+
+    assert_eq!(dispatcher.backoff(1), Duration::from_millis(100));
+    assert_eq!(dispatcher.backoff(2), Duration::from_millis(200));
+    assert_eq!(dispatcher.backoff(3), Duration::from_millis(400));
+    assert_eq!(dispatcher.backoff(4), Duration::from_millis(500));
+  }
+
+  #[tokio::test]
+  async fn undeliverable_callback_is_recorded() {
+    let dispatcher = Dispatcher::from_config(&CallbackConfig {
+      max_attempts: Some(2),
+      initial_backoff_ms: Some(1),
+      ..CallbackConfig::default()
+    })
+    .unwrap();
+
+    // Nothing listens on a port that was just released
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let callback = Callback {
+      url: format!("http://{}/hook", addr),
+      secret: None,
+    };
+    let delivery = dispatcher.deliver(&callback, payload()).await;
+
+    assert_eq!(delivery.status, DeliveryStatus::Failed);
+    assert_eq!(delivery.attempts, 2);
+    assert!(delivery.last_error.is_some());
+
+    let failed = dispatcher.failed();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].payload.job_id, "job");
+  }
+}
@@ -1,27 +1,194 @@
 use axum::{
+  Json,
   http::StatusCode,
   response::{IntoResponse, Response},
 };
+use serde::Serialize;
+use serde_json::json;
 use thiserror::Error;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::http::request_id;
+use crate::http::timeout::Phase;
+use crate::image_processing::report::ConfigurationStatus;
+use crate::image_processing::validation::FieldError;
 
 #[derive(Error, Debug)]
 pub enum AppError {
   #[error("bad request {0}")]
   BadRequest(String),
-  #[error("not found")]
-  NotFound,
+  #[error("invalid option {0}")]
+  InvalidOption(String),
+  #[error("validation failed {0:?}")]
+  Validation(Vec<FieldError>),
+  #[error("unauthorized")]
+  Unauthorized,
+  #[error("source not found {0}")]
+  SourceNotFound(String),
+  #[error("failed to fetch source {0}")]
+  SourceFetchFailed(String),
+  #[error("source larger than {0} bytes")]
+  SourceTooLarge(usize),
+  #[error("environment image not found {0}")]
+  EnvironmentNotFound(String),
+  #[error("watermark not found {0}")]
+  WatermarkNotFound(String),
+  #[error("failed to decode image {0}")]
+  DecodeFailed(String),
+  #[error("image too small {width}x{height}, minimum {min_size}")]
+  ImageTooSmall {
+    width: i32,
+    height: i32,
+    min_size: i32,
+  },
+  #[error("job not found {0}")]
+  JobNotFound(String),
+  #[error("job {0} has already finished")]
+  JobFinished(String),
+  #[error("job {0} was cancelled")]
+  JobCancelled(String),
+  #[error("dry run images exceed {0} bytes")]
+  DryRunTooLarge(usize),
+  #[error("processing failed {0:?}")]
+  ProcessingFailed(Vec<ConfigurationStatus>),
   #[error("internal server error {0}")]
   InternalServerError(String),
+  #[error("request timed out")]
+  RequestTimeout,
+  #[error("{} timed out", .0.as_str())]
+  Timeout(Phase),
 }
 
-impl IntoResponse for AppError {
-  fn into_response(self) -> Response {
+/// Error body returned by every endpoint
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ErrorResponse {
+  /// Machine-readable error code, e.g. `invalid_option` or `source_not_found`
+  pub code: String,
+  pub message: String,
+  pub request_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[schema(value_type = Option<Object>)]
+  pub details: Option<serde_json::Value>,
+}
+
+impl AppError {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      AppError::BadRequest(_) | AppError::InvalidOption(_) => StatusCode::BAD_REQUEST,
+      AppError::DecodeFailed(_) | AppError::ImageTooSmall { .. } => StatusCode::BAD_REQUEST,
+      AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+      AppError::SourceNotFound(_)
+      | AppError::EnvironmentNotFound(_)
+      | AppError::WatermarkNotFound(_) => StatusCode::NOT_FOUND,
+      AppError::SourceFetchFailed(_) => StatusCode::BAD_GATEWAY,
+      AppError::SourceTooLarge(_) | AppError::DryRunTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+      AppError::JobNotFound(_) => StatusCode::NOT_FOUND,
+      AppError::JobFinished(_) | AppError::JobCancelled(_) => StatusCode::CONFLICT,
+      AppError::ProcessingFailed(_) | AppError::InternalServerError(_) => {
+        StatusCode::INTERNAL_SERVER_ERROR
+      }
+      AppError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+      AppError::Timeout(Phase::Processing) => StatusCode::SERVICE_UNAVAILABLE,
+      AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+    }
+  }
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      AppError::BadRequest(_) => "bad_request",
+      AppError::InvalidOption(_) => "invalid_option",
+      AppError::Validation(_) => "validation_failed",
+      AppError::Unauthorized => "unauthorized",
+      AppError::SourceNotFound(_) => "source_not_found",
+      AppError::SourceFetchFailed(_) => "source_fetch_failed",
+      AppError::SourceTooLarge(_) => "source_too_large",
+      AppError::EnvironmentNotFound(_) => "environment_not_found",
+      AppError::WatermarkNotFound(_) => "watermark_not_found",
+      AppError::DecodeFailed(_) => "decode_failed",
+      AppError::ImageTooSmall { .. } => "image_too_small",
+      AppError::JobNotFound(_) => "job_not_found",
+      AppError::JobFinished(_) => "job_finished",
+      AppError::JobCancelled(_) => "job_cancelled",
+      AppError::DryRunTooLarge(_) => "dry_run_too_large",
+      AppError::ProcessingFailed(_) => "processing_failed",
+      AppError::InternalServerError(_) => "internal_error",
+      AppError::RequestTimeout => "request_timeout",
+      AppError::Timeout(Phase::StorageDownload) => "storage_download_timeout",
+      AppError::Timeout(Phase::Processing) => "processing_timeout",
+      AppError::Timeout(Phase::StorageUpload) => "storage_upload_timeout",
+    }
+  }
+
+  fn message(&self) -> String {
+    match self {
+      AppError::BadRequest(msg) | AppError::InvalidOption(msg) => msg.clone(),
+      AppError::Validation(_) => "Request validation failed".to_owned(),
+      AppError::Unauthorized => "Invalid or missing API key".to_owned(),
+      AppError::SourceNotFound(_) => "Source image not found".to_owned(),
+      AppError::SourceFetchFailed(_) => "Failed to fetch source image".to_owned(),
+      AppError::SourceTooLarge(_) => "Source image too large".to_owned(),
+      AppError::EnvironmentNotFound(_) => "Environment image not found".to_owned(),
+      AppError::WatermarkNotFound(_) => "Watermark image not found".to_owned(),
+      AppError::DecodeFailed(_) => "Failed to decode image".to_owned(),
+      AppError::ImageTooSmall { .. } => "Image too small".to_owned(),
+      AppError::JobNotFound(_) => "Job not found".to_owned(),
+      AppError::JobFinished(_) => "Job has already finished".to_owned(),
+      AppError::JobCancelled(_) => "Job was cancelled".to_owned(),
+      AppError::DryRunTooLarge(_) => "Dry run images too large to return inline".to_owned(),
+      AppError::ProcessingFailed(_) => {
+        "A configuration failed, uploaded images were removed".to_owned()
+      }
+      // Never expose internal details to the client
+      AppError::InternalServerError(_) => "Internal server error".to_owned(),
+      AppError::RequestTimeout => "Request timed out".to_owned(),
+      AppError::Timeout(Phase::StorageDownload) => "Storage download timed out".to_owned(),
+      AppError::Timeout(Phase::Processing) => "Image processing timed out".to_owned(),
+      AppError::Timeout(Phase::StorageUpload) => "Storage upload timed out".to_owned(),
+    }
+  }
+
+  fn details(&self) -> Option<serde_json::Value> {
     match self {
-      AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
-      AppError::NotFound => (StatusCode::NOT_FOUND, "Not Found").into_response(),
-      AppError::InternalServerError(_msg) => {
-        (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+      AppError::SourceNotFound(path)
+      | AppError::EnvironmentNotFound(path)
+      | AppError::WatermarkNotFound(path) => Some(json!({ "path": path })),
+      AppError::SourceFetchFailed(reason) => Some(json!({ "reason": reason })),
+      AppError::SourceTooLarge(max_bytes) | AppError::DryRunTooLarge(max_bytes) => {
+        Some(json!({ "max_bytes": max_bytes }))
       }
+      AppError::JobNotFound(id) | AppError::JobFinished(id) | AppError::JobCancelled(id) => {
+        Some(json!({ "job_id": id }))
+      }
+      AppError::Validation(errors) => Some(json!({ "errors": errors })),
+      AppError::ProcessingFailed(statuses) => Some(json!({ "configurations": statuses })),
+      AppError::DecodeFailed(reason) => Some(json!({ "reason": reason })),
+      AppError::ImageTooSmall {
+        width,
+        height,
+        min_size,
+      } => Some(json!({ "width": width, "height": height, "min_size": min_size })),
+      _ => None,
+    }
+  }
+
+  pub fn to_response_body(&self) -> ErrorResponse {
+    ErrorResponse {
+      code: self.code().to_owned(),
+      message: self.message(),
+      request_id: request_id::current(),
+      details: self.details(),
     }
   }
 }
+
+impl IntoResponse for AppError {
+  fn into_response(self) -> Response {
+    if let AppError::InternalServerError(msg) = &self {
+      error!("internal server error: {}", msg);
+    }
+
+    (self.status(), Json(self.to_response_body())).into_response()
+  }
+}
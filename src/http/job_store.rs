@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::http::callback::CallbackDelivery;
+use crate::http::error::ErrorResponse;
+use crate::image_processing::ProcessedImage;
+use crate::image_processing::report::ConfigurationStatus;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+  Queued,
+  Running,
+  Completed,
+  Failed,
+  Cancelled,
+}
+
+impl JobStatus {
+  pub fn is_finished(&self) -> bool {
+    matches!(
+      self,
+      JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+    )
+  }
+}
+
+/// A process-image request running in the background
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Job {
+  pub id: String,
+  pub status: JobStatus,
+  /// Number of configurations in the request
+  pub configuration_count: usize,
+  /// Number of images uploaded so far
+  pub uploaded: usize,
+  /// The processed images, once completed
+  pub result: Option<Vec<ProcessedImage>>,
+  /// Outcome of every configuration, once completed
+  pub configurations: Option<Vec<ConfigurationStatus>>,
+  /// The error, once failed
+  pub error: Option<ErrorResponse>,
+  /// Delivery of the callback, if the request has a `callback_url`
+  pub callback: Option<CallbackDelivery>,
+  /// Unix timestamp in seconds
+  pub created_at: u64,
+  /// Unix timestamp in seconds
+  pub updated_at: u64,
+}
+
+impl Job {
+  pub fn new(id: String, configuration_count: usize) -> Self {
+    let now = unix_now();
+
+    Self {
+      id,
+      status: JobStatus::Queued,
+      configuration_count,
+      uploaded: 0,
+      result: None,
+      configurations: None,
+      error: None,
+      callback: None,
+      created_at: now,
+      updated_at: now,
+    }
+  }
+}
+
+pub fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+#[async_trait]
+pub trait JobStore: Send + Sync {
+  async fn insert(&self, job: Job) -> Result<()>;
+
+  async fn get(&self, id: &str) -> Result<Option<Job>>;
+
+  /// Apply `change` to the job atomically and return it, `None` if there is
+  /// no such job. A finished or cancelled job keeps its status.
+  async fn modify(&self, id: &str, change: JobChange) -> Result<Option<Job>>;
+}
+
+pub type JobChange = Box<dyn FnOnce(&mut Job) + Send>;
+
+/// Keeps jobs in memory, dropping finished jobs once their TTL has passed
+pub struct InMemoryJobStore {
+  jobs: Mutex<HashMap<String, Job>>,
+  ttl: Duration,
+}
+
+impl InMemoryJobStore {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      jobs: Mutex::new(HashMap::new()),
+      ttl,
+    }
+  }
+}
+
+#[async_trait]
+impl JobStore for InMemoryJobStore {
+  async fn insert(&self, job: Job) -> Result<()> {
+    let mut jobs = self
+      .jobs
+      .lock()
+      .map_err(|_| anyhow!("job store poisoned"))?;
+
+    let expired_before = unix_now().saturating_sub(self.ttl.as_secs());
+    jobs.retain(|_, job| !job.status.is_finished() || job.updated_at >= expired_before);
+
+    jobs.insert(job.id.clone(), job);
+
+    Ok(())
+  }
+
+  async fn get(&self, id: &str) -> Result<Option<Job>> {
+    let jobs = self
+      .jobs
+      .lock()
+      .map_err(|_| anyhow!("job store poisoned"))?;
+
+    Ok(jobs.get(id).cloned())
+  }
+
+  async fn modify(&self, id: &str, change: JobChange) -> Result<Option<Job>> {
+    let mut jobs = self
+      .jobs
+      .lock()
+      .map_err(|_| anyhow!("job store poisoned"))?;
+
+    let Some(job) = jobs.get_mut(id) else {
+      return Ok(None);
+    };
+    let status = job.status;
+    change(job);
+    if status.is_finished() {
+      job.status = status;
+    }
+    job.updated_at = unix_now();
+
+    Ok(Some(job.clone()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn in_memory_store_expires_finished_jobs() {
+    let store = InMemoryJobStore::new(Duration::from_secs(60));
+
+    let mut finished = Job::new("finished".to_owned(), 1);
+    finished.status = JobStatus::Completed;
+    store.insert(finished).await.unwrap();
+    store
+      .insert(Job::new("running".to_owned(), 1))
+      .await
+      .unwrap();
+
+    // Backdate both jobs past the TTL, only the finished one should expire
+    for job in store.jobs.lock().unwrap().values_mut() {
+      job.updated_at = 0;
+    }
+    store.insert(Job::new("new".to_owned(), 1)).await.unwrap();
+
+    assert!(store.get("finished").await.unwrap().is_none());
+    assert!(store.get("running").await.unwrap().is_some());
+    assert!(store.get("new").await.unwrap().is_some());
+  }
+
+  #[tokio::test]
+  async fn modify_keeps_finished_status() {
+    let store = InMemoryJobStore::new(Duration::from_secs(60));
+    store.insert(Job::new("job".to_owned(), 2)).await.unwrap();
+
+    let job = store
+      .modify("job", Box::new(|job| job.status = JobStatus::Cancelled))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(job.status, JobStatus::Cancelled);
+
+    // A late progress report of the cancelled job doesn't make it run again
+    let job = store
+      .modify(
+        "job",
+        Box::new(|job| {
+          job.status = JobStatus::Running;
+          job.uploaded = 1;
+        }),
+      )
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!((job.status, job.uploaded), (JobStatus::Cancelled, 1));
+
+    assert!(
+      store
+        .modify("missing", Box::new(|_| {}))
+        .await
+        .unwrap()
+        .is_none()
+    );
+  }
+}
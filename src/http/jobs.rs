@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+  Json,
+  extract::{Path, State},
+};
+use serde::Serialize;
+use tokio::sync::{Notify, Semaphore};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::http::AppState;
+use crate::http::callback::{Callback, CallbackDelivery, CallbackPayload};
+use crate::http::error::{AppError, ErrorResponse};
+use crate::http::job_store::{Job, JobChange, JobStatus, JobStore};
+use crate::http::{process_image, request_id};
+use crate::image_processing::ImageProcessingRequest;
+
+/// Response to a process-image request submitted with `async=true`
+#[derive(Serialize, Debug, ToSchema)]
+pub struct JobAccepted {
+  pub job_id: String,
+  /// Where to poll for the status of the job
+  pub status_url: String,
+}
+
+/// Runs process-image requests in the background, keeping their state in a
+/// `JobStore`. At most `max_concurrent` jobs run at a time, the others stay
+/// queued until one has finished.
+pub struct JobManager {
+  store: Arc<dyn JobStore>,
+  workers: Arc<Semaphore>,
+  active: Mutex<HashMap<String, Arc<Cancellation>>>,
+}
+
+/// Signals the cancellation of a queued or running job
+#[derive(Default)]
+struct Cancellation {
+  /// Checked by the render between configurations
+  cancelled: Arc<AtomicBool>,
+  /// Wakes the job while it waits for a worker
+  queued: Notify,
+}
+
+impl Cancellation {
+  fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+    self.queued.notify_one();
+  }
+
+  fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+  }
+}
+
+/// Reports the progress of a running job to its store
+pub struct JobProgress {
+  store: Arc<dyn JobStore>,
+  id: String,
+  cancelled: Arc<AtomicBool>,
+}
+
+impl JobProgress {
+  /// Whether the job has been cancelled, rendering stops before the next
+  /// configuration
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+  }
+
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  /// Flag set once the job has been cancelled, for checking from the render
+  pub fn cancellation(&self) -> Arc<AtomicBool> {
+    self.cancelled.clone()
+  }
+
+  pub async fn uploaded(&self, uploaded: usize) {
+    let change: JobChange = Box::new(move |job| job.uploaded = uploaded);
+    if let Err(e) = self.store.modify(&self.id, change).await {
+      warn!("failed to update job {}: {:#}", self.id, e);
+    }
+  }
+}
+
+impl JobManager {
+  pub fn new(store: Arc<dyn JobStore>, max_concurrent: usize) -> Self {
+    Self {
+      store,
+      workers: Arc::new(Semaphore::new(max_concurrent.max(1))),
+      active: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Queue the request and process it in the background
+  pub async fn submit(
+    &self,
+    state: &AppState,
+    request: ImageProcessingRequest,
+    data: Arc<Vec<u8>>,
+  ) -> Result<JobAccepted, AppError> {
+    let id = Uuid::new_v4().to_string();
+    let mut job = Job::new(id.clone(), request.configurations.len());
+    job.callback = request
+      .callback_url
+      .as_deref()
+      .map(CallbackDelivery::pending);
+    self
+      .store
+      .insert(job)
+      .await
+      .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+    // Register the job before spawning it so it can't finish unregistered
+    let cancellation = Arc::new(Cancellation::default());
+    self
+      .active
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .insert(id.clone(), cancellation.clone());
+
+    let task_state = state.clone();
+    let task_id = id.clone();
+    tokio::spawn(request_id::scope(request_id::current(), async move {
+      run(task_state, task_id, request, data, cancellation).await;
+    }));
+
+    Ok(JobAccepted {
+      status_url: format!("/api/v1/jobs/{}", id),
+      job_id: id,
+    })
+  }
+
+  pub async fn get(&self, id: &str) -> Result<Job, AppError> {
+    self
+      .store
+      .get(id)
+      .await
+      .map_err(|e| AppError::InternalServerError(e.to_string()))?
+      .ok_or_else(|| AppError::JobNotFound(id.to_owned()))
+  }
+
+  /// Stop the job if it's still queued or running. A queued job leaves the
+  /// queue, a running one stops rendering and removes what it has uploaded.
+  pub async fn cancel(&self, id: &str) -> Result<Job, AppError> {
+    let job = self.get(id).await?;
+    if job.status.is_finished() {
+      return Err(AppError::JobFinished(id.to_owned()));
+    }
+
+    // Without an entry the job is just finishing, let it record its outcome
+    match self
+      .active
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .get(id)
+    {
+      Some(cancellation) => cancellation.cancel(),
+      None => return Err(AppError::JobFinished(id.to_owned())),
+    }
+
+    let job = self
+      .modify(id, Box::new(|job| job.status = JobStatus::Cancelled))
+      .await
+      .ok_or_else(|| AppError::JobNotFound(id.to_owned()))?;
+
+    Ok(job)
+  }
+
+  /// Forget the finished job, returning whether it was cancelled
+  fn finish(&self, id: &str) -> bool {
+    self
+      .active
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .remove(id)
+      .is_some_and(|cancellation| cancellation.is_cancelled())
+  }
+
+  async fn modify(&self, id: &str, change: JobChange) -> Option<Job> {
+    self
+      .store
+      .modify(id, change)
+      .await
+      .inspect_err(|e| error!("failed to update job {}: {:#}", id, e))
+      .ok()
+      .flatten()
+  }
+}
+
+async fn run(
+  state: AppState,
+  id: String,
+  mut request: ImageProcessingRequest,
+  data: Arc<Vec<u8>>,
+  cancellation: Arc<Cancellation>,
+) {
+  let jobs = state.jobs.clone();
+  let source_id = request.id.clone();
+  let callback = request.callback_url.take().map(|url| Callback {
+    url,
+    secret: request.callback_secret.take(),
+  });
+
+  // Stay queued until a worker is free or the job is cancelled
+  let worker = tokio::select! {
+    worker = jobs.workers.clone().acquire_owned() => worker.ok(),
+    _ = cancellation.queued.notified() => None,
+  };
+
+  let result = match worker {
+    Some(_worker) if !cancellation.is_cancelled() => {
+      jobs
+        .modify(&id, Box::new(|job| job.status = JobStatus::Running))
+        .await;
+
+      let progress = JobProgress {
+        store: jobs.store.clone(),
+        id: id.clone(),
+        cancelled: cancellation.cancelled.clone(),
+      };
+      process_image::process(
+        &state,
+        request,
+        data,
+        &process_image::OverlayImages::default(),
+        Some(&progress),
+      )
+      .await
+    }
+    _ => Err(AppError::JobCancelled(id.clone())),
+  };
+
+  // Deregister first so that a concurrent cancel either reaches the job now
+  // or fails because it has finished
+  let cancelled = jobs.finish(&id);
+
+  let change: JobChange = match result {
+    // Cancelled after the last configuration was rendered
+    Ok(report) if cancelled => {
+      process_image::remove_uploads(&state, &report.images).await;
+      Box::new(|job| job.status = JobStatus::Cancelled)
+    }
+    Err(_) if cancelled => Box::new(|job| job.status = JobStatus::Cancelled),
+    Ok(report) => Box::new(|job| {
+      job.status = JobStatus::Completed;
+      job.result = Some(report.images);
+      job.configurations = Some(report.configurations);
+    }),
+    Err(e) => {
+      if let AppError::InternalServerError(msg) = &e {
+        error!("job {} failed: {}", id, msg);
+      }
+      let error = e.to_response_body();
+      Box::new(|job| {
+        job.status = JobStatus::Failed;
+        job.error = Some(error);
+      })
+    }
+  };
+  let Some(job) = jobs.modify(&id, change).await else {
+    return;
+  };
+
+  if let Some(callback) = callback {
+    let payload = CallbackPayload {
+      job_id: id.clone(),
+      id: source_id,
+      status: job.status,
+      images: job.result.clone(),
+      configurations: job.configurations.clone(),
+      error: job.error.clone(),
+    };
+    let delivery = state.callbacks.deliver(&callback, payload).await;
+
+    jobs
+      .modify(&id, Box::new(|job| job.callback = Some(delivery)))
+      .await;
+  }
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/v1/jobs/{id}",
+  params(("id" = String, Path, description = "Job id")),
+  responses(
+    (status = 200, description = "Status, progress and result of the job", body = Job),
+    (status = 401, description = "Unauthorized - invalid API key", body = ErrorResponse),
+    (status = 404, description = "Job not found or expired", body = ErrorResponse)
+  ),
+  security(("api_key" = []))
+)]
+pub async fn get_job(
+  State(state): State<AppState>,
+  Path(id): Path<String>,
+) -> Result<Json<Job>, AppError> {
+  Ok(Json(state.jobs.get(&id).await?))
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/v1/jobs/{id}/cancel",
+  params(("id" = String, Path, description = "Job id")),
+  responses(
+    (status = 200, description = "Job cancelled", body = Job),
+    (status = 401, description = "Unauthorized - invalid API key", body = ErrorResponse),
+    (status = 404, description = "Job not found or expired", body = ErrorResponse),
+    (status = 409, description = "Job has already finished", body = ErrorResponse)
+  ),
+  security(("api_key" = []))
+)]
+pub async fn cancel_job(
+  State(state): State<AppState>,
+  Path(id): Path<String>,
+) -> Result<Json<Job>, AppError> {
+  Ok(Json(state.jobs.cancel(&id).await?))
+}
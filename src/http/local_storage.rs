@@ -1,10 +1,37 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-use crate::http::storage::{PutObjectOutput, Storage};
-use anyhow::{Context, Result};
+use crate::http::storage::{HeadObjectOutput, PutObjectOutput, Storage};
+use crate::image_processing::metadata::ObjectMetadata;
+use anyhow::{Context, Result, bail};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
 
+/// Suffix of the sidecar file holding the metadata of an object
+const METADATA_SUFFIX: &str = ".meta.json";
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+  mime: &'a str,
+  cache_control: &'a str,
+  content_disposition: Option<&'a str>,
+  storage_class: Option<&'a str>,
+  metadata: &'a HashMap<String, String>,
+}
+
+/// A sidecar read back when looking up an object
+#[derive(Deserialize, Default)]
+struct StoredSidecar {
+  mime: Option<String>,
+  cache_control: Option<String>,
+  content_disposition: Option<String>,
+  storage_class: Option<String>,
+  #[serde(default)]
+  metadata: HashMap<String, String>,
+}
+
 pub struct Client {
   path: PathBuf,
 }
@@ -13,12 +40,25 @@ impl Client {
   pub fn new(path: PathBuf) -> Self {
     Self { path }
   }
+
+  /// Path of the object, refusing keys that resolve outside the root
+  fn object_path(&self, key: &str) -> Result<PathBuf> {
+    let key_path = Path::new(key);
+    if !key_path
+      .components()
+      .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+      bail!("key outside of the storage root: {}", key);
+    }
+
+    Ok(self.path.join(key_path))
+  }
 }
 
 #[async_trait]
 impl Storage for Client {
   async fn download_object(&self, key: &str) -> Result<Vec<u8>> {
-    let file_path = &self.path.join(key);
+    let file_path = &self.object_path(key)?;
 
     let mut file = tokio::fs::File::open(&file_path)
       .await
@@ -33,10 +73,16 @@ impl Storage for Client {
     Ok(data)
   }
 
-  async fn upload_object(&self, data: Vec<u8>, key: &str, _mime: &str) -> Result<PutObjectOutput> {
+  async fn upload_object(
+    &self,
+    data: Vec<u8>,
+    key: &str,
+    mime: &str,
+    metadata: &ObjectMetadata,
+  ) -> Result<PutObjectOutput> {
     let size = data.len() as u64;
 
-    let file_path = &self.path.join(key);
+    let file_path = &self.object_path(key)?;
 
     tokio::fs::create_dir_all(
       file_path
@@ -50,10 +96,99 @@ impl Storage for Client {
       .await
       .with_context(|| format!("failed to write file: {}", key))?;
 
+    // Persist the metadata next to the object, as there is nowhere else to keep it
+    let sidecar = serde_json::to_vec_pretty(&Sidecar {
+      mime,
+      cache_control: metadata.cache_control(),
+      content_disposition: metadata.content_disposition.as_deref(),
+      storage_class: metadata.storage_class.as_deref(),
+      metadata: &metadata.user_metadata,
+    })?;
+    let mut sidecar_path = file_path.clone().into_os_string();
+    sidecar_path.push(METADATA_SUFFIX);
+    tokio::fs::write(&sidecar_path, sidecar)
+      .await
+      .with_context(|| format!("failed to write metadata file: {}", key))?;
+
     Ok(PutObjectOutput {
-      etag: "".to_owned(),
       url: "".to_owned(),
       size,
     })
   }
+
+  async fn delete_object(&self, key: &str) -> Result<()> {
+    let file_path = self.object_path(key)?;
+
+    tokio::fs::remove_file(&file_path)
+      .await
+      .with_context(|| format!("failed to remove file: {}", key))?;
+
+    // The sidecar may be missing for objects written before it existed
+    let mut sidecar_path = file_path.into_os_string();
+    sidecar_path.push(METADATA_SUFFIX);
+    match tokio::fs::remove_file(&sidecar_path).await {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+        Err(e).with_context(|| format!("failed to remove metadata file: {}", key))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  async fn head_object(&self, key: &str) -> Result<Option<HeadObjectOutput>> {
+    let file_path = self.object_path(key)?;
+
+    let file = match tokio::fs::metadata(&file_path).await {
+      Ok(file) => file,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e).with_context(|| format!("failed to read file metadata: {}", key)),
+    };
+
+    // Objects written before sidecars existed have no metadata
+    let mut sidecar_path = file_path.into_os_string();
+    sidecar_path.push(METADATA_SUFFIX);
+    let sidecar = match tokio::fs::read(&sidecar_path).await {
+      Ok(sidecar) => serde_json::from_slice::<StoredSidecar>(&sidecar)
+        .with_context(|| format!("failed to parse metadata file: {}", key))?,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoredSidecar::default(),
+      Err(e) => return Err(e).with_context(|| format!("failed to read metadata file: {}", key)),
+    };
+
+    // Modification time and size stand in for an etag
+    let etag = file
+      .modified()
+      .ok()
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map(|modified| format!("{:x}-{:x}", modified.as_nanos(), file.len()));
+
+    Ok(Some(HeadObjectOutput {
+      url: "".to_owned(),
+      size: file.len(),
+      etag,
+      mime: sidecar.mime,
+      metadata: ObjectMetadata {
+        cache_control: sidecar.cache_control,
+        content_disposition: sidecar.content_disposition,
+        storage_class: sidecar.storage_class,
+        user_metadata: sidecar.metadata,
+      },
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn object_paths_stay_below_the_root() {
+    let client = Client::new(PathBuf::from("/data"));
+
+    assert_eq!(
+      client.object_path("output/a.png").unwrap(),
+      PathBuf::from("/data/output/a.png")
+    );
+    assert!(client.object_path("../secret.png").is_err());
+    assert!(client.object_path("output/../../secret.png").is_err());
+    assert!(client.object_path("/etc/passwd").is_err());
+  }
 }
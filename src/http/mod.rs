@@ -2,47 +2,77 @@ use anyhow::anyhow;
 use axum::{
   Router,
   extract::{DefaultBodyLimit, MatchedPath, Request, State},
-  http::StatusCode,
   middleware::{self, Next},
   response::{IntoResponse, Response},
   routing::{get, post},
 };
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::collections::HashMap;
 use std::future::ready;
+use std::time::Duration;
 use std::{path::Path, sync::Arc};
 use tokio::signal;
-use tokio::time::{Duration, Instant};
+use tokio::time::Instant;
 use tower_http::{
   catch_panic::CatchPanicLayer,
-  timeout::TimeoutLayer,
   trace::{self, TraceLayer},
 };
 use tracing::Level;
 use utoipa::OpenApi;
 use utoipa_redoc::{Redoc, Servable};
 
-use crate::config::{Config, StorageType};
+use crate::config::{Config, JobStoreType, StorageType};
+use crate::http::error::{AppError, ErrorResponse};
+use crate::image_modifier::adjust::ColourAdjustments;
+use crate::image_modifier::duotone::Duotone;
+use crate::image_modifier::environment::{
+  EnvironmentBlend, EnvironmentCorners, EnvironmentFit, EnvironmentShadow, Point,
+};
+use crate::image_modifier::sharpen::Sharpen;
+use crate::image_modifier::text::TextOverlay;
+use crate::image_modifier::watermark::{Gravity, Watermark};
 use crate::image_processing::{
   EnvironmentImage, ImageConditions, ImageConfiguration, ImageProcessingRequest, ProcessedImage,
+  batch::{BatchRequest, BatchSource},
+  environment::Orientation,
+  output::OutputFormat,
+  path_template::ExtensionPolicy,
+  report::{
+    ConfigurationOutcome, ConfigurationStatus, FailureMode, ProcessReport, ProcessResponse,
+  },
+  validation::{self, FieldError},
 };
 use anyhow::Result;
 use libvips::VipsApp;
 
+mod callback;
 mod error;
+mod job_store;
+mod jobs;
 mod local_storage;
+mod overlay_cache;
+mod process_batch;
 mod process_image;
+mod request_id;
 mod s3;
 mod scale_image;
+mod source;
 mod storage;
+mod timeout;
 
 #[derive(OpenApi)]
 #[openapi(
   paths(
     process_image::process_image,
+    process_image::process_image_source,
+    process_batch::process_batch,
+    jobs::get_job,
+    jobs::cancel_job,
+    callback::failed_callbacks,
     scale_image::scale
   ),
   components(
-    schemas(ImageProcessingRequest, ImageConfiguration, ImageConditions, EnvironmentImage, ProcessedImage)
+    schemas(ImageProcessingRequest, ImageConfiguration, ImageConditions, EnvironmentImage, ProcessedImage, OutputFormat, ExtensionPolicy, EnvironmentFit, EnvironmentBlend, EnvironmentShadow, EnvironmentCorners, Point, Orientation, Watermark, Gravity, TextOverlay, ColourAdjustments, Duotone, Sharpen, ErrorResponse, FieldError, jobs::JobAccepted, job_store::Job, job_store::JobStatus, callback::CallbackPayload, callback::CallbackDelivery, callback::DeliveryStatus, callback::FailedDelivery, process_batch::BatchResponse, process_batch::BatchResult, process_batch::BatchStatus, BatchRequest, BatchSource, FailureMode, ProcessReport, ProcessResponse, ConfigurationStatus, ConfigurationOutcome)
   ),
   modifiers(&SecurityAddon),
   info(
@@ -75,8 +105,28 @@ struct AppState {
   storage_client: Arc<dyn storage::Storage>,
   vips_app: Arc<VipsApp>,
   api_key: String,
+  timeouts: timeout::Timeouts,
+  deny_unknown_fields: bool,
+  jobs: Arc<jobs::JobManager>,
+  callbacks: Arc<callback::Dispatcher>,
+  sources: Arc<source::SourceFetcher>,
+  batch: process_batch::BatchLimits,
+  processing: process_image::ProcessingLimits,
+  overlays: Arc<overlay_cache::OverlayCache>,
+  watermarks: Arc<HashMap<String, Watermark>>,
 }
 
+const SCALE_ROUTE: &str = "/scale/{options}/{*uri}";
+const PROCESS_IMAGE_ROUTE: &str = "/api/v1/process-image";
+const PROCESS_IMAGE_SOURCE_ROUTE: &str = "/api/v1/process-image/source";
+const PROCESS_BATCH_ROUTE: &str = "/api/v1/process-batch";
+const JOB_ROUTE: &str = "/api/v1/jobs/{id}";
+const JOB_CANCEL_ROUTE: &str = "/api/v1/jobs/{id}/cancel";
+const FAILED_CALLBACKS_ROUTE: &str = "/api/v1/callbacks/failed";
+
+const DEFAULT_JOB_TTL_SECS: u64 = 3600;
+const DEFAULT_MAX_CONCURRENT_JOBS: usize = 2;
+
 const X_API_KEY: &str = "X-API-Key";
 
 async fn auth(State(state): State<AppState>, req: Request, next: Next) -> Response {
@@ -88,11 +138,11 @@ async fn auth(State(state): State<AppState>, req: Request, next: Next) -> Respon
   let auth_header = if let Some(auth_header) = auth_header {
     auth_header
   } else {
-    return StatusCode::UNAUTHORIZED.into_response();
+    return AppError::Unauthorized.into_response();
   };
 
   if !auth_header.eq(&state.api_key) {
-    return StatusCode::UNAUTHORIZED.into_response();
+    return AppError::Unauthorized.into_response();
   }
 
   next.run(req).await
@@ -148,20 +198,107 @@ pub fn bootstrap(cfg: &Config) -> Result<Router> {
     }
   };
 
+  // Per-route request timeouts and per-phase budgets
+  let timeout_cfg = cfg.app.timeouts.clone().unwrap_or_default();
+  let route_timeout = |route: &str| {
+    middleware::from_fn_with_state(
+      timeout::route_timeout(&timeout_cfg, route),
+      timeout::enforce,
+    )
+  };
+
+  // Job store for asynchronous process-image requests
+  let jobs_cfg = cfg.jobs.as_ref();
+  let job_ttl = Duration::from_secs(
+    jobs_cfg
+      .and_then(|jobs| jobs.ttl_secs)
+      .unwrap_or(DEFAULT_JOB_TTL_SECS),
+  );
+  let job_store: Arc<dyn job_store::JobStore> = match jobs_cfg
+    .and_then(|jobs| jobs.store_type)
+    .unwrap_or_default()
+  {
+    JobStoreType::Memory => Arc::new(job_store::InMemoryJobStore::new(job_ttl)),
+  };
+
+  // Webhook callbacks for finished jobs
+  let callbacks = Arc::new(callback::Dispatcher::from_config(
+    &cfg.callbacks.clone().unwrap_or_default(),
+  )?);
+
+  // Sources referenced by storage key or URL, limited to the body size
+  let max_body_size = cfg.app.max_body_size_mb * 1000 * 1000;
+  let sources = Arc::new(source::SourceFetcher::new(
+    source::AllowedHosts::new(cfg.app.allowed_source_hosts.clone().unwrap_or_default()),
+    max_body_size,
+  )?);
+
+  // Named watermarks are stamped by /scale as configured, so they are
+  // checked like the watermarks of process-image configurations
+  let watermarks = cfg.watermarks.clone().unwrap_or_default();
+  validation::validate_watermarks(&watermarks).map_err(|errors| {
+    let errors: Vec<String> = errors
+      .iter()
+      .map(|error| format!("{} {}", error.field, error.message))
+      .collect();
+    anyhow!("invalid watermarks: {}", errors.join(", "))
+  })?;
+
   // App state
+  let processing_cfg = cfg.processing.clone().unwrap_or_default();
   let state = AppState {
     storage_client,
     vips_app,
     api_key: cfg.app.api_key.clone(),
+    timeouts: timeout::Timeouts::from_config(&timeout_cfg),
+    deny_unknown_fields: cfg.app.deny_unknown_fields.unwrap_or(false),
+    jobs: Arc::new(jobs::JobManager::new(
+      job_store,
+      jobs_cfg
+        .and_then(|jobs| jobs.max_concurrent)
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_JOBS),
+    )),
+    callbacks,
+    sources,
+    batch: process_batch::BatchLimits::from_config(&cfg.batch.clone().unwrap_or_default()),
+    processing: process_image::ProcessingLimits::from_config(&processing_cfg),
+    overlays: Arc::new(overlay_cache::OverlayCache::from_config(&processing_cfg)),
+    watermarks: Arc::new(watermarks),
   };
 
   // Routing
-  let public_app = Router::new().route("/scale/{options}/{*uri}", get(scale_image::scale));
+  let public_app = Router::new().route(
+    SCALE_ROUTE,
+    get(scale_image::scale).layer(route_timeout(SCALE_ROUTE)),
+  );
 
   let private_app = Router::new()
-    .route("/api/v1/process-image", post(process_image::process_image))
+    .route(
+      PROCESS_IMAGE_ROUTE,
+      post(process_image::process_image).layer(route_timeout(PROCESS_IMAGE_ROUTE)),
+    )
+    .route(
+      PROCESS_IMAGE_SOURCE_ROUTE,
+      post(process_image::process_image_source).layer(route_timeout(PROCESS_IMAGE_SOURCE_ROUTE)),
+    )
+    .route(
+      PROCESS_BATCH_ROUTE,
+      post(process_batch::process_batch).layer(route_timeout(PROCESS_BATCH_ROUTE)),
+    )
+    .route(
+      JOB_ROUTE,
+      get(jobs::get_job).layer(route_timeout(JOB_ROUTE)),
+    )
+    .route(
+      JOB_CANCEL_ROUTE,
+      post(jobs::cancel_job).layer(route_timeout(JOB_CANCEL_ROUTE)),
+    )
+    .route(
+      FAILED_CALLBACKS_ROUTE,
+      get(callback::failed_callbacks).layer(route_timeout(FAILED_CALLBACKS_ROUTE)),
+    )
     .layer((
-      DefaultBodyLimit::max(cfg.app.max_body_size_mb * 1000 * 1000),
+      DefaultBodyLimit::max(max_body_size),
       middleware::from_fn_with_state(state.clone(), auth),
     ));
 
@@ -182,11 +319,11 @@ pub fn bootstrap(cfg: &Config) -> Result<Router> {
 
   let app = app.layer((
     middleware::from_fn(track_metrics),
+    middleware::from_fn(request_id::request_id),
     TraceLayer::new_for_http()
       .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
       .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
-    TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(60)),
-    CatchPanicLayer::new(),
+    CatchPanicLayer::custom(handle_panic),
   ));
 
   Ok(app)
@@ -203,6 +340,10 @@ pub async fn serve(router: Router, listen: &str) {
     .expect("error running HTTP server");
 }
 
+fn handle_panic(_err: Box<dyn std::any::Any + Send + 'static>) -> Response {
+  AppError::InternalServerError("handler panicked".to_owned()).into_response()
+}
+
 async fn healthz() -> &'static str {
   "pong"
 }
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use libvips::{VipsImage, ops};
+
+use crate::config::ProcessingConfig;
+use crate::http::AppState;
+use crate::http::error::AppError;
+use crate::http::timeout::Phase;
+use crate::image_processing::render::SharedImage;
+
+const DEFAULT_OVERLAY_CACHE_MB: usize = 256;
+
+/// Decoded environment images, masks and watermarks shared by every request,
+/// keyed by storage key and reloaded when the etag of the object changes
+pub struct OverlayCache {
+  scenes: Mutex<Scenes<Arc<SharedImage>>>,
+}
+
+impl OverlayCache {
+  pub fn from_config(cfg: &ProcessingConfig) -> Self {
+    let max_bytes = cfg.overlay_cache_mb.unwrap_or(DEFAULT_OVERLAY_CACHE_MB) * 1000 * 1000;
+
+    Self {
+      scenes: Mutex::new(Scenes::new(max_bytes)),
+    }
+  }
+
+  /// Decoded image at the storage key, from the cache if the object hasn't
+  /// changed since it was decoded. A missing object is `not_found`.
+  pub async fn get(
+    &self,
+    state: &AppState,
+    key: &str,
+    not_found: fn(String) -> AppError,
+  ) -> Result<Arc<SharedImage>, AppError> {
+    let etag = match state
+      .timeouts
+      .run(
+        Phase::StorageDownload,
+        state.storage_client.head_object(key),
+      )
+      .await?
+    {
+      Ok(Some(head)) => head.etag,
+      Ok(None) => return Err(not_found(key.to_owned())),
+      Err(e) => {
+        return Err(AppError::InternalServerError(format!(
+          "failed to look up {}: {:#}",
+          key, e
+        )));
+      }
+    };
+
+    // Objects without an etag can't be told apart from newer versions
+    let reason = match &etag {
+      Some(etag) => match self.scenes().get(key, etag) {
+        Lookup::Hit(image) => {
+          metrics::counter!("overlay_cache_hits_total").increment(1);
+          return Ok(image);
+        }
+        Lookup::Changed => "changed",
+        Lookup::Missing => "missing",
+      },
+      None => "no_etag",
+    };
+    metrics::counter!("overlay_cache_loads_total", "reason" => reason).increment(1);
+
+    let data = state
+      .timeouts
+      .run(
+        Phase::StorageDownload,
+        state.storage_client.download_object(key),
+      )
+      .await?
+      .map_err(|e| AppError::InternalServerError(format!("failed to download {}: {:#}", key, e)))?;
+
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    rayon::spawn(move || {
+      let _ = sender.send(decode(&data));
+    });
+    let (image, bytes) = state
+      .timeouts
+      .run(Phase::Processing, receiver)
+      .await?
+      .map_err(|_| AppError::InternalServerError("overlay image decoding failed".into()))?
+      .map_err(|e| AppError::DecodeFailed(format!("overlay image {}: {}", key, e)))?;
+    let image = Arc::new(image);
+
+    if let Some(etag) = etag {
+      let mut scenes = self.scenes();
+      let evicted = scenes.insert(key, etag, image.clone(), bytes);
+      metrics::counter!("overlay_cache_evictions_total").increment(evicted as u64);
+      metrics::gauge!("overlay_cache_bytes").set(scenes.bytes as f64);
+    }
+
+    Ok(image)
+  }
+
+  fn scenes(&self) -> std::sync::MutexGuard<'_, Scenes<Arc<SharedImage>>> {
+    self.scenes.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+/// Decode the image into memory, returning it with its size in bytes
+fn decode(data: &[u8]) -> Result<(SharedImage, usize), libvips::error::Error> {
+  let shared = SharedImage::new(VipsImage::new_from_buffer(data, "")?)?;
+  let image = shared.image();
+  let band_bytes = match image.get_format()? {
+    ops::BandFormat::Uchar | ops::BandFormat::Char => 1,
+    ops::BandFormat::Ushort | ops::BandFormat::Short => 2,
+    ops::BandFormat::Uint | ops::BandFormat::Int | ops::BandFormat::Float => 4,
+    ops::BandFormat::Double | ops::BandFormat::Complex => 8,
+    ops::BandFormat::Dpcomplex => 16,
+    _ => 1,
+  };
+  let bytes = image.get_width() as usize
+    * image.get_height() as usize
+    * image.get_bands() as usize
+    * band_bytes;
+
+  Ok((shared, bytes))
+}
+
+enum Lookup<T> {
+  Hit(T),
+  /// Cached for an older version of the object
+  Changed,
+  Missing,
+}
+
+struct Entry<T> {
+  etag: String,
+  value: T,
+  bytes: usize,
+  last_used: u64,
+}
+
+/// Least recently used values within a memory limit
+struct Scenes<T> {
+  entries: HashMap<String, Entry<T>>,
+  max_bytes: usize,
+  bytes: usize,
+  clock: u64,
+}
+
+impl<T: Clone> Scenes<T> {
+  fn new(max_bytes: usize) -> Self {
+    Self {
+      entries: HashMap::new(),
+      max_bytes,
+      bytes: 0,
+      clock: 0,
+    }
+  }
+
+  fn get(&mut self, key: &str, etag: &str) -> Lookup<T> {
+    self.clock += 1;
+    match self.entries.get_mut(key) {
+      Some(entry) if entry.etag == etag => {
+        entry.last_used = self.clock;
+        Lookup::Hit(entry.value.clone())
+      }
+      Some(_) => Lookup::Changed,
+      None => Lookup::Missing,
+    }
+  }
+
+  /// Insert the value, evicting the least recently used values until it
+  /// fits. Values larger than the limit aren't kept. Returns the number of
+  /// evicted values.
+  fn insert(&mut self, key: &str, etag: String, value: T, bytes: usize) -> usize {
+    if let Some(previous) = self.entries.remove(key) {
+      self.bytes -= previous.bytes;
+    }
+    if bytes > self.max_bytes {
+      return 0;
+    }
+
+    let mut evicted = 0;
+    while self.bytes + bytes > self.max_bytes {
+      let Some(oldest) = self
+        .entries
+        .iter()
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(key, _)| key.clone())
+      else {
+        break;
+      };
+      if let Some(entry) = self.entries.remove(&oldest) {
+        self.bytes -= entry.bytes;
+        evicted += 1;
+      }
+    }
+
+    self.clock += 1;
+    self.bytes += bytes;
+    self.entries.insert(
+      key.to_owned(),
+      Entry {
+        etag,
+        value,
+        bytes,
+        last_used: self.clock,
+      },
+    );
+
+    evicted
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hit(scenes: &mut Scenes<u32>, key: &str, etag: &str) -> Option<u32> {
+    match scenes.get(key, etag) {
+      Lookup::Hit(value) => Some(value),
+      Lookup::Changed | Lookup::Missing => None,
+    }
+  }
+
+  #[test]
+  fn reload_changed_objects() {
+    let mut scenes = Scenes::new(100);
+    scenes.insert("scene.png", "v1".to_owned(), 1, 10);
+
+    assert_eq!(hit(&mut scenes, "scene.png", "v1"), Some(1));
+    assert!(matches!(scenes.get("scene.png", "v2"), Lookup::Changed));
+    assert!(matches!(scenes.get("other.png", "v1"), Lookup::Missing));
+
+    scenes.insert("scene.png", "v2".to_owned(), 2, 30);
+    assert_eq!(hit(&mut scenes, "scene.png", "v2"), Some(2));
+    assert_eq!(scenes.bytes, 30);
+  }
+
+  #[test]
+  fn evict_least_recently_used() {
+    let mut scenes = Scenes::new(100);
+    scenes.insert("a", "1".to_owned(), 1, 40);
+    scenes.insert("b", "1".to_owned(), 2, 40);
+    hit(&mut scenes, "a", "1");
+
+    assert_eq!(scenes.insert("c", "1".to_owned(), 3, 40), 1);
+    assert_eq!(hit(&mut scenes, "b", "1"), None);
+    assert_eq!(hit(&mut scenes, "a", "1"), Some(1));
+    assert_eq!(scenes.bytes, 80);
+  }
+
+  #[test]
+  fn skip_values_over_the_limit() {
+    let mut scenes = Scenes::new(100);
+    scenes.insert("a", "1".to_owned(), 1, 40);
+
+    assert_eq!(scenes.insert("b", "1".to_owned(), 2, 101), 0);
+    assert_eq!(hit(&mut scenes, "b", "1"), None);
+    assert_eq!(hit(&mut scenes, "a", "1"), Some(1));
+  }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+  Json,
+  extract::{self, State, multipart::MultipartRejection},
+};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::config::BatchConfig;
+use crate::http::AppState;
+use crate::http::error::{AppError, ErrorResponse};
+use crate::http::process_image::{self, OverlayImages};
+use crate::http::request_id;
+use crate::image_processing::batch::{BatchRequest, ProcessBatchForm};
+use crate::image_processing::report::{ConfigurationStatus, ProcessReport};
+use crate::image_processing::validation::{self, FieldError};
+use crate::image_processing::{ImageProcessingRequest, ProcessedImage};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_MAX_SOURCES: usize = 100;
+
+/// Bounds on the size and parallelism of a batch
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+  concurrency: usize,
+  max_sources: usize,
+}
+
+impl BatchLimits {
+  pub fn from_config(cfg: &BatchConfig) -> Self {
+    Self {
+      concurrency: cfg.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1),
+      max_sources: cfg.max_sources.unwrap_or(DEFAULT_MAX_SOURCES),
+    }
+  }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+  Succeeded,
+  /// Some configurations failed with the `best_effort` failure mode
+  Partial,
+  Failed,
+}
+
+/// Outcome of a single source of a batch
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BatchResult {
+  pub id: String,
+  pub status: BatchStatus,
+  pub images: Option<Vec<ProcessedImage>>,
+  /// Outcome of every configuration of the source
+  pub configurations: Option<Vec<ConfigurationStatus>>,
+  pub error: Option<ErrorResponse>,
+}
+
+/// Results of every source, in the order of the request
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BatchResponse {
+  pub results: Vec<BatchResult>,
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/v1/process-batch",
+  request_body(content = ProcessBatchForm, content_type = "multipart/form-data"),
+  responses(
+    (status = 200, description = "Results of every source, succeeded or failed", body = BatchResponse),
+    (status = 400, description = "Bad request - invalid input", body = ErrorResponse),
+    (status = 401, description = "Unauthorized - invalid API key", body = ErrorResponse),
+    (status = 422, description = "Validation failed - field errors in details", body = ErrorResponse),
+    (status = 500, description = "Internal server error", body = ErrorResponse)
+  ),
+  security(("api_key" = []))
+)]
+pub async fn process_batch(
+  State(state): State<AppState>,
+  multipart: Result<extract::Multipart, MultipartRejection>,
+) -> Result<Json<BatchResponse>, AppError> {
+  let (batch, mut uploads) = read_form(&state, multipart).await?;
+
+  // Environment images are downloaded once for the whole batch
+  let overlays = Arc::new(OverlayImages::default());
+  let semaphore = Arc::new(Semaphore::new(state.batch.concurrency));
+
+  let mut tasks = JoinSet::new();
+  for (index, source) in batch.sources.iter().enumerate() {
+    let mut request = batch.request_for(source);
+    let upload = source
+      .image
+      .as_ref()
+      .and_then(|name| uploads.get(name))
+      .map(|(data, file_name)| {
+        request.source_name = file_name.clone();
+        data.clone()
+      });
+
+    let state = state.clone();
+    let overlays = overlays.clone();
+    let semaphore = semaphore.clone();
+    tasks.spawn(request_id::scope(request_id::current(), async move {
+      // The semaphore is never closed, so acquiring can't fail
+      let _permit = semaphore.acquire_owned().await;
+      let result = process_source(&state, request, upload, &overlays).await;
+
+      (index, result)
+    }));
+  }
+  // Every task holds its own upload, free the ones no source refers to
+  uploads.clear();
+
+  let mut results: Vec<Option<BatchResult>> = batch.sources.iter().map(|_| None).collect();
+  while let Some(joined) = tasks.join_next().await {
+    let (index, result) = joined.map_err(|e| AppError::InternalServerError(e.to_string()))?;
+    let id = batch.sources[index].id.clone();
+
+    results[index] = Some(match result {
+      Ok(report) => BatchResult {
+        id,
+        status: if report.has_failures() {
+          BatchStatus::Partial
+        } else {
+          BatchStatus::Succeeded
+        },
+        images: Some(report.images),
+        configurations: Some(report.configurations),
+        error: None,
+      },
+      Err(e) => {
+        if let AppError::InternalServerError(msg) = &e {
+          error!("batch source {} failed: {}", id, msg);
+        }
+        BatchResult {
+          id,
+          status: BatchStatus::Failed,
+          images: None,
+          configurations: None,
+          error: Some(e.to_response_body()),
+        }
+      }
+    });
+  }
+
+  Ok(Json(BatchResponse {
+    results: results.into_iter().flatten().collect(),
+  }))
+}
+
+async fn process_source(
+  state: &AppState,
+  request: ImageProcessingRequest,
+  upload: Option<Arc<Vec<u8>>>,
+  overlays: &OverlayImages,
+) -> Result<ProcessReport, AppError> {
+  let data = match (upload, request.source.as_deref()) {
+    (Some(data), _) => data,
+    (None, Some(source)) => state.sources.fetch(state, source).await?,
+    (None, None) => return Err(AppError::BadRequest("missing image or source".to_owned())),
+  };
+
+  process_image::process(state, request, data, overlays, None).await
+}
+
+/// Uploaded images with their file names, keyed by field name
+type Uploads = HashMap<String, (Arc<Vec<u8>>, Option<String>)>;
+
+/// Read the batch from the `details` field and every other field as an upload
+async fn read_form(
+  state: &AppState,
+  multipart: Result<extract::Multipart, MultipartRejection>,
+) -> Result<(BatchRequest, Uploads), AppError> {
+  let mut multipart = multipart.map_err(|e| AppError::BadRequest(e.body_text()))?;
+  let mut batch: Option<BatchRequest> = None;
+  let mut uploads = HashMap::new();
+
+  while let Some(field) = multipart
+    .next_field()
+    .await
+    .map_err(|e| AppError::BadRequest(e.to_string()))?
+  {
+    let name = field.name().unwrap_or("").to_owned();
+    let file_name = field.file_name().map(str::to_owned);
+    let bytes = field
+      .bytes()
+      .await
+      .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    if name == "details" {
+      batch =
+        Some(validation::parse(&bytes, state.deny_unknown_fields).map_err(AppError::Validation)?);
+    } else if !name.is_empty() {
+      uploads.insert(name, (Arc::new(bytes.to_vec()), file_name));
+    }
+  }
+
+  let batch = batch.ok_or_else(|| AppError::BadRequest("missing details".to_owned()))?;
+
+  let mut errors = batch
+    .validate(state.batch.max_sources)
+    .err()
+    .unwrap_or_default();
+  for (i, source) in batch.sources.iter().enumerate() {
+    if let Some(image) = &source.image
+      && !uploads.contains_key(image)
+    {
+      errors.push(FieldError::new(
+        format!("sources[{}].image", i),
+        format!("no uploaded field named {}", image),
+      ));
+    }
+  }
+  if !errors.is_empty() {
+    return Err(AppError::Validation(errors));
+  }
+
+  Ok((batch, uploads))
+}
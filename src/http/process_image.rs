@@ -1,40 +1,216 @@
-use crate::image_modifier;
 use crate::image_processing::{
-  self, ImageProcessingRequest, ProcessImageForm, ProcessedImage, UploadImage,
+  self, ImageProcessingRequest, ProcessImageForm, ProcessedImage, UploadImage, environment,
+  path_template::{PathContext, PathImage},
+  render::{self, Environment, SharedImage},
+  report::{
+    ConfigurationOutcome, ConfigurationStatus, FailureMode, ProcessReport, ProcessResponse,
+  },
+  srcset,
+  validation::FieldError,
 };
 
 use anyhow::anyhow;
 use axum::{
   Json,
-  extract::{self, State},
+  body::Bytes,
+  extract::{self, Query, State, multipart::MultipartRejection, rejection::QueryRejection},
+  http::{StatusCode, header},
+  response::{IntoResponse, Response},
 };
-use libvips::{VipsImage, ops};
-use std::sync::Arc;
-use tracing::error;
-use uuid::Uuid;
-
+use base64::prelude::*;
+use libvips::VipsImage;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OnceCell, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{error, warn};
+use utoipa::IntoParams;
+
+use crate::config::ProcessingConfig;
 use crate::http::AppState;
-use crate::http::error::AppError;
+use crate::http::error::{AppError, ErrorResponse};
+use crate::http::jobs::{JobAccepted, JobProgress};
+use crate::http::storage::HeadObjectOutput;
+use crate::http::timeout::{self, Phase};
+
+const DEFAULT_RENDER_CONCURRENCY: usize = 4;
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+const DEFAULT_DRY_RUN_MAX_MB: usize = 10;
+
+/// Bounds on the parallelism within a single request
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessingLimits {
+  render_concurrency: usize,
+  upload_concurrency: usize,
+  dry_run_max_bytes: usize,
+  deduplicate_uploads: bool,
+}
+
+impl ProcessingLimits {
+  pub fn from_config(cfg: &ProcessingConfig) -> Self {
+    Self {
+      render_concurrency: cfg
+        .render_concurrency
+        .unwrap_or(DEFAULT_RENDER_CONCURRENCY)
+        .max(1),
+      upload_concurrency: cfg
+        .upload_concurrency
+        .unwrap_or(DEFAULT_UPLOAD_CONCURRENCY)
+        .max(1),
+      dry_run_max_bytes: cfg.dry_run_max_mb.unwrap_or(DEFAULT_DRY_RUN_MAX_MB) * 1000 * 1000,
+      deduplicate_uploads: cfg.deduplicate_uploads.unwrap_or(false),
+    }
+  }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ProcessImageParams {
+  /// Process in the background and respond with a job to poll
+  #[serde(default, rename = "async")]
+  run_async: bool,
+}
 
 #[utoipa::path(
   post,
   path = "/api/v1/process-image",
+  params(ProcessImageParams),
   request_body(content = ProcessImageForm, content_type = "multipart/form-data"),
   responses(
-    (status = 200, description = "Successfully processed images", body = [ProcessedImage]),
-    (status = 400, description = "Bad request - invalid input"),
-    (status = 401, description = "Unauthorized - invalid API key"),
-    (status = 404, description = "Not found - environment image not found"),
-    (status = 500, description = "Internal server error")
+    (status = 200, description = "Processed images with the `all_or_nothing` failure mode, or a report of every configuration with `best_effort`", body = ProcessResponse),
+    (status = 202, description = "Job accepted, poll the status url for the result", body = JobAccepted,
+      headers(("Location" = String, description = "Status url of the job"))),
+    (status = 400, description = "Bad request - invalid input, undecodable or too small image", body = ErrorResponse),
+    (status = 401, description = "Unauthorized - invalid API key", body = ErrorResponse),
+    (status = 422, description = "Validation failed - field errors in details", body = ErrorResponse),
+    (status = 404, description = "Not found - environment image not found", body = ErrorResponse),
+    (status = 500, description = "Internal server error", body = ErrorResponse),
+    (status = 503, description = "Image processing timed out", body = ErrorResponse),
+    (status = 504, description = "Storage download or upload timed out", body = ErrorResponse)
   ),
   security(("api_key" = []))
 )]
 pub async fn process_image(
   State(state): State<AppState>,
-  mut multipart: extract::Multipart,
-) -> Result<axum::Json<Vec<ProcessedImage>>, AppError> {
+  params: Result<Query<ProcessImageParams>, QueryRejection>,
+  multipart: Result<extract::Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+  let Query(params) = params.map_err(|e| AppError::BadRequest(e.body_text()))?;
+  let (processing_request, data) = read_form(&state, multipart).await?;
+
+  respond(&state, params, processing_request, data).await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/v1/process-image/source",
+  params(ProcessImageParams),
+  request_body(content = ImageProcessingRequest, description = "Details naming the `source` storage key or URL"),
+  responses(
+    (status = 200, description = "Processed images with the `all_or_nothing` failure mode, or a report of every configuration with `best_effort`", body = ProcessResponse),
+    (status = 202, description = "Job accepted, poll the status url for the result", body = JobAccepted,
+      headers(("Location" = String, description = "Status url of the job"))),
+    (status = 400, description = "Bad request - invalid input, undecodable or too small image", body = ErrorResponse),
+    (status = 401, description = "Unauthorized - invalid API key", body = ErrorResponse),
+    (status = 404, description = "Not found - source or environment image not found", body = ErrorResponse),
+    (status = 413, description = "Source image too large", body = ErrorResponse),
+    (status = 422, description = "Validation failed - field errors in details", body = ErrorResponse),
+    (status = 500, description = "Internal server error", body = ErrorResponse),
+    (status = 502, description = "Fetching the source URL failed", body = ErrorResponse),
+    (status = 503, description = "Image processing timed out", body = ErrorResponse),
+    (status = 504, description = "Storage download or upload timed out", body = ErrorResponse)
+  ),
+  security(("api_key" = []))
+)]
+pub async fn process_image_source(
+  State(state): State<AppState>,
+  params: Result<Query<ProcessImageParams>, QueryRejection>,
+  body: Bytes,
+) -> Result<Response, AppError> {
+  let Query(params) = params.map_err(|e| AppError::BadRequest(e.body_text()))?;
+
+  let processing_request =
+    image_processing::validation::parse_request(&body, state.deny_unknown_fields)
+      .map_err(AppError::Validation)?;
+  processing_request
+    .validate()
+    .map_err(AppError::Validation)?;
+
+  let Some(source) = processing_request.source.as_deref() else {
+    return Err(AppError::Validation(vec![FieldError::new(
+      "source",
+      "must be set",
+    )]));
+  };
+  let data = state.sources.fetch(&state, source).await?;
+
+  respond(&state, params, processing_request, data).await
+}
+
+/// Process the request, or submit it as a job when running asynchronously
+async fn respond(
+  state: &AppState,
+  params: ProcessImageParams,
+  processing_request: ImageProcessingRequest,
+  data: Arc<Vec<u8>>,
+) -> Result<Response, AppError> {
+  if processing_request.callback_url.is_some() && !params.run_async {
+    return Err(AppError::BadRequest(
+      "callback_url requires async=true".to_owned(),
+    ));
+  }
+
+  if let Some(callback_url) = &processing_request.callback_url
+    && !state.callbacks.allows(callback_url)
+  {
+    return Err(AppError::Validation(vec![FieldError::new(
+      "callback_url",
+      "host is not allowed",
+    )]));
+  }
+
+  if processing_request.dry_run && params.run_async {
+    return Err(AppError::BadRequest(
+      "dry_run can't be combined with async=true".to_owned(),
+    ));
+  }
+
+  if params.run_async {
+    let accepted = state.jobs.submit(state, processing_request, data).await?;
+    return Ok(
+      (
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, accepted.status_url.clone())],
+        Json(accepted),
+      )
+        .into_response(),
+    );
+  }
+
+  let failure_mode = processing_request.failure_mode;
+  let report = process(
+    state,
+    processing_request,
+    data,
+    &OverlayImages::default(),
+    None,
+  )
+  .await?;
+
+  Ok(Json(ProcessResponse::new(report, failure_mode)).into_response())
+}
+
+/// Read and validate the details and the image from the multipart form
+async fn read_form(
+  state: &AppState,
+  multipart: Result<extract::Multipart, MultipartRejection>,
+) -> Result<(ImageProcessingRequest, Arc<Vec<u8>>), AppError> {
+  let mut multipart = multipart.map_err(|e| AppError::BadRequest(e.body_text()))?;
   let mut processing_request: Option<ImageProcessingRequest> = None;
   let mut uploaded_image: Option<axum::body::Bytes> = None;
+  let mut file_name = None;
 
   while let Some(field) = multipart
     .next_field()
@@ -45,6 +221,7 @@ pub async fn process_image(
 
     match name {
       "image" => {
+        file_name = field.file_name().map(str::to_owned);
         uploaded_image = Some(
           field
             .bytes()
@@ -57,19 +234,76 @@ pub async fn process_image(
           .bytes()
           .await
           .map_err(|e| AppError::BadRequest(e.to_string()))?;
-        processing_request =
-          serde_json::from_slice(&bytes).map_err(|e| AppError::BadRequest(e.to_string()))?;
+        processing_request = Some(
+          image_processing::validation::parse_request(&bytes, state.deny_unknown_fields)
+            .map_err(AppError::Validation)?,
+        );
       }
       _ => {}
     }
   }
 
-  let (processing_request, uploaded_image) = match (processing_request, uploaded_image) {
+  let (mut processing_request, uploaded_image) = match (processing_request, uploaded_image) {
     (Some(pr), Some(ui)) => (pr, ui),
     _ => return Err(AppError::BadRequest("missing image or details".to_owned())),
   };
-  let data = Arc::new(uploaded_image.to_vec());
+  processing_request.source_name = file_name;
+  processing_request
+    .validate()
+    .map_err(AppError::Validation)?;
+
+  if processing_request.source.is_some() {
+    return Err(AppError::BadRequest(
+      "source is only supported by /api/v1/process-image/source".to_owned(),
+    ));
+  }
+
+  Ok((processing_request, Arc::new(uploaded_image.to_vec())))
+}
+
+/// Decoded overlay image, shared by the configurations using it
+type OverlayCell = Arc<OnceCell<Arc<SharedImage>>>;
 
+/// Environment images, masks and watermarks of a request by path, looked up
+/// on first use so they can be shared by every source of a batch
+#[derive(Default)]
+pub(crate) struct OverlayImages {
+  images: Mutex<HashMap<String, OverlayCell>>,
+}
+
+impl OverlayImages {
+  async fn get(
+    &self,
+    state: &AppState,
+    path: &str,
+    not_found: fn(String) -> AppError,
+  ) -> Result<Arc<SharedImage>, AppError> {
+    let cell = self
+      .images
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .entry(path.to_owned())
+      .or_default()
+      .clone();
+
+    cell
+      .get_or_try_init(|| state.overlays.get(state, path, not_found))
+      .await
+      .cloned()
+  }
+}
+
+/// Render every configuration of the request and upload the results,
+/// reporting uploads to `progress` when running as a job. Depending on the
+/// failure mode, a failing configuration either removes every upload and
+/// fails the request, or is reported alongside the other configurations.
+pub(crate) async fn process(
+  state: &AppState,
+  processing_request: ImageProcessingRequest,
+  data: Arc<Vec<u8>>,
+  overlays: &OverlayImages,
+  progress: Option<&JobProgress>,
+) -> Result<ProcessReport, AppError> {
   let (image_portrait_sender, image_portrait_recv) = tokio::sync::oneshot::channel();
 
   let orientation_data = data.clone();
@@ -77,7 +311,7 @@ pub async fn process_image(
     let image = match VipsImage::new_from_buffer(&orientation_data, "") {
       Ok(i) => i,
       Err(e) => {
-        let _ = image_portrait_sender.send(Err(AppError::BadRequest(e.to_string())));
+        let _ = image_portrait_sender.send(Err(AppError::DecodeFailed(e.to_string())));
         return;
       }
     };
@@ -85,50 +319,104 @@ pub async fn process_image(
     let _ = image_portrait_sender.send(Ok((image.get_width(), image.get_height())));
   });
 
-  let image_size = image_portrait_recv
-    .await
+  let image_size = state
+    .timeouts
+    .run(Phase::Processing, image_portrait_recv)
+    .await?
     .map_err(|_| AppError::InternalServerError("orientation detection failed".into()))??;
-  let image_portrait = image_size.0 < image_size.1;
 
   if let Some(min_size) = processing_request.min_size
     && image_size.0 < min_size && image_size.1 < min_size {
-      return Err(AppError::BadRequest("image too small".to_owned()));
+      return Err(AppError::ImageTooSmall {
+        width: image_size.0,
+        height: image_size.1,
+        min_size,
+      });
     }
 
-  let environment_image_conf = if image_portrait {
-    processing_request.portrait_environment_image.as_ref()
-  } else {
-    processing_request.landscape_environment_image.as_ref()
-  };
-
-  // Download the environment image from storage if there is one
-  let (environment_image, environment_image_opts) = if let Some(env_conf) = environment_image_conf {
-    let object_data = state
-      .storage_client
-      .download_object(&env_conf.path)
-      .await
-      .map_err(|_| AppError::NotFound)?;
-
-    let opts = image_modifier::environment::EnvironmentOptions {
-      width: env_conf.width,
-      height: env_conf.height,
-      x: env_conf.x,
-      y: env_conf.y,
-      margin_percent: env_conf.margin_percent,
+  // Download the environment images the configurations use, either their
+  // own or the one chosen for the source
+  let scenes = processing_request.environment_scenes();
+  let selected =
+    environment::select(&scenes, image_size.0, image_size.1).and_then(|scene| scene.id.clone());
+  let mut scene_environments: HashMap<String, Environment> = HashMap::new();
+  for config in &processing_request.configurations {
+    let Some(id) = config.environment.as_ref().or(selected.as_ref()) else {
+      continue;
+    };
+    if !config.conditions.use_environment_image || scene_environments.contains_key(id) {
+      continue;
+    }
+    let Some(scene) = scenes.iter().find(|scene| scene.id.as_ref() == Some(id)) else {
+      continue;
     };
 
-    (Some(Arc::new(object_data)), Some(opts))
-  } else {
-    (None, None)
-  };
+    let image = overlays
+      .get(state, &scene.path, AppError::EnvironmentNotFound)
+      .await?;
+    if let Some(corners) = &scene.corners
+      && !corners.is_within(image.image().get_width(), image.image().get_height())
+    {
+      return Err(AppError::BadRequest(format!(
+        "corners of environment image {} must be inside it",
+        id
+      )));
+    }
+
+    scene_environments.insert(
+      id.clone(),
+      Environment {
+        image,
+        mask: match &scene.mask_path {
+          Some(mask_path) => Some(
+            overlays
+              .get(state, mask_path, AppError::EnvironmentNotFound)
+              .await?,
+          ),
+          None => None,
+        },
+        opts: scene.options(),
+      },
+    );
+  }
+
+  // Download the watermarks of the configurations
+  let mut watermarks: HashMap<String, Arc<SharedImage>> = HashMap::new();
+  for watermark in processing_request
+    .configurations
+    .iter()
+    .filter_map(|config| config.watermark.as_ref())
+  {
+    if !watermarks.contains_key(&watermark.path) {
+      let overlay = overlays
+        .get(state, &watermark.path, AppError::WatermarkNotFound)
+        .await?;
+      watermarks.insert(watermark.path.clone(), overlay);
+    }
+  }
+
+  let failure_mode = processing_request.failure_mode;
+  let dry_run = processing_request.dry_run;
+  let mut statuses: Vec<ConfigurationStatus> = processing_request
+    .configurations
+    .iter()
+    .map(|config| ConfigurationStatus::new(&config.id))
+    .collect();
+  if processing_request.save_original {
+    statuses.push(ConfigurationStatus::new(&processing_request.id));
+  }
+  let original_index = processing_request.configurations.len();
 
   let (send, recv) = tokio::sync::oneshot::channel();
-  let (tx, mut rx) = tokio::sync::mpsc::channel(processing_request.configurations.len().max(1));
+  // Every configuration and the original send a single message, so sending never blocks
+  let (tx, mut rx) = tokio::sync::mpsc::channel(processing_request.configurations.len() + 1);
+  let render_concurrency = state.processing.render_concurrency;
+  let paths = PathContext::new(&processing_request);
+  let cancelled = progress.map(JobProgress::cancellation);
 
   // Run the image transformation in a thread from the thread pool
   rayon::spawn(move || {
-    // Decode the image once and reuse across all configurations
-    let source_image = match VipsImage::new_from_buffer(&data, "") {
+    let image = match VipsImage::new_from_buffer(&data, "") {
       Ok(i) => i,
       Err(e) => {
         let _ = send.send(Err(anyhow!("failed to create image from buffer: {}", e)));
@@ -136,7 +424,7 @@ pub async fn process_image(
       }
     };
 
-    let loader = match source_image.get_as_string("vips-loader") {
+    let loader = match image.get_as_string("vips-loader") {
       Ok(l) => l,
       Err(e) => {
         let _ = send.send(Err(anyhow!("failed to get vips-loader metadata: {}", e)));
@@ -144,226 +432,377 @@ pub async fn process_image(
       }
     };
 
-    for config in processing_request.configurations {
-      // Pass the image as is
-      if config.conditions.allow_vector && loader == "svgload_buffer" {
-        if let Err(e) = tx.blocking_send(UploadImage {
-          path: format!("{}.svg", &config.path),
-          mime: "image/svg+xml".to_string(),
-          id: config.id.clone(),
-          data: data.clone(),
-          alternative_to: None,
-          width: source_image.get_width(),
-          height: source_image.get_height(),
-        }) {
-          let _ = send.send(Err(anyhow!("failed to send image: {}", e)));
-          return;
-        }
-
-        let _ = send.send(Ok(()));
+    // Decode the image once and share it across all configurations
+    let source_image = match SharedImage::new(image) {
+      Ok(i) => i,
+      Err(e) => {
+        let _ = send.send(Err(anyhow!("failed to decode image: {}", e)));
         return;
       }
+    };
 
-      let alternative_possible =
-        image_processing::alternative_possible(&loader, config.conditions.allow_vector);
-
-      // Create a lightweight copy of the decoded image for this configuration
-      let mut output_image = match ops::copy(&source_image) {
-        Ok(img) => img,
-        Err(e) => {
-          let _ = send.send(Err(anyhow!("failed to copy source image: {}", e)));
+    // Each worker renders every n-th configuration, bounding the configurations
+    // of the request rendered at the same time
+    let configurations = &processing_request.configurations;
+    // Borrow from the wrapper rather than capturing the image it holds
+    let shared_image = &source_image;
+    let workers = render_concurrency.min(configurations.len()).max(1);
+    (0..workers).into_par_iter().for_each(|worker| {
+      let source = render::Source {
+        image: shared_image.image(),
+        data: &data,
+        loader: &loader,
+      };
+      for index in (worker..configurations.len()).step_by(workers) {
+        // A cancelled job renders no further configurations
+        if cancelled
+          .as_ref()
+          .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
           return;
         }
-      };
 
-      // Build a vector of modifiers to apply to the image
-      let mut modifiers: Vec<Box<dyn image_modifier::ImageModifier>> = Vec::new();
-
-      if config.conditions.black_and_white {
-        modifiers.push(Box::new(
-          image_modifier::blackandwhite::BlackAndWhiteModifier,
-        ));
+        let config = &configurations[index];
+        let rendered = render::render_configuration(
+          &source,
+          config,
+          processing_request.object_metadata(Some(config)),
+          config
+            .environment
+            .as_ref()
+            .or(selected.as_ref())
+            .and_then(|id| scene_environments.get(id)),
+          config
+            .watermark
+            .as_ref()
+            .and_then(|watermark| watermarks.get(&watermark.path))
+            .map(|overlay| &**overlay),
+          processing_request.generate_alternative,
+          &paths,
+        )
+        .map(|images| images.into_iter().map(UploadImage::addressed).collect());
+
+        // The receiver is gone once the request has failed or timed out
+        if tx.blocking_send((index, rendered)).is_err() {
+          return;
+        }
       }
+    });
 
-      if config.conditions.trim {
-        modifiers.push(Box::new(image_modifier::trim::TrimModifier::new(vec![
-          255.0, 255.0, 255.0,
-        ])));
-      }
+    // Upload the given image as well
+    if processing_request.save_original && !cancelled.is_some_and(|c| c.load(Ordering::Relaxed)) {
+      let meta = image_processing::loader_to_mime_ext(&loader);
+      let _ = tx.blocking_send((
+        original_index,
+        Ok(vec![
+          UploadImage {
+            path: paths.render(
+              &processing_request.path,
+              &PathImage {
+                config_id: &processing_request.id,
+                format: meta.1,
+                width: source_image.image().get_width(),
+                height: source_image.image().get_height(),
+                descriptor: None,
+                extension: None,
+              },
+            ),
+            id: processing_request.id.clone(),
+            data,
+            hash: String::new(),
+            content_addressed: false,
+            descriptor: None,
+            mime: meta.0.to_owned(),
+            alternative_to: None,
+            width: source_image.image().get_width(),
+            height: source_image.image().get_height(),
+            metadata: processing_request.object_metadata(None),
+          }
+          .addressed(),
+        ]),
+      ));
+    }
 
-      // If we are trimming, don't crop the resulting image
-      modifiers.push(Box::new(image_modifier::scale::ScaleModifier::new(
-        config.aspect,
-        config.margin_percent,
-        Some(config.size),
-        !config.conditions.trim,
-      )));
+    let _ = send.send(Ok(()));
+  });
 
-      if config.conditions.use_environment_image
-        && let (Some(env_img), Some(env_opts)) = (&environment_image, &environment_image_opts) {
-          modifiers.push(Box::new(
-            image_modifier::environment::EnvironmentModifier::new(
-              env_img.clone(),
-              env_opts.clone(),
-            ),
-          ));
+  let semaphore = Arc::new(Semaphore::new(state.processing.upload_concurrency));
+  let mut uploads = JoinSet::new();
+  // Uploaded images with the index of their configuration and their position in it
+  let mut uploaded: Vec<(usize, usize, ProcessedImage)> = Vec::new();
+  let mut inline_bytes = 0;
+  let mut rendering = true;
+  let mut failure = None;
+  // The processing budget bounds rendering every configuration, not each of them
+  let deadline = state.timeouts.deadline(Phase::Processing);
+  let mut timed_out = false;
+
+  loop {
+    tokio::select! {
+      received = timeout::run_until(Phase::Processing, deadline, rx.recv()), if rendering => match received {
+        // Nothing rendered after the job was cancelled is uploaded
+        Ok(Some(_)) if progress.is_some_and(JobProgress::is_cancelled) => {
+          rendering = false;
+          rx.close();
         }
-
-      for opt in modifiers {
-        match opt.apply(&output_image) {
-          Err(e) => {
-            let _ = send.send(Err(anyhow!("failed to apply modifier: {}", e)));
-            return;
+        Ok(Some((index, Ok(images)))) => {
+          statuses[index].status = ConfigurationOutcome::Succeeded;
+          // Uploads wait for a permit in their own task, so that finished
+          // uploads keep being drained meanwhile
+          for (position, img) in images.into_iter().enumerate() {
+            let semaphore = semaphore.clone();
+            let state = state.clone();
+            uploads.spawn(async move {
+              let uploaded = match semaphore.acquire_owned().await {
+                Ok(_permit) if dry_run => Ok(inline(img)),
+                Ok(_permit) => upload(&state, img).await,
+                Err(e) => Err(AppError::InternalServerError(e.to_string())),
+              };
+              (index, position, uploaded)
+            });
           }
-          Ok(Some(m)) => output_image = m,
-          Ok(None) => {}
         }
-      }
-
-      // Save as png if the image is transparent
-      let image_data = if config.conditions.transparent {
-        match ops::pngsave_buffer_with_opts(
-          &output_image,
-          &ops::PngsaveBufferOptions {
-            profile: Some("sRGB".to_owned()),
-            ..ops::PngsaveBufferOptions::default()
-          },
-        ) {
-          Ok(data) => Arc::new(data),
-          Err(e) => {
-            let _ = send.send(Err(anyhow!("failed to save image: {}", e)));
-            return;
+        Ok(Some((index, Err(e)))) => {
+          error!("failed to render {}: {:#}", statuses[index].id, e);
+          statuses[index].fail("rendering failed");
+          if failure_mode == FailureMode::AllOrNothing {
+            rendering = false;
+            rx.close();
           }
         }
-      } else {
-        match ops::jpegsave_buffer_with_opts(
-          &output_image,
-          &ops::JpegsaveBufferOptions {
-            q: config.quality,
-            background: vec![255.0, 255.0, 255.0],
-            profile: Some("sRGB".to_owned()),
-            ..ops::JpegsaveBufferOptions::default()
-          },
-        ) {
-          Ok(data) => Arc::new(data),
-          Err(e) => {
-            let _ = send.send(Err(anyhow!("failed to save image: {}", e)));
-            return;
+        Ok(None) => rendering = false,
+        Err(timeout) => {
+          rendering = false;
+          timed_out = true;
+          rx.close();
+          if failure_mode == FailureMode::AllOrNothing {
+            failure = Some(timeout);
+          } else {
+            for status in &mut statuses {
+              if status.status == ConfigurationOutcome::Cancelled {
+                status.fail("processing timed out");
+              }
+            }
           }
         }
-      };
-
-      let (ext, mime) = if config.conditions.transparent {
-        ("png", "image/png")
-      } else {
-        ("jpg", "image/jpeg")
-      };
-
-      // Pass the resulting image via the channel
-      if let Err(e) = tx.blocking_send(UploadImage {
-        path: format!("{}.{}", &config.path, ext),
-        mime: mime.to_owned(),
-        id: config.id.clone(),
-        data: image_data,
-        alternative_to: None,
-        width: output_image.get_width(),
-        height: output_image.get_height(),
-      }) {
-        let _ = send.send(Err(anyhow!("failed to send image: {}", e)));
-        return;
-      }
-
-      // Generate an alternative format if possible
-      if alternative_possible {
-        let webp_data = match ops::webpsave_buffer_with_opts(
-          &output_image,
-          &ops::WebpsaveBufferOptions {
-            q: config.quality,
-            background: vec![255.0, 255.0, 255.0],
-            profile: Some("sRGB".to_owned()),
-            ..ops::WebpsaveBufferOptions::default()
-          },
-        ) {
-          Ok(data) => Arc::new(data),
+      },
+      Some(joined) = uploads.join_next() => {
+        let (index, position, result) = match joined {
+          Ok(joined) => joined,
           Err(e) => {
-            let _ = send.send(Err(anyhow!("failed to save image: {}", e)));
-            return;
+            rendering = false;
+            rx.close();
+            failure = Some(AppError::InternalServerError(e.to_string()));
+            continue;
           }
         };
 
-        let alternative_id = Uuid::new_v4();
-        if let Err(e) = tx.blocking_send(UploadImage {
-          path: format!("{}.webp", &config.path),
-          id: alternative_id.into(),
-          data: webp_data,
-          mime: "image/webp".to_owned(),
-          alternative_to: Some(config.id.clone()),
-          width: output_image.get_width(),
-          height: output_image.get_height(),
-        }) {
-          let _ = send.send(Err(anyhow!("failed to send image: {}", e)));
-          return;
+        match result {
+          Ok(image) => {
+            if dry_run {
+              inline_bytes += image.size as usize;
+            }
+            uploaded.push((index, position, image));
+            if let Some(progress) = progress {
+              progress.uploaded(uploaded.len()).await;
+            }
+            if inline_bytes > state.processing.dry_run_max_bytes {
+              failure = Some(AppError::DryRunTooLarge(
+                state.processing.dry_run_max_bytes,
+              ));
+              rendering = false;
+              rx.close();
+            }
+            continue;
+          }
+          Err(timeout @ AppError::Timeout(_)) => {
+            if failure_mode == FailureMode::AllOrNothing {
+              failure = Some(timeout);
+            }
+            statuses[index].fail("upload timed out");
+          }
+          Err(_) => statuses[index].fail("upload failed"),
+        }
+
+        // Uploads already running are awaited so that they can be removed
+        if failure_mode == FailureMode::AllOrNothing {
+          rendering = false;
+          rx.close();
         }
       }
+      else => break,
     }
+  }
 
-    // Upload the given image as well
-    if processing_request.save_original {
-      let meta = image_processing::loader_to_mime_ext(&loader);
-      let _ = tx.blocking_send(UploadImage {
-        path: format!("{}.{}", &processing_request.path, meta.1),
-        id: processing_request.id.clone(),
-        data,
-        mime: meta.0.to_owned(),
-        alternative_to: None,
-        width: source_image.get_width(),
-        height: source_image.get_height(),
-      });
+  // Failed configurations keep none of their images
+  let (mut uploaded, orphaned): (Vec<_>, Vec<_>) = uploaded
+    .into_iter()
+    .partition(|(index, _, _)| statuses[*index].status != ConfigurationOutcome::Failed);
+  let orphaned: Vec<ProcessedImage> = orphaned.into_iter().map(|(_, _, image)| image).collect();
+  remove_uploads(state, &orphaned).await;
+
+  // Images come back in the order of the configurations, whatever order they
+  // were rendered and uploaded in
+  uploaded.sort_by_key(|(index, position, _)| (*index, *position));
+  let mut images: Vec<ProcessedImage> = uploaded.into_iter().map(|(_, _, image)| image).collect();
+  srcset::link(&mut images);
+  let report = ProcessReport {
+    images,
+    configurations: statuses,
+  };
+
+  if let Some(e) = failure {
+    remove_uploads(state, &report.images).await;
+    return Err(e);
+  }
+
+  if let Some(progress) = progress
+    && progress.is_cancelled()
+  {
+    remove_uploads(state, &report.images).await;
+    return Err(AppError::JobCancelled(progress.id().to_owned()));
+  }
+
+  // Errors decoding the source fail the request whatever the failure mode.
+  // Configurations still rendering past the deadline have already failed.
+  let rendering = match timeout::run_until(Phase::Processing, deadline, recv).await {
+    Ok(Ok(rendering)) => rendering,
+    Ok(Err(e)) => Err(anyhow!("failed to receive: {}", e)),
+    Err(_) if timed_out => Ok(()),
+    Err(timeout) => {
+      remove_uploads(state, &report.images).await;
+      return Err(timeout);
     }
+  };
+  if let Err(e) = rendering {
+    remove_uploads(state, &report.images).await;
+    return Err(AppError::InternalServerError(e.to_string()));
+  }
 
-    let _ = send.send(Ok(()));
-  });
+  if failure_mode == FailureMode::AllOrNothing && report.has_failures() {
+    remove_uploads(state, &report.images).await;
 
-  let mut processed_images = Vec::new();
-  while let Some(img) = rx.recv().await {
-    // Upload image
-    let data = match Arc::try_unwrap(img.data) {
-      Ok(data) => data,
-      Err(arc) => (*arc).clone(),
-    };
-    let upload_res = match state
-      .storage_client
-      .upload_object(data, &img.path, &img.mime)
-      .await
-    {
-      Ok(r) => r,
-      Err(e) => {
-        error!("failed to upload image: {:#}", e);
-        rx.close();
-        return Err(AppError::InternalServerError(e.to_string()));
+    let mut statuses = report.configurations;
+    for status in &mut statuses {
+      if status.status == ConfigurationOutcome::Succeeded {
+        status.status = ConfigurationOutcome::Cancelled;
       }
-    };
+    }
+    return Err(AppError::ProcessingFailed(statuses));
+  }
 
-    processed_images.push(ProcessedImage {
-      id: img.id,
-      path: img.path,
-      hash: upload_res.etag,
-      size: upload_res.size,
-      url: upload_res.url,
-      mime: img.mime,
-      alternative_to: img.alternative_to,
-      width: img.width,
-      height: img.height,
+  Ok(report)
+}
+
+/// Upload a rendered image within the upload budget, unless an identical
+/// object already exists at its path
+async fn upload(state: &AppState, mut img: UploadImage) -> Result<ProcessedImage, AppError> {
+  // Only paths derived from the content are likely to hold an identical
+  // object, other paths are looked up when deduplication is enabled
+  if (img.content_addressed || state.processing.deduplicate_uploads)
+    && let Some(existing) = find_identical(state, &img).await?
+  {
+    return Ok(ProcessedImage {
+      deduplicated: true,
+      ..processed(img, existing.url, existing.size)
     });
   }
 
-  if let Err(recv_err) = recv.await {
-    error!("failed to receive: {}", recv_err);
-    rx.close();
-    return Err(AppError::InternalServerError(recv_err.to_string()));
+  let data = match Arc::try_unwrap(std::mem::take(&mut img.data)) {
+    Ok(data) => data,
+    Err(arc) => (*arc).clone(),
+  };
+  let upload_res = state
+    .timeouts
+    .run(
+      Phase::StorageUpload,
+      state
+        .storage_client
+        .upload_object(data, &img.path, &img.mime, &img.metadata),
+    )
+    .await?
+    .map_err(|e| {
+      error!("failed to upload image {}: {:#}", img.path, e);
+      AppError::InternalServerError(e.to_string())
+    })?;
+
+  Ok(processed(img, upload_res.url, upload_res.size))
+}
+
+/// The object at the path of the image, if it has the same content and would
+/// be stored with the same content type and metadata
+async fn find_identical(
+  state: &AppState,
+  img: &UploadImage,
+) -> Result<Option<HeadObjectOutput>, AppError> {
+  let existing = state
+    .timeouts
+    .run(
+      Phase::StorageUpload,
+      state.storage_client.head_object(&img.path),
+    )
+    .await?
+    .unwrap_or_else(|e| {
+      warn!("failed to look up {}, uploading anyway: {:#}", img.path, e);
+      None
+    });
+
+  // The checksum is part of the metadata
+  Ok(existing.filter(|existing| {
+    existing.mime.as_deref() == Some(img.mime.as_str()) && img.metadata.matches(&existing.metadata)
+  }))
+}
+
+/// Return a rendered image inline instead of uploading it
+fn inline(img: UploadImage) -> ProcessedImage {
+  let data = BASE64_STANDARD.encode(img.data.as_slice());
+  let size = img.data.len() as u64;
+
+  ProcessedImage {
+    data: Some(data),
+    ..processed(img, String::new(), size)
   }
+}
 
-  rx.close();
+fn processed(img: UploadImage, url: String, size: u64) -> ProcessedImage {
+  ProcessedImage {
+    id: img.id,
+    path: img.path,
+    hash: img.hash,
+    size,
+    url,
+    mime: img.mime,
+    alternative_to: img.alternative_to,
+    width: img.width,
+    height: img.height,
+    descriptor: img.descriptor,
+    srcset: None,
+    deduplicated: false,
+    data: None,
+  }
+}
 
-  Ok(Json(processed_images))
+/// Remove uploaded images after the request has failed or its job was
+/// cancelled, logging what can't be removed
+pub(crate) async fn remove_uploads(state: &AppState, images: &[ProcessedImage]) {
+  // Inline images of a dry run were never uploaded, and deduplicated images
+  // existed before the request
+  for image in images
+    .iter()
+    .filter(|image| image.data.is_none() && !image.deduplicated)
+  {
+    let removed = state
+      .timeouts
+      .run(
+        Phase::StorageUpload,
+        state.storage_client.delete_object(&image.path),
+      )
+      .await;
+
+    match removed {
+      Ok(Ok(())) => {}
+      Ok(Err(e)) => warn!("failed to remove {}: {:#}", image.path, e),
+      Err(_) => warn!("timed out removing {}", image.path),
+    }
+  }
 }
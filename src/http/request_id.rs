@@ -0,0 +1,45 @@
+use axum::{
+  extract::Request,
+  http::HeaderValue,
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use uuid::Uuid;
+
+pub const X_REQUEST_ID: &str = "X-Request-Id";
+
+tokio::task_local! {
+  static REQUEST_ID: String;
+}
+
+/// The id of the request currently being handled, if any
+pub fn current() -> Option<String> {
+  REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Run `fut` with the given request id, e.g. for work outliving the request
+pub async fn scope<F: Future>(id: Option<String>, fut: F) -> F::Output {
+  match id {
+    Some(id) => REQUEST_ID.scope(id, fut).await,
+    None => fut.await,
+  }
+}
+
+/// Assign every request an id, reusing the one sent by the client if present
+pub async fn request_id(req: Request, next: Next) -> impl IntoResponse {
+  let id = req
+    .headers()
+    .get(X_REQUEST_ID)
+    .and_then(|header| header.to_str().ok())
+    .filter(|id| !id.is_empty() && id.len() <= 128)
+    .map(ToOwned::to_owned)
+    .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+  let mut response: Response = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+
+  if let Ok(value) = HeaderValue::from_str(&id) {
+    response.headers_mut().insert(X_REQUEST_ID, value);
+  }
+
+  response
+}
@@ -1,7 +1,9 @@
-use crate::http::storage::{PutObjectOutput, Storage};
+use crate::http::storage::{HeadObjectOutput, PutObjectOutput, Storage};
+use crate::image_processing::metadata::ObjectMetadata;
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::StorageClass;
 use tokio::io::AsyncReadExt;
 use tracing::debug;
 use url::Url;
@@ -48,16 +50,25 @@ impl Storage for Client {
     Ok(data)
   }
 
-  async fn upload_object(&self, data: Vec<u8>, key: &str, mime: &str) -> Result<PutObjectOutput> {
+  async fn upload_object(
+    &self,
+    data: Vec<u8>,
+    key: &str,
+    mime: &str,
+    metadata: &ObjectMetadata,
+  ) -> Result<PutObjectOutput> {
     let size = data.len() as u64;
     let body = ByteStream::from(data);
-    let res = self
+    self
       .s3_client
       .put_object()
       .bucket(self.bucket.as_str())
       .key(key)
       .body(body)
-      .cache_control("public, max-age=31536000, immutable".to_owned())
+      .cache_control(metadata.cache_control())
+      .set_content_disposition(metadata.content_disposition.clone())
+      .set_storage_class(metadata.storage_class.as_deref().map(StorageClass::from))
+      .set_metadata((!metadata.user_metadata.is_empty()).then(|| metadata.user_metadata.clone()))
       .content_type(mime)
       .send()
       .await
@@ -66,14 +77,66 @@ impl Storage for Client {
           "failed to upload object to bucket {} key {}",
           self.bucket, key
         )
-      });
+      })?;
 
     let url = self.base_url.join(key)?.to_string();
 
-    Ok(PutObjectOutput {
-      etag: res?.e_tag.unwrap_or("".to_owned()).trim_matches('"').into(),
-      url,
-      size,
-    })
+    Ok(PutObjectOutput { url, size })
+  }
+
+  async fn delete_object(&self, key: &str) -> Result<()> {
+    self
+      .s3_client
+      .delete_object()
+      .bucket(self.bucket.as_str())
+      .key(key)
+      .send()
+      .await
+      .with_context(|| {
+        format!(
+          "failed to delete object from bucket {} key {}",
+          self.bucket, key
+        )
+      })?;
+
+    Ok(())
+  }
+
+  async fn head_object(&self, key: &str) -> Result<Option<HeadObjectOutput>> {
+    let res = self
+      .s3_client
+      .head_object()
+      .bucket(self.bucket.as_str())
+      .key(key)
+      .send()
+      .await;
+
+    let object = match res {
+      Ok(object) => object,
+      Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(None),
+      Err(e) => {
+        return Err(e).with_context(|| {
+          format!(
+            "failed to look up object in bucket {} key {}",
+            self.bucket, key
+          )
+        });
+      }
+    };
+
+    Ok(Some(HeadObjectOutput {
+      url: self.base_url.join(key)?.to_string(),
+      size: object.content_length.unwrap_or(0) as u64,
+      etag: object.e_tag,
+      mime: object.content_type,
+      metadata: ObjectMetadata {
+        cache_control: object.cache_control,
+        content_disposition: object.content_disposition,
+        storage_class: object
+          .storage_class
+          .map(|storage_class| storage_class.as_str().to_owned()),
+        user_metadata: object.metadata.unwrap_or_default(),
+      },
+    }))
   }
 }
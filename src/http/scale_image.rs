@@ -9,7 +9,8 @@ use tracing::error;
 use crate::http::AppState;
 use crate::image_modifier;
 
-use crate::http::error::AppError;
+use crate::http::error::{AppError, ErrorResponse};
+use crate::http::timeout::Phase;
 
 #[utoipa::path(
   get,
@@ -20,44 +21,112 @@ use crate::http::error::AppError;
   ),
   responses(
     (status = 200, description = "Successfully transformed image", content_type = "image/jpeg"),
-    (status = 404, description = "Image not found"),
-    (status = 500, description = "Internal server error")
+    (status = 400, description = "Invalid options or undecodable image", body = ErrorResponse),
+    (status = 404, description = "Image not found", body = ErrorResponse),
+    (status = 500, description = "Internal server error", body = ErrorResponse),
+    (status = 503, description = "Image processing timed out", body = ErrorResponse),
+    (status = 504, description = "Storage download timed out", body = ErrorResponse)
   )
 )]
 pub async fn scale(
   Path((options, uri)): Path<(String, String)>,
   State(state): State<AppState>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, AppError> {
   // Read image from storage using the provided uri
-  let data = match state.storage_client.download_object(&uri).await {
-    Ok(data) => data,
-    Err(_) => {
-      return AppError::NotFound.into_response();
+  let data = state
+    .timeouts
+    .run(
+      Phase::StorageDownload,
+      state.storage_client.download_object(&uri),
+    )
+    .await?
+    .map_err(|_| AppError::SourceNotFound(uri.clone()))?;
+
+  // Only watermarks named in the configuration can be stamped
+  let watermark = match options
+    .split('-')
+    .find_map(image_modifier::watermark::WatermarkModifier::token)
+  {
+    Some(name) => {
+      let watermark = state
+        .watermarks
+        .get(name)
+        .ok_or_else(|| AppError::InvalidOption(format!("unknown watermark {:?}", name)))?;
+      let overlay = state
+        .overlays
+        .get(&state, &watermark.path, AppError::WatermarkNotFound)
+        .await?;
+      Some((overlay, watermark.clone()))
     }
+    None => None,
+  };
+
+  let text = match options
+    .split('-')
+    .find_map(image_modifier::text::TextModifier::token)
+  {
+    Some(text) => Some(text.map_err(|e| AppError::InvalidOption(format!("invalid text: {}", e)))?),
+    None => None,
   };
 
   // Run the image transformation in a thread from the thread pool
   let (send, recv) = tokio::sync::oneshot::channel();
   rayon::spawn(move || {
     // Parse options and create modifiers
-    let modifiers = parse_options(&options);
-    if modifiers.is_empty() {
-      let _ = send.send(Err("no valid options provided".to_owned()));
+    let mut modifiers = match parse_options(&options) {
+      Ok(modifiers) => modifiers,
+      Err(e) => {
+        let _ = send.send(Err(AppError::InvalidOption(e)));
+        return;
+      }
+    };
+    if modifiers.is_empty() && watermark.is_none() && text.is_none() {
+      let _ = send.send(Err(AppError::InvalidOption(format!(
+        "no valid options provided in {:?}",
+        options
+      ))));
       return;
     }
 
     let mut output_image = match VipsImage::new_from_buffer(&data, "") {
       Ok(img) => img,
       Err(e) => {
-        let _ = send.send(Err(format!("failed to load image: {}", e)));
+        let _ = send.send(Err(AppError::DecodeFailed(e.to_string())));
         return;
       }
     };
 
+    // Stamp the watermark and then the text last, relative to the output size
+    if let Some((overlay, opts)) = &watermark {
+      match ops::copy(overlay.image()) {
+        Ok(overlay) => modifiers.push(Box::new(image_modifier::watermark::WatermarkModifier::new(
+          overlay,
+          opts.clone(),
+        ))),
+        Err(e) => {
+          let _ = send.send(Err(AppError::InternalServerError(format!(
+            "failed to copy watermark: {}",
+            e
+          ))));
+          return;
+        }
+      }
+    }
+
+    if let Some(text) = text {
+      modifiers.push(Box::new(image_modifier::text::TextModifier::new(text)));
+    }
+
+    // Apply the modifiers by stage, whatever the order of the options
+    modifiers.sort_by_key(|modifier| modifier.stage());
+
     for opt in modifiers {
       match opt.apply(&output_image) {
         Err(e) => {
-          let _ = send.send(Err(e.to_string()));
+          let _ = send.send(Err(AppError::InternalServerError(format!(
+            "failed to transform image: {}",
+            e
+          ))));
           return;
         }
         Ok(Some(m)) => output_image = m,
@@ -78,7 +147,10 @@ pub async fn scale(
         let _ = send.send(Ok(buffer));
       }
       Err(e) => {
-        let _ = send.send(Err(e.to_string()));
+        let _ = send.send(Err(AppError::InternalServerError(format!(
+          "failed to save image: {}",
+          e
+        ))));
       }
     }
 
@@ -86,47 +158,83 @@ pub async fn scale(
     drop(data);
   });
 
+  let image_data = state
+    .timeouts
+    .run(Phase::Processing, recv)
+    .await?
+    .map_err(|e| {
+      AppError::InternalServerError(format!(
+        "failed to receive from image processing task: {}",
+        e
+      ))
+    })?
+    .inspect_err(|e| {
+      if let AppError::InternalServerError(_) = e {
+        error!(
+          "vips error buffer: {}",
+          state.vips_app.error_buffer().unwrap_or("")
+        );
+      }
+    })?;
+
   let headers = [(header::CONTENT_TYPE, "image/jpeg")];
 
-  match recv.await {
-    Ok(Ok(image_data)) => (StatusCode::OK, headers, image_data).into_response(),
-    Ok(Err(e)) => {
-      error!(
-        "failed to transform image: {} {}",
-        e,
-        state.vips_app.error_buffer().unwrap_or("")
-      );
-      (StatusCode::INTERNAL_SERVER_ERROR, "").into_response()
-    }
-    Err(e) => {
-      error!("failed to receive from image processing task: {}", e);
-      (StatusCode::INTERNAL_SERVER_ERROR, "").into_response()
+  Ok((StatusCode::OK, headers, image_data))
+}
+
+/// Split the option string on `-`, joining negative numbers back onto the
+/// options taking them, e.g. `sat-20`
+fn split_options(option_string: &str) -> Vec<String> {
+  let mut options: Vec<String> = Vec::new();
+  for opt in option_string.split('-') {
+    if let Some(previous) = options.last_mut()
+      && image_modifier::adjust::SIGNED_OPTIONS.contains(&previous.as_str())
+      && !opt.is_empty()
+      && opt.chars().all(|c| c.is_ascii_digit())
+    {
+      previous.push('-');
+      previous.push_str(opt);
+    } else {
+      options.push(opt.to_owned());
     }
   }
+
+  options
 }
 
-fn parse_options(option_string: &str) -> Vec<Box<dyn image_modifier::ImageModifier>> {
-  let options: Vec<&str> = option_string.split('-').collect();
+/// Modifiers of the options, or why a recognised option is invalid
+fn parse_options(
+  option_string: &str,
+) -> Result<Vec<Box<dyn image_modifier::ImageModifier>>, String> {
+  let options = split_options(option_string);
+  let options: Vec<&str> = options.iter().map(String::as_str).collect();
   let mut opts = Vec::new();
 
   let eval_options: Vec<image_modifier::ImageModifierEvaluator> = vec![
     image_modifier::orientation::OrientationModifier::evaluate,
+    image_modifier::adjust::AdjustModifier::evaluate,
     image_modifier::blackandwhite::BlackAndWhiteModifier::evaluate,
+    image_modifier::sepia::SepiaModifier::evaluate,
+    image_modifier::tint::TintModifier::evaluate,
+    image_modifier::duotone::DuotoneModifier::evaluate,
     image_modifier::trim::TrimModifier::evaluate,
     image_modifier::scale::ScaleModifier::evaluate,
     image_modifier::resize::ResizeModifier::evaluate,
+    image_modifier::blur::BlurModifier::evaluate,
+    image_modifier::sharpen::SharpenModifier::evaluate,
+    image_modifier::pixelate::PixelateModifier::evaluate,
   ];
 
   for opt in &options {
     for eval in eval_options.iter() {
       if let Some(o) = eval(opt, &options) {
-        opts.push(o);
+        opts.push(o?);
         break;
       }
     }
   }
 
-  opts
+  Ok(opts)
 }
 
 #[cfg(test)]
@@ -135,43 +243,105 @@ mod tests {
 
   #[test]
   fn parse_options_scale_and_margin() {
-    let opts = parse_options("s400x300-m10");
+    let opts = parse_options("s400x300-m10").unwrap();
     assert_eq!(opts.len(), 1); // scale modifier (margin is consumed by scale)
   }
 
   #[test]
   fn parse_options_multiple_modifiers() {
-    let opts = parse_options("bw-olandscape-s400x400-m20");
+    let opts = parse_options("bw-olandscape-s400x400-m20").unwrap();
     assert_eq!(opts.len(), 3); // blackandwhite, orientation, scale
   }
 
   #[test]
   fn parse_options_resize_height() {
-    let opts = parse_options("rh200");
+    let opts = parse_options("rh200").unwrap();
     assert_eq!(opts.len(), 1);
   }
 
   #[test]
   fn parse_options_resize_width() {
-    let opts = parse_options("rw300");
+    let opts = parse_options("rw300").unwrap();
     assert_eq!(opts.len(), 1);
   }
 
   #[test]
   fn parse_options_empty_string() {
-    let opts = parse_options("");
+    let opts = parse_options("").unwrap();
     assert_eq!(opts.len(), 0);
   }
 
   #[test]
   fn parse_options_invalid() {
-    let opts = parse_options("invalid-xyz-123");
+    let opts = parse_options("invalid-xyz-123").unwrap();
     assert_eq!(opts.len(), 0);
   }
 
+  #[test]
+  fn split_options_joins_negative_adjustments() {
+    assert_eq!(
+      split_options("br10-ct-15-sat-20-s400x300-m10"),
+      vec!["br10", "ct-15", "sat-20", "s400x300", "m10"]
+    );
+    assert_eq!(split_options("sat-s400x300"), vec!["sat", "s400x300"]);
+  }
+
+  #[test]
+  fn parse_options_adjustments() {
+    let opts = parse_options("br10-ct15-sat-20-gm1.8-norm-s400x400").unwrap();
+    assert_eq!(opts.len(), 2); // adjustments + scale
+  }
+
+  #[test]
+  fn parse_options_sorted_by_stage() {
+    let mut opts = parse_options("rh200-s400x300-bw-br10-oportrait").unwrap();
+    opts.sort_by_key(|opt| opt.stage());
+    let stages: Vec<image_modifier::Stage> = opts.iter().map(|opt| opt.stage()).collect();
+    assert_eq!(
+      stages,
+      vec![
+        image_modifier::Stage::Prepare,
+        image_modifier::Stage::Adjust,
+        image_modifier::Stage::Effect,
+        image_modifier::Stage::Scale,
+        image_modifier::Stage::Resize,
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_options_effects() {
+    let opts = parse_options("sepia-tintc62828-duo1a237e_ffca28-s400x400").unwrap();
+    assert_eq!(opts.len(), 4);
+  }
+
+  #[test]
+  fn parse_options_blur_sharpen_pixelate() {
+    let opts = parse_options("rw300-ash-blur4-sh1_2-px12").unwrap();
+    assert_eq!(opts.len(), 4); // resize consumes ash
+  }
+
+  #[test]
+  fn parse_options_out_of_range() {
+    for (options, message) in [
+      ("s400x300-blur999", "blur must be between 0.3 and 100"),
+      ("px1", "pixelate must be between 2 and 512"),
+      ("sh20", "sharpen sigma must be between 0.1 and 10"),
+      ("rw300-ash1_21", "sharpen amount must be between 0 and 20"),
+      ("br10-ct-101", "contrast must be between -100 and 100"),
+      ("gm0", "gamma must be between 0.1 and 10"),
+    ] {
+      assert_eq!(
+        parse_options(options).err().as_deref(),
+        Some(message),
+        "{options}"
+      );
+    }
+  }
+
   #[test]
   fn parse_options_trim() {
-    let opts = parse_options("tr-s200x200");
+    let opts = parse_options("tr-s200x200").unwrap();
     assert_eq!(opts.len(), 2); // trim + scale
   }
 }
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::redirect;
+use url::Url;
+
+use crate::http::AppState;
+use crate::http::error::AppError;
+use crate::http::timeout::Phase;
+use crate::image_processing::validation::FieldError;
+
+/// Maximum number of redirects followed when fetching a source URL
+const MAX_REDIRECTS: usize = 5;
+
+/// Hosts URLs may point to, e.g. source images or callbacks, either exact
+/// names or wildcards like `*.example.com` matching any subdomain
+#[derive(Debug, Clone, Default)]
+pub struct AllowedHosts(Vec<String>);
+
+impl AllowedHosts {
+  pub fn new(hosts: Vec<String>) -> Self {
+    Self(
+      hosts
+        .into_iter()
+        .map(|host| host.to_ascii_lowercase())
+        .collect(),
+    )
+  }
+
+  pub fn allows(&self, url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+      return false;
+    }
+
+    let Some(host) = url.host_str().map(|host| host.to_ascii_lowercase()) else {
+      return false;
+    };
+
+    self
+      .0
+      .iter()
+      .any(|allowed| match allowed.strip_prefix("*.") {
+        Some(domain) => host
+          .strip_suffix(domain)
+          .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => *allowed == host,
+      })
+  }
+}
+
+/// Fetches source images referenced by storage key or URL
+pub struct SourceFetcher {
+  client: reqwest::Client,
+  allowed_hosts: AllowedHosts,
+  max_bytes: usize,
+}
+
+impl SourceFetcher {
+  pub fn new(allowed_hosts: AllowedHosts, max_bytes: usize) -> Result<Self> {
+    // Redirects must not lead to hosts that aren't allowed
+    let redirect_hosts = allowed_hosts.clone();
+    let client = reqwest::Client::builder()
+      .redirect(redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+          attempt.error("too many redirects")
+        } else if redirect_hosts.allows(attempt.url()) {
+          attempt.follow()
+        } else {
+          attempt.error("redirect to a host that isn't allowed")
+        }
+      }))
+      .connect_timeout(Duration::from_secs(10))
+      .user_agent(concat!("rusty-pixel/", env!("CARGO_PKG_VERSION")))
+      .build()?;
+
+    Ok(Self {
+      client,
+      allowed_hosts,
+      max_bytes,
+    })
+  }
+
+  /// Download the source, a URL if it has an `http` or `https` scheme and a
+  /// storage key otherwise
+  pub async fn fetch(&self, state: &AppState, source: &str) -> Result<Arc<Vec<u8>>, AppError> {
+    match Url::parse(source) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => {
+        if !self.allowed_hosts.allows(&url) {
+          return Err(AppError::Validation(vec![FieldError::new(
+            "source",
+            "host is not allowed",
+          )]));
+        }
+
+        let data = state
+          .timeouts
+          .run(Phase::StorageDownload, self.download(url))
+          .await??;
+
+        Ok(Arc::new(data))
+      }
+      _ => {
+        // Objects larger than the limit aren't read into memory
+        let head = state
+          .timeouts
+          .run(
+            Phase::StorageDownload,
+            state.storage_client.head_object(source),
+          )
+          .await?
+          .map_err(|e| {
+            AppError::InternalServerError(format!("failed to look up source {}: {:#}", source, e))
+          })?
+          .ok_or_else(|| AppError::SourceNotFound(source.to_owned()))?;
+        if head.size > self.max_bytes as u64 {
+          return Err(AppError::SourceTooLarge(self.max_bytes));
+        }
+
+        let data = state
+          .timeouts
+          .run(
+            Phase::StorageDownload,
+            state.storage_client.download_object(source),
+          )
+          .await?
+          .map_err(|_| AppError::SourceNotFound(source.to_owned()))?;
+
+        if data.len() > self.max_bytes {
+          return Err(AppError::SourceTooLarge(self.max_bytes));
+        }
+
+        Ok(Arc::new(data))
+      }
+    }
+  }
+
+  async fn download(&self, url: Url) -> Result<Vec<u8>, AppError> {
+    let fetch_failed = |e: reqwest::Error| AppError::SourceFetchFailed(e.to_string());
+
+    let mut response = self
+      .client
+      .get(url.clone())
+      .send()
+      .await
+      .map_err(fetch_failed)?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+      return Err(AppError::SourceNotFound(url.to_string()));
+    }
+    if !response.status().is_success() {
+      return Err(AppError::SourceFetchFailed(format!(
+        "{} responded with {}",
+        url,
+        response.status()
+      )));
+    }
+    if response
+      .content_length()
+      .is_some_and(|length| length > self.max_bytes as u64)
+    {
+      return Err(AppError::SourceTooLarge(self.max_bytes));
+    }
+
+    // The content length may be missing or wrong, enforce the limit while reading
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_failed)? {
+      if data.len() + chunk.len() > self.max_bytes {
+        return Err(AppError::SourceTooLarge(self.max_bytes));
+      }
+      data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn allowed_hosts() {
+    let hosts = AllowedHosts::new(vec![
+      "images.example.com".to_owned(),
+      "*.CDN.example.com".to_owned(),
+    ]);
+    let allows = |url: &str| hosts.allows(&Url::parse(url).unwrap());
+
+    assert!(allows("https://images.example.com/a.png"));
+    assert!(allows("http://IMAGES.example.com/a.png"));
+    assert!(allows("https://eu.cdn.example.com/a.png"));
+    assert!(!allows("https://cdn.example.com/a.png"));
+    assert!(!allows("https://evilcdn.example.com/a.png"));
+    assert!(!allows("https://example.com/a.png"));
+    assert!(!allows("ftp://images.example.com/a.png"));
+  }
+
+  #[test]
+  fn no_allowed_hosts() {
+    let hosts = AllowedHosts::default();
+    assert!(!hosts.allows(&Url::parse("https://images.example.com/a.png").unwrap()));
+  }
+}
@@ -1,15 +1,37 @@
 use anyhow::Result;
 use async_trait::async_trait;
 
+use crate::image_processing::metadata::ObjectMetadata;
+
 pub struct PutObjectOutput {
-  pub etag: String,
   pub url: String,
   pub size: u64,
 }
 
+pub struct HeadObjectOutput {
+  pub url: String,
+  pub size: u64,
+  /// Changes whenever the object does, if the backend tells
+  pub etag: Option<String>,
+  /// Content type of the object, if the backend tells
+  pub mime: Option<String>,
+  pub metadata: ObjectMetadata,
+}
+
 #[async_trait]
 pub trait Storage: Send + Sync {
   async fn download_object(&self, key: &str) -> Result<Vec<u8>>;
 
-  async fn upload_object(&self, data: Vec<u8>, key: &str, mime: &str) -> Result<PutObjectOutput>;
+  async fn upload_object(
+    &self,
+    data: Vec<u8>,
+    key: &str,
+    mime: &str,
+    metadata: &ObjectMetadata,
+  ) -> Result<PutObjectOutput>;
+
+  async fn delete_object(&self, key: &str) -> Result<()>;
+
+  /// Size and metadata of the object, or `None` if there is none at the key
+  async fn head_object(&self, key: &str) -> Result<Option<HeadObjectOutput>>;
 }
@@ -0,0 +1,182 @@
+use std::future::Future;
+
+use axum::{
+  extract::{Request, State},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use tokio::time::{Duration, Instant};
+
+use crate::config::TimeoutConfig;
+use crate::http::error::AppError;
+
+const DEFAULT_REQUEST_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+  StorageDownload,
+  Processing,
+  StorageUpload,
+}
+
+impl Phase {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Phase::StorageDownload => "storage_download",
+      Phase::Processing => "processing",
+      Phase::StorageUpload => "storage_upload",
+    }
+  }
+}
+
+/// Time budgets for the individual phases of handling a request
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+  storage_download: Duration,
+  processing: Duration,
+  storage_upload: Duration,
+}
+
+impl Timeouts {
+  pub fn from_config(cfg: &TimeoutConfig) -> Self {
+    let request_secs = cfg.request_secs.unwrap_or(DEFAULT_REQUEST_SECS);
+
+    Self {
+      storage_download: Duration::from_secs(cfg.storage_download_secs.unwrap_or(request_secs)),
+      processing: Duration::from_secs(cfg.processing_secs.unwrap_or(request_secs)),
+      storage_upload: Duration::from_secs(cfg.storage_upload_secs.unwrap_or(request_secs)),
+    }
+  }
+
+  pub fn budget(&self, phase: Phase) -> Duration {
+    match phase {
+      Phase::StorageDownload => self.storage_download,
+      Phase::Processing => self.processing,
+      Phase::StorageUpload => self.storage_upload,
+    }
+  }
+
+  /// Run the future within the budget of the given phase
+  pub async fn run<F: Future>(&self, phase: Phase, fut: F) -> Result<F::Output, AppError> {
+    tokio::time::timeout(self.budget(phase), fut)
+      .await
+      .map_err(|_| expired(phase))
+  }
+
+  /// When the budget of the given phase runs out if it starts now, for
+  /// phases spanning several waits
+  pub fn deadline(&self, phase: Phase) -> Instant {
+    Instant::now() + self.budget(phase)
+  }
+}
+
+/// Run the future until the deadline of the given phase
+pub async fn run_until<F: Future>(
+  phase: Phase,
+  deadline: Instant,
+  fut: F,
+) -> Result<F::Output, AppError> {
+  tokio::time::timeout_at(deadline, fut)
+    .await
+    .map_err(|_| expired(phase))
+}
+
+fn expired(phase: Phase) -> AppError {
+  metrics::counter!("phase_timeouts_total", "phase" => phase.as_str()).increment(1);
+  AppError::Timeout(phase)
+}
+
+/// Resolve the request timeout for the given route path
+pub fn route_timeout(cfg: &TimeoutConfig, route: &str) -> Duration {
+  let secs = cfg
+    .routes
+    .as_ref()
+    .and_then(|routes| routes.get(route).copied())
+    .or(cfg.request_secs)
+    .unwrap_or(DEFAULT_REQUEST_SECS);
+
+  Duration::from_secs(secs)
+}
+
+/// Enforce a request timeout, responding with a JSON error once it elapses
+pub async fn enforce(State(timeout): State<Duration>, req: Request, next: Next) -> Response {
+  match tokio::time::timeout(timeout, next.run(req)).await {
+    Ok(response) => response,
+    Err(_) => AppError::RequestTimeout.into_response(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  #[test]
+  fn phases_default_to_request_timeout() {
+    let timeouts = Timeouts::from_config(&TimeoutConfig {
+      request_secs: Some(30),
+      processing_secs: Some(5),
+      ..TimeoutConfig::default()
+    });
+
+    assert_eq!(
+      timeouts.budget(Phase::StorageDownload),
+      Duration::from_secs(30)
+    );
+    assert_eq!(timeouts.budget(Phase::Processing), Duration::from_secs(5));
+    assert_eq!(
+      timeouts.budget(Phase::StorageUpload),
+      Duration::from_secs(30)
+    );
+  }
+
+  #[tokio::test]
+  async fn deadline_bounds_every_wait() {
+    let timeouts = Timeouts {
+      storage_download: Duration::from_secs(1),
+      processing: Duration::from_millis(250),
+      storage_upload: Duration::from_secs(1),
+    };
+
+    // Configurations rendered 100ms apart, each within the budget on its own
+    let (tx, mut rx) = tokio::sync::mpsc::channel(5);
+    tokio::spawn(async move {
+      for index in 0..5 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if tx.send(index).await.is_err() {
+          return;
+        }
+      }
+    });
+
+    let deadline = timeouts.deadline(Phase::Processing);
+    let mut received = 0;
+    let result = loop {
+      match run_until(Phase::Processing, deadline, rx.recv()).await {
+        Ok(Some(_)) => received += 1,
+        Ok(None) => break Ok(()),
+        Err(e) => break Err(e),
+      }
+    };
+
+    assert!(matches!(result, Err(AppError::Timeout(Phase::Processing))));
+    assert!(received < 5);
+  }
+
+  #[test]
+  fn route_timeout_override() {
+    let cfg = TimeoutConfig {
+      routes: Some(HashMap::from([("/api/v1/process-image".to_owned(), 300)])),
+      ..TimeoutConfig::default()
+    };
+
+    assert_eq!(
+      route_timeout(&cfg, "/api/v1/process-image"),
+      Duration::from_secs(300)
+    );
+    assert_eq!(
+      route_timeout(&cfg, "/scale/{options}/{*uri}"),
+      Duration::from_secs(DEFAULT_REQUEST_SECS)
+    );
+  }
+}
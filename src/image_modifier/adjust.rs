@@ -0,0 +1,251 @@
+use std::sync::LazyLock;
+
+use libvips::{VipsImage, ops};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ImageModifier, Stage, util};
+
+static LEVEL_REGEX: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r"^(br|ct|sat)(-?\d+)$").unwrap());
+static GAMMA_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^gm(\d+(?:\.\d+)?)$").unwrap());
+
+/// Options taking negative values, e.g. `sat-20`, which are split on the `-`
+/// separating options
+pub const SIGNED_OPTIONS: [&str; 3] = ["br", "ct", "sat"];
+
+/// Range of brightness, contrast and saturation
+pub const LEVEL_RANGE: std::ops::RangeInclusive<f64> = -100.0..=100.0;
+
+/// Range of gamma
+pub const GAMMA_RANGE: std::ops::RangeInclusive<f64> = 0.1..=10.0;
+
+/// Colour adjustments, applied in the order normalize, gamma, brightness,
+/// contrast and saturation
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct ColourAdjustments {
+  /// From -100 (black) to 100 (twice as bright), 0 leaves the image as is
+  pub brightness: Option<f64>,
+  /// From -100 (flat grey) to 100 (twice the contrast)
+  pub contrast: Option<f64>,
+  /// From -100 (greyscale) to 100 (twice as saturated)
+  pub saturation: Option<f64>,
+  /// Gamma correction from 0.1 to 10, above 1 lightens the midtones
+  pub gamma: Option<f64>,
+  /// Stretch the lightness to the full range, ignoring the darkest and
+  /// lightest percent of the pixels
+  #[serde(default)]
+  pub normalize: bool,
+}
+
+impl ColourAdjustments {
+  /// Set the adjustment of the option, `None` if it isn't one and an error
+  /// if its value is out of range
+  fn parse(&mut self, opt: &str) -> Option<Result<(), String>> {
+    if opt == "norm" {
+      self.normalize = true;
+      return Some(Ok(()));
+    }
+
+    if let Some(captures) = GAMMA_REGEX.captures(opt) {
+      return Some(match captures[1].parse::<f64>() {
+        Ok(gamma) if GAMMA_RANGE.contains(&gamma) => {
+          self.gamma = Some(gamma);
+          Ok(())
+        }
+        _ => Err(format!("gamma {}", super::between(&GAMMA_RANGE))),
+      });
+    }
+
+    let captures = LEVEL_REGEX.captures(opt)?;
+    let (name, adjustment) = match &captures[1] {
+      "br" => ("brightness", &mut self.brightness),
+      "ct" => ("contrast", &mut self.contrast),
+      _ => ("saturation", &mut self.saturation),
+    };
+    Some(match captures[2].parse::<f64>() {
+      Ok(level) if LEVEL_RANGE.contains(&level) => {
+        *adjustment = Some(level);
+        Ok(())
+      }
+      _ => Err(format!("{} {}", name, super::between(&LEVEL_RANGE))),
+    })
+  }
+}
+
+pub struct AdjustModifier {
+  adjustments: ColourAdjustments,
+}
+
+impl AdjustModifier {
+  pub fn new(adjustments: ColourAdjustments) -> AdjustModifier {
+    AdjustModifier { adjustments }
+  }
+
+  pub fn evaluate(opt: &str, opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    // Every adjustment is applied by the modifier of the first one, so they
+    // are applied in a fixed order
+    let first = opts
+      .iter()
+      .find(|o| ColourAdjustments::default().parse(o).is_some())?;
+    if *first != opt {
+      return None;
+    }
+
+    let mut adjustments = ColourAdjustments::default();
+    for o in opts {
+      if let Some(Err(e)) = adjustments.parse(o) {
+        return Some(Err(e));
+      }
+    }
+
+    Some(Ok(Box::new(AdjustModifier { adjustments })))
+  }
+}
+
+/// Stretch the lightness between its 1st and 99th percentile to the full range
+fn normalize(
+  colour: &VipsImage,
+  interpretation: ops::Interpretation,
+) -> Result<VipsImage, libvips::error::Error> {
+  let lab = ops::colourspace(colour, ops::Interpretation::Lab)?;
+
+  // L runs from 0 to 100, the percentiles come from a histogram of 256 levels
+  let lightness = ops::linear_with_opts(
+    &ops::extract_band(&lab, 0)?,
+    &mut [2.55],
+    &mut [0.0],
+    &ops::LinearOptions { uchar: true },
+  )?;
+  let low = ops::percent(&lightness, 1.0)? as f64 / 2.55;
+  let high = ops::percent(&lightness, 99.0)? as f64 / 2.55;
+  if high <= low {
+    return ops::copy(colour);
+  }
+
+  let factor = 100.0 / (high - low);
+  let lab = ops::linear(
+    &lab,
+    &mut [factor, 1.0, 1.0],
+    &mut [-low * factor, 0.0, 0.0],
+  )?;
+  ops::colourspace(&lab, interpretation)
+}
+
+impl ImageModifier for AdjustModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let adjustments = &self.adjustments;
+
+    // Adjust RGB and greyscale images as they are, anything else as sRGB
+    let img = match img.get_interpretation()? {
+      ops::Interpretation::Srgb
+      | ops::Interpretation::Rgb16
+      | ops::Interpretation::BW
+      | ops::Interpretation::Grey16 => ops::copy(img)?,
+      _ => ops::colourspace(img, ops::Interpretation::Srgb)?,
+    };
+    let interpretation = img.get_interpretation()?;
+    let format = img.get_format()?;
+
+    let (mut colour, alpha) = util::split_alpha(&img)?;
+
+    if adjustments.normalize {
+      colour = ops::cast(&normalize(&colour, interpretation)?, format)?;
+    }
+
+    if let Some(gamma) = adjustments.gamma {
+      colour = ops::gamma_with_opts(&colour, &ops::GammaOptions { exponent: gamma })?;
+    }
+
+    if adjustments.brightness.is_some() || adjustments.contrast.is_some() {
+      let brightness = 1.0 + adjustments.brightness.unwrap_or(0.0) / 100.0;
+      let contrast = 1.0 + adjustments.contrast.unwrap_or(0.0) / 100.0;
+      // Contrast is scaled around the middle of the range
+      let middle = util::max_value(interpretation) / 2.0;
+      let bands = colour.get_bands() as usize;
+      colour = ops::cast(
+        &ops::linear(
+          &colour,
+          &mut vec![brightness * contrast; bands],
+          &mut vec![middle * (1.0 - contrast); bands],
+        )?,
+        format,
+      )?;
+    }
+
+    if let Some(saturation) = adjustments.saturation
+      && colour.get_bands() >= 3
+    {
+      let lch = ops::linear(
+        &ops::colourspace(&colour, ops::Interpretation::Lch)?,
+        &mut [1.0, 1.0 + saturation / 100.0, 1.0],
+        &mut [0.0, 0.0, 0.0],
+      )?;
+      colour = ops::cast(&ops::colourspace(&lch, interpretation)?, format)?;
+    }
+
+    Ok(Some(util::join_alpha(colour, alpha)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Adjust
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(opts: &[&str]) -> ColourAdjustments {
+    let mut adjustments = ColourAdjustments::default();
+    for opt in opts {
+      assert_eq!(adjustments.parse(opt), Some(Ok(())), "{}", opt);
+    }
+    adjustments
+  }
+
+  #[test]
+  fn parse_adjustment_options() {
+    assert_eq!(
+      parse(&["br10", "ct-15", "sat-100", "gm2.2", "norm"]),
+      ColourAdjustments {
+        brightness: Some(10.0),
+        contrast: Some(-15.0),
+        saturation: Some(-100.0),
+        gamma: Some(2.2),
+        normalize: true,
+      }
+    );
+  }
+
+  #[test]
+  fn reject_out_of_range_options() {
+    let mut adjustments = ColourAdjustments::default();
+    for (opt, message) in [
+      ("br101", "brightness must be between -100 and 100"),
+      ("sat-101", "saturation must be between -100 and 100"),
+      ("gm0.05", "gamma must be between 0.1 and 10"),
+      ("gm11", "gamma must be between 0.1 and 10"),
+    ] {
+      assert_eq!(
+        adjustments.parse(opt),
+        Some(Err(message.to_owned())),
+        "{}",
+        opt
+      );
+    }
+    assert_eq!(adjustments.parse("s400x300"), None);
+    assert_eq!(adjustments, ColourAdjustments::default());
+  }
+
+  #[test]
+  fn evaluate_once_for_every_adjustment() {
+    let opts = ["s400x300", "br10", "ct15", "sat-20"];
+    let modifiers = opts
+      .iter()
+      .filter_map(|opt| AdjustModifier::evaluate(opt, &opts))
+      .count();
+    assert_eq!(modifiers, 1);
+  }
+}
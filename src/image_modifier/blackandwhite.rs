@@ -1,13 +1,13 @@
 use libvips::{VipsImage, ops};
 
-use super::ImageModifier;
+use super::{ImageModifier, Stage};
 
 pub struct BlackAndWhiteModifier;
 
 impl BlackAndWhiteModifier {
-  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Box<dyn ImageModifier>> {
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
     if opt == "bw" {
-      Some(Box::new(BlackAndWhiteModifier))
+      Some(Ok(Box::new(BlackAndWhiteModifier)))
     } else {
       None
     }
@@ -18,4 +18,8 @@ impl ImageModifier for BlackAndWhiteModifier {
   fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
     Ok(Some(ops::colourspace(img, ops::Interpretation::BW)?))
   }
+
+  fn stage(&self) -> Stage {
+    Stage::Effect
+  }
 }
@@ -0,0 +1,57 @@
+use std::sync::LazyLock;
+
+use libvips::{VipsImage, ops};
+use regex::Regex;
+
+use super::{ImageModifier, Stage, util};
+
+static BLUR_REGEX: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r"^blur(\d+(?:\.\d+)?)$").unwrap());
+
+/// Range of the sigma of the blur in pixels
+pub const SIGMA_RANGE: std::ops::RangeInclusive<f64> = 0.3..=100.0;
+
+pub struct BlurModifier {
+  sigma: f64,
+}
+
+impl BlurModifier {
+  pub fn new(sigma: f64) -> BlurModifier {
+    BlurModifier { sigma }
+  }
+
+  /// `blur<sigma>`, e.g. `blur8`
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    let captures = BLUR_REGEX.captures(opt)?;
+    match captures[1].parse() {
+      Ok(sigma) if SIGMA_RANGE.contains(&sigma) => Some(Ok(Box::new(BlurModifier { sigma }))),
+      _ => Some(Err(format!("blur {}", super::between(&SIGMA_RANGE)))),
+    }
+  }
+}
+
+impl ImageModifier for BlurModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    Ok(Some(util::premultiplied(img, |img| {
+      ops::gaussblur(img, self.sigma)
+    })?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Finish
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evaluate_blur_sigma() {
+    assert!(BlurModifier::evaluate("blur8", &[]).is_some_and(|m| m.is_ok()));
+    assert!(BlurModifier::evaluate("blur0.5", &[]).is_some_and(|m| m.is_ok()));
+    assert!(BlurModifier::evaluate("blur0.1", &[]).is_some_and(|m| m.is_err()));
+    assert!(BlurModifier::evaluate("blur101", &[]).is_some_and(|m| m.is_err()));
+    assert!(BlurModifier::evaluate("blur", &[]).is_none());
+  }
+}
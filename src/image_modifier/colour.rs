@@ -0,0 +1,78 @@
+/// RGB values of a hex colour like `#1a2b3c` or `#abc`
+pub fn parse_hex(value: &str) -> Option<[f64; 3]> {
+  let hex = value.strip_prefix('#').unwrap_or(value);
+  if !hex.is_ascii() {
+    return None;
+  }
+
+  let channel = |digits: &str| u8::from_str_radix(digits, 16).ok().map(f64::from);
+  match hex.len() {
+    3 => {
+      let mut rgb = [0.0; 3];
+      for (i, digit) in hex.chars().enumerate() {
+        rgb[i] = channel(&digit.to_string().repeat(2))?;
+      }
+      Some(rgb)
+    }
+    6 => Some([
+      channel(&hex[0..2])?,
+      channel(&hex[2..4])?,
+      channel(&hex[4..6])?,
+    ]),
+    _ => None,
+  }
+}
+
+/// CIE Lab of an sRGB colour with a D65 white point, as libvips converts it
+pub fn to_lab([r, g, b]: [f64; 3]) -> [f64; 3] {
+  let linear = |c: f64| {
+    let c = c / 255.0;
+    if c <= 0.04045 {
+      c / 12.92
+    } else {
+      ((c + 0.055) / 1.055).powf(2.4)
+    }
+  };
+  let (r, g, b) = (linear(r), linear(g), linear(b));
+
+  let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+  let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+  let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+  let f = |t: f64| {
+    if t > 216.0 / 24389.0 {
+      t.cbrt()
+    } else {
+      (24389.0 / 27.0 * t + 16.0) / 116.0
+    }
+  };
+  let (fx, fy, fz) = (f(x), f(y), f(z));
+
+  [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_hex_colours() {
+    assert_eq!(parse_hex("#1a2B3c"), Some([26.0, 43.0, 60.0]));
+    assert_eq!(parse_hex("fff"), Some([255.0, 255.0, 255.0]));
+    assert_eq!(parse_hex("#12345"), None);
+    assert_eq!(parse_hex("#gg0000"), None);
+  }
+
+  #[test]
+  fn lab_of_colours() {
+    let close = |[l, a, b]: [f64; 3], expected: [f64; 3]| {
+      (l - expected[0]).abs() < 0.1
+        && (a - expected[1]).abs() < 0.1
+        && (b - expected[2]).abs() < 0.1
+    };
+
+    assert!(close(to_lab([255.0, 255.0, 255.0]), [100.0, 0.0, 0.0]));
+    assert!(close(to_lab([0.0, 0.0, 0.0]), [0.0, 0.0, 0.0]));
+    assert!(close(to_lab([255.0, 0.0, 0.0]), [53.24, 80.09, 67.2]));
+  }
+}
@@ -0,0 +1,84 @@
+use std::sync::LazyLock;
+
+use libvips::{VipsImage, ops};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ImageModifier, Stage, colour, util};
+
+static DUOTONE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+  Regex::new(r"^duo([0-9a-fA-F]{3}|[0-9a-fA-F]{6})_([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$").unwrap()
+});
+
+/// Colours the lightness of the image is mapped between
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Duotone {
+  /// Hex colour of black
+  pub shadows: String,
+  /// Hex colour of white
+  pub highlights: String,
+}
+
+pub struct DuotoneModifier {
+  opts: Duotone,
+}
+
+impl DuotoneModifier {
+  pub fn new(opts: Duotone) -> DuotoneModifier {
+    DuotoneModifier { opts }
+  }
+
+  /// `duo<shadows>_<highlights>`, e.g. `duo1a237e_ffca28`
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    let captures = DUOTONE_REGEX.captures(opt)?;
+
+    Some(Ok(Box::new(DuotoneModifier {
+      opts: Duotone {
+        shadows: captures[1].to_owned(),
+        highlights: captures[2].to_owned(),
+      },
+    })))
+  }
+}
+
+impl ImageModifier for DuotoneModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let shadows = colour::parse_hex(&self.opts.shadows).ok_or("invalid shadows colour")?;
+    let highlights = colour::parse_hex(&self.opts.highlights).ok_or("invalid highlights colour")?;
+
+    let (colour, alpha) = util::split_alpha(&ops::colourspace(img, ops::Interpretation::Srgb)?)?;
+
+    // Grey in each of the red, green and blue bands, mapped from the shadows
+    // at black to the highlights at white
+    let grey = ops::colourspace(
+      &ops::colourspace(&colour, ops::Interpretation::BW)?,
+      ops::Interpretation::Srgb,
+    )?;
+    let colour = ops::linear_with_opts(
+      &grey,
+      &mut [0, 1, 2].map(|band| (highlights[band] - shadows[band]) / 255.0),
+      &mut shadows.clone(),
+      &ops::LinearOptions { uchar: true },
+    )?;
+
+    Ok(Some(util::join_alpha(colour, alpha)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Effect
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evaluate_duotone_colours() {
+    assert!(DuotoneModifier::evaluate("duo1a237e_ffca28", &[]).is_some());
+    assert!(DuotoneModifier::evaluate("duo000_fff", &[]).is_some());
+    assert!(DuotoneModifier::evaluate("duo1a237e", &[]).is_none());
+    assert!(DuotoneModifier::evaluate("duo1a237e_ffca2", &[]).is_none());
+  }
+}
@@ -1,8 +1,282 @@
-use std::sync::Arc;
-
 use libvips::{VipsImage, ops};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ImageModifier, Stage, colour, util};
+
+/// How the product is fitted into the placement box of an environment image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvironmentFit {
+  /// Fit inside the box, centred, leaving the rest of the box transparent
+  #[default]
+  Contain,
+  /// Fill the box, cropping the product around its centre
+  Cover,
+  /// Fit inside the box, centred horizontally and standing on its bottom edge
+  Bottom,
+}
+
+/// How the product is blended with the environment image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvironmentBlend {
+  /// Behind the environment image, showing through its transparent areas
+  #[default]
+  Behind,
+  /// On top of the environment image
+  Over,
+  /// Darkens the environment image, keeping its texture
+  Multiply,
+  /// Lightens the environment image, keeping its texture
+  Screen,
+  Overlay,
+  SoftLight,
+  HardLight,
+  Darken,
+  Lighten,
+}
+
+impl EnvironmentBlend {
+  fn mode(self) -> ops::BlendMode {
+    match self {
+      EnvironmentBlend::Behind => ops::BlendMode::DestOver,
+      EnvironmentBlend::Over => ops::BlendMode::Over,
+      EnvironmentBlend::Multiply => ops::BlendMode::Multiply,
+      EnvironmentBlend::Screen => ops::BlendMode::Screen,
+      EnvironmentBlend::Overlay => ops::BlendMode::Overlay,
+      EnvironmentBlend::SoftLight => ops::BlendMode::SoftLight,
+      EnvironmentBlend::HardLight => ops::BlendMode::HardLight,
+      EnvironmentBlend::Darken => ops::BlendMode::Darken,
+      EnvironmentBlend::Lighten => ops::BlendMode::Lighten,
+    }
+  }
+}
+
+/// Soft drop shadow cast by the product, derived from its alpha
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(default)]
+pub struct EnvironmentShadow {
+  /// Horizontal offset from the product in pixels
+  pub offset_x: i32,
+  /// Vertical offset from the product in pixels
+  pub offset_y: i32,
+  /// Blur radius in pixels, 0 for a hard shadow
+  pub blur: f64,
+  /// Between 0 (invisible) and 1
+  pub opacity: f64,
+  /// Hex colour, e.g. `#000000`
+  pub colour: String,
+}
+
+impl Default for EnvironmentShadow {
+  fn default() -> Self {
+    EnvironmentShadow {
+      offset_x: 0,
+      offset_y: 10,
+      blur: 12.0,
+      opacity: 0.4,
+      colour: "#000000".to_owned(),
+    }
+  }
+}
+
+impl EnvironmentShadow {
+  /// Extra space around the product the blur spreads into
+  fn padding(&self) -> i32 {
+    (self.blur * 1.5).ceil() as i32
+  }
+
+  /// Shadow of the product with `padding()` on every side
+  fn render(&self, product: &VipsImage) -> Result<VipsImage, Box<dyn std::error::Error>> {
+    let rgb = colour::parse_hex(&self.colour).ok_or("invalid shadow colour")?;
+    let product = ops::colourspace(product, ops::Interpretation::Srgb)?;
+    let alpha = if product.image_hasalpha() {
+      ops::extract_band(&product, product.get_bands() - 1)?
+    } else {
+      VipsImage::new_from_image1(&product, 255.0)?
+    };
+
+    let pad = self.padding();
+    let mut alpha = ops::embed(
+      &alpha,
+      pad,
+      pad,
+      product.get_width() + 2 * pad,
+      product.get_height() + 2 * pad,
+    )?;
+    if self.blur > 0.0 {
+      // Most of a gaussian is within 3 sigma, the radius
+      alpha = ops::gaussblur(&alpha, self.blur / 2.0)?;
+    }
+    let alpha = ops::linear_with_opts(
+      &alpha,
+      &mut [self.opacity],
+      &mut [0.0],
+      &ops::LinearOptions { uchar: true },
+    )?;
+
+    // Takes the sRGB interpretation of the product
+    let padded = ops::embed(&product, pad, pad, alpha.get_width(), alpha.get_height())?;
+    let colour = VipsImage::new_from_image(&padded, &rgb)?;
+
+    Ok(ops::bandjoin(&mut [colour, alpha])?)
+  }
+}
+
+/// Point of an environment image in pixels
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub struct Point {
+  pub x: f64,
+  pub y: f64,
+}
+
+/// Corners of the area of an environment image the product is mapped onto,
+/// e.g. a frame on a wall photographed at an angle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub struct EnvironmentCorners {
+  pub top_left: Point,
+  pub top_right: Point,
+  pub bottom_right: Point,
+  pub bottom_left: Point,
+}
 
-use super::ImageModifier;
+/// Largest coordinate of a corner, the largest size of a JPEG image
+pub const MAX_CORNER_COORDINATE: f64 = 65535.0;
+
+impl EnvironmentCorners {
+  fn points(&self) -> [Point; 4] {
+    [
+      self.top_left,
+      self.top_right,
+      self.bottom_right,
+      self.bottom_left,
+    ]
+  }
+
+  /// Whether the corners form a convex quadrilateral, in either direction.
+  /// Corners that aren't finite never do.
+  pub fn is_convex(&self) -> bool {
+    let points = self.points();
+    let turns: Vec<f64> = (0..4)
+      .map(|i| {
+        let (a, b, c) = (points[i], points[(i + 1) % 4], points[(i + 2) % 4]);
+        (b.x - a.x) * (c.y - b.y) - (b.y - a.y) * (c.x - b.x)
+      })
+      .collect();
+
+    turns.iter().all(|turn| *turn > 0.0) || turns.iter().all(|turn| *turn < 0.0)
+  }
+
+  /// Whether every corner is finite and between 0 and `MAX_CORNER_COORDINATE`
+  pub fn in_range(&self) -> bool {
+    self.points().iter().all(|point| {
+      (0.0..=MAX_CORNER_COORDINATE).contains(&point.x)
+        && (0.0..=MAX_CORNER_COORDINATE).contains(&point.y)
+    })
+  }
+
+  /// Whether every corner is inside an image of `width` by `height` pixels
+  pub fn is_within(&self, width: i32, height: i32) -> bool {
+    self.points().iter().all(|point| {
+      (0.0..=width as f64).contains(&point.x) && (0.0..=height as f64).contains(&point.y)
+    })
+  }
+
+  /// Size of the rectangle mapped onto the corners, the average length of
+  /// opposite sides
+  fn size(&self) -> (i32, i32) {
+    let length = |a: Point, b: Point| (b.x - a.x).hypot(b.y - a.y);
+    let width =
+      (length(self.top_left, self.top_right) + length(self.bottom_left, self.bottom_right)) / 2.0;
+    let height =
+      (length(self.top_left, self.bottom_left) + length(self.top_right, self.bottom_right)) / 2.0;
+
+    (
+      (width.round() as i32).max(1),
+      (height.round() as i32).max(1),
+    )
+  }
+
+  /// Smallest box of whole pixels containing the corners, as x, y, width
+  /// and height
+  fn bounds(&self) -> (i32, i32, i32, i32) {
+    let points = self.points();
+    let min_x = points
+      .iter()
+      .map(|p| p.x)
+      .fold(f64::INFINITY, f64::min)
+      .floor();
+    let min_y = points
+      .iter()
+      .map(|p| p.y)
+      .fold(f64::INFINITY, f64::min)
+      .floor();
+    let max_x = points
+      .iter()
+      .map(|p| p.x)
+      .fold(f64::NEG_INFINITY, f64::max)
+      .ceil();
+    let max_y = points
+      .iter()
+      .map(|p| p.y)
+      .fold(f64::NEG_INFINITY, f64::max)
+      .ceil();
+
+    (
+      min_x as i32,
+      min_y as i32,
+      ((max_x - min_x) as i32).max(1),
+      ((max_y - min_y) as i32).max(1),
+    )
+  }
+
+  /// Perspective transform from points of the environment image to points of
+  /// a `width` by `height` rectangle, whose corners are mapped onto ours.
+  /// The coefficients `h` map x, y to
+  /// `((h0 x + h1 y + h2) / (h6 x + h7 y + 1), (h3 x + h4 y + h5) / (h6 x + h7 y + 1))`.
+  fn homography(&self, width: f64, height: f64) -> Option<[f64; 8]> {
+    let targets = [(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)];
+
+    let mut system = [[0.0; 9]; 8];
+    for (i, (point, (u, v))) in self.points().into_iter().zip(targets).enumerate() {
+      let (x, y) = (point.x, point.y);
+      system[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u, u];
+      system[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v, v];
+    }
+
+    solve(system)
+  }
+}
+
+/// Solve the linear system of 8 equations given as augmented rows, by
+/// gaussian elimination with partial pivoting
+fn solve(mut system: [[f64; 9]; 8]) -> Option<[f64; 8]> {
+  for col in 0..8 {
+    let pivot = (col..8).max_by(|a, b| system[*a][col].abs().total_cmp(&system[*b][col].abs()))?;
+    if system[pivot][col].abs() < 1e-12 {
+      return None;
+    }
+    system.swap(col, pivot);
+
+    let pivot_row = system[col];
+    for (row, equation) in system.iter_mut().enumerate() {
+      if row != col {
+        let factor = equation[col] / pivot_row[col];
+        for (value, pivot_value) in equation[col..].iter_mut().zip(&pivot_row[col..]) {
+          *value -= factor * pivot_value;
+        }
+      }
+    }
+  }
+
+  let mut solution = [0.0; 8];
+  for (i, value) in solution.iter_mut().enumerate() {
+    *value = system[i][8] / system[i][i];
+  }
+
+  Some(solution)
+}
 
 #[derive(Clone)]
 pub struct EnvironmentOptions {
@@ -11,48 +285,371 @@ pub struct EnvironmentOptions {
   pub x: i32,
   pub y: i32,
   pub margin_percent: i32,
+  pub fit: EnvironmentFit,
+  pub blend: EnvironmentBlend,
+  pub shadow: Option<EnvironmentShadow>,
+  /// Replaces the placement box when set
+  pub corners: Option<EnvironmentCorners>,
+}
+
+impl EnvironmentOptions {
+  /// Placement box as x, y, width and height. With corners the product is
+  /// laid out in a rectangle of their size which is mapped onto them.
+  fn placement_box(&self) -> (i32, i32, i32, i32) {
+    match &self.corners {
+      Some(corners) => {
+        let (width, height) = corners.size();
+        (0, 0, width, height)
+      }
+      None => (self.x, self.y, self.width, self.height),
+    }
+  }
+
+  /// Size of the box inside the margin, which is a percentage of the
+  /// shortest side of the placement box split between both sides
+  fn inner_size(&self) -> (i32, i32, i32) {
+    let (_, _, box_width, box_height) = self.placement_box();
+    let margin = (self.margin_percent as f64 * 0.01 * box_width.min(box_height) as f64) as i32;
+
+    (
+      (box_width - margin).max(1),
+      (box_height - margin).max(1),
+      margin,
+    )
+  }
+
+  /// Position of a product of the given size within the placement box's
+  /// coordinates
+  fn position(&self, width: i32, height: i32) -> (i32, i32) {
+    let (box_x, box_y, box_width, box_height) = self.placement_box();
+    let (_, _, margin) = self.inner_size();
+    let x = box_x + (box_width - width) / 2;
+
+    match self.fit {
+      EnvironmentFit::Bottom => (x, box_y + box_height - margin / 2 - height),
+      EnvironmentFit::Contain | EnvironmentFit::Cover => (x, box_y + (box_height - height) / 2),
+    }
+  }
+}
+
+/// Map the image with the perspective transform `h` onto the area of the
+/// environment image at x, y of width by height
+fn perspective(
+  img: &VipsImage,
+  h: &[f64; 8],
+  (x, y, width, height): (i32, i32, i32, i32),
+) -> Result<VipsImage, libvips::error::Error> {
+  let grid = ops::xyz(width, height)?;
+  let grid_x = ops::extract_band(&grid, 0)?;
+  let grid_y = ops::extract_band(&grid, 1)?;
+
+  // a x + b y + c for the coordinates of the environment image
+  let plane = |a: f64, b: f64, c: f64| {
+    ops::add(
+      &ops::linear(&grid_x, &mut [a], &mut [a * x as f64 + b * y as f64 + c])?,
+      &ops::linear(&grid_y, &mut [b], &mut [0.0])?,
+    )
+  };
+  let w = plane(h[6], h[7], 1.0)?;
+  let u = ops::divide(&plane(h[0], h[1], h[2])?, &w)?;
+  let v = ops::divide(&plane(h[3], h[4], h[5])?, &w)?;
+
+  // Pixels mapped from outside the image are transparent
+  ops::mapim(img, &ops::bandjoin(&mut [u, v])?)
+}
+
+/// Multiply the alpha of the product at x, y by the mask, returning the
+/// product with the size of the environment image
+fn masked(
+  product: &VipsImage,
+  mask: &VipsImage,
+  (x, y): (i32, i32),
+) -> Result<VipsImage, libvips::error::Error> {
+  let placed = ops::embed(product, x, y, mask.get_width(), mask.get_height())?;
+  let bands = placed.get_bands();
+
+  let colour = ops::extract_band_with_opts(&placed, 0, &ops::ExtractBandOptions { n: bands - 1 })?;
+  let alpha = ops::linear_with_opts(
+    &ops::multiply(&ops::extract_band(&placed, bands - 1)?, mask)?,
+    &mut [1.0 / 255.0],
+    &mut [0.0],
+    &ops::LinearOptions { uchar: true },
+  )?;
+
+  ops::bandjoin(&mut [colour, alpha])
 }
 
 pub struct EnvironmentModifier {
-  env_image: Arc<Vec<u8>>,
+  env_image: VipsImage,
+  /// Greyscale image where white shows the product and black hides it
+  mask: Option<VipsImage>,
   opts: EnvironmentOptions,
 }
 
 impl EnvironmentModifier {
-  pub fn new(env_image: Arc<Vec<u8>>, opts: EnvironmentOptions) -> EnvironmentModifier {
-    EnvironmentModifier { env_image, opts }
+  pub fn new(
+    env_image: VipsImage,
+    mask: Option<VipsImage>,
+    opts: EnvironmentOptions,
+  ) -> EnvironmentModifier {
+    EnvironmentModifier {
+      env_image,
+      mask,
+      opts,
+    }
   }
 }
 
 impl ImageModifier for EnvironmentModifier {
   fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
-    let env_image = VipsImage::new_from_buffer(&self.env_image, "")
-      .map_err(|e| format!("failed to load environment image: {}", e))?;
+    let env_image = &self.env_image;
 
-    // scale input image
+    // scale input image into the box inside the margin
+    let (width, height, _) = self.opts.inner_size();
     let scaled = ops::thumbnail_image_with_opts(
       img,
-      self.opts.width,
+      width,
       &ops::ThumbnailImageOptions {
-        height: self.opts.height,
+        height,
         size: ops::Size::Both,
-        crop: ops::Interesting::Centre,
+        crop: match self.opts.fit {
+          EnvironmentFit::Cover => ops::Interesting::Centre,
+          EnvironmentFit::Contain | EnvironmentFit::Bottom => ops::Interesting::None,
+        },
         output_profile: Some("sRGB".to_owned()),
         input_profile: Some("sRGB".to_owned()),
         ..ops::ThumbnailImageOptions::default()
       },
     )?;
+    let (x, y) = self.opts.position(scaled.get_width(), scaled.get_height());
+
+    // Lay the product out in a rectangle the size of the corners, then map
+    // the rectangle onto them
+    let (scaled, x, y) = match &self.opts.corners {
+      Some(corners) => {
+        // Keeps the rectangle and the mapped area within the environment image
+        if !corners.is_within(env_image.get_width(), env_image.get_height()) {
+          return Err("environment corners outside the environment image".into());
+        }
+
+        let (width, height) = corners.size();
+        let rectangle = ops::embed(&util::with_alpha(&scaled)?, x, y, width, height)?;
+        let h = corners
+          .homography(width as f64, height as f64)
+          .ok_or("environment corners don't form a quadrilateral")?;
+        let bounds = corners.bounds();
+
+        (perspective(&rectangle, &h, bounds)?, bounds.0, bounds.1)
+      }
+      None => (scaled, x, y),
+    };
+
+    let (scaled, x, y) = match &self.mask {
+      Some(mask) => {
+        let mask = ops::extract_band(&ops::colourspace(mask, ops::Interpretation::BW)?, 0)?;
+        // Masks are stretched to the environment image if their sizes differ
+        let mask = ops::thumbnail_image_with_opts(
+          &mask,
+          env_image.get_width(),
+          &ops::ThumbnailImageOptions {
+            height: env_image.get_height(),
+            size: ops::Size::Force,
+            ..ops::ThumbnailImageOptions::default()
+          },
+        )?;
+
+        (masked(&util::with_alpha(&scaled)?, &mask, (x, y))?, 0, 0)
+      }
+      None => (scaled, x, y),
+    };
+
+    let composite = |base: &VipsImage, overlay: &VipsImage, mode, x, y| {
+      ops::composite2_with_opts(
+        base,
+        overlay,
+        mode,
+        &ops::Composite2Options {
+          x,
+          y,
+          ..ops::Composite2Options::default()
+        },
+      )
+    };
+    let shadow = match &self.opts.shadow {
+      Some(shadow) => Some((
+        shadow.render(&scaled)?,
+        x + shadow.offset_x - shadow.padding(),
+        y + shadow.offset_y - shadow.padding(),
+      )),
+      None => None,
+    };
+
+    // The shadow is always beneath the product, behind the environment image
+    // too when the product is
+    let blend = self.opts.blend;
+    let mut output = ops::copy(env_image)?;
+    if blend != EnvironmentBlend::Behind
+      && let Some((shadow, sx, sy)) = &shadow
+    {
+      output = composite(&output, shadow, ops::BlendMode::Over, *sx, *sy)?;
+    }
+    output = composite(&output, &scaled, blend.mode(), x, y)?;
+    if blend == EnvironmentBlend::Behind
+      && let Some((shadow, sx, sy)) = &shadow
+    {
+      output = composite(&output, shadow, ops::BlendMode::DestOver, *sx, *sy)?;
+    }
+
+    Ok(Some(output))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Compose
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn options(fit: EnvironmentFit) -> EnvironmentOptions {
+    EnvironmentOptions {
+      width: 400,
+      height: 200,
+      x: 100,
+      y: 50,
+      margin_percent: 10,
+      fit,
+      blend: EnvironmentBlend::Behind,
+      shadow: None,
+      corners: None,
+    }
+  }
+
+  #[test]
+  fn inner_size_without_margin() {
+    let opts = EnvironmentOptions {
+      margin_percent: 0,
+      ..options(EnvironmentFit::Contain)
+    };
+    assert_eq!(opts.inner_size(), (400, 200, 0));
+  }
+
+  #[test]
+  fn margin_of_shortest_side() {
+    assert_eq!(
+      options(EnvironmentFit::Contain).inner_size(),
+      (380, 180, 20)
+    );
+  }
+
+  fn corners() -> EnvironmentCorners {
+    let point = |x, y| Point { x, y };
+    EnvironmentCorners {
+      top_left: point(110.0, 40.0),
+      top_right: point(290.0, 60.0),
+      bottom_right: point(280.0, 250.0),
+      bottom_left: point(100.0, 230.0),
+    }
+  }
+
+  #[test]
+  fn homography_maps_corners_to_rectangle() {
+    let corners = corners();
+    let h = corners.homography(180.0, 190.0).expect("solvable");
+    let project = |p: Point| {
+      let w = h[6] * p.x + h[7] * p.y + 1.0;
+      (
+        (h[0] * p.x + h[1] * p.y + h[2]) / w,
+        (h[3] * p.x + h[4] * p.y + h[5]) / w,
+      )
+    };
+
+    for (point, (u, v)) in
+      corners
+        .points()
+        .into_iter()
+        .zip([(0.0, 0.0), (180.0, 0.0), (180.0, 190.0), (0.0, 190.0)])
+    {
+      let (pu, pv) = project(point);
+      assert!((pu - u).abs() < 1e-6 && (pv - v).abs() < 1e-6);
+    }
+  }
 
-    // composite with env image
-    Ok(Some(ops::composite2_with_opts(
-      &env_image,
-      &scaled,
-      ops::BlendMode::DestOver,
-      &ops::Composite2Options {
-        x: self.opts.x,
-        y: self.opts.y,
-        ..ops::Composite2Options::default()
+  #[test]
+  fn corners_size_and_bounds() {
+    let corners = corners();
+    assert_eq!(corners.size(), (181, 190));
+    assert_eq!(corners.bounds(), (100, 40, 190, 210));
+  }
+
+  #[test]
+  fn convex_corners() {
+    assert!(corners().is_convex());
+
+    // Top corners swapped, crossing the sides
+    let crossed = EnvironmentCorners {
+      top_left: corners().top_right,
+      top_right: corners().top_left,
+      ..corners()
+    };
+    assert!(!crossed.is_convex());
+  }
+
+  #[test]
+  fn corners_within_scene() {
+    assert!(corners().in_range());
+    assert!(corners().is_within(290, 250));
+    assert!(!corners().is_within(289, 250));
+
+    let far = EnvironmentCorners {
+      bottom_right: Point { x: 1e9, y: 250.0 },
+      ..corners()
+    };
+    assert!(!far.in_range());
+    assert!(!far.is_within(400, 300));
+
+    let infinite = EnvironmentCorners {
+      top_left: Point {
+        x: f64::NAN,
+        y: 40.0,
       },
-    )?))
+      ..corners()
+    };
+    assert!(!infinite.in_range());
+  }
+
+  #[test]
+  fn shadow_padding_covers_blur() {
+    let shadow = EnvironmentShadow {
+      blur: 5.0,
+      ..EnvironmentShadow::default()
+    };
+    assert_eq!(shadow.padding(), 8);
+    assert_eq!(
+      EnvironmentShadow {
+        blur: 0.0,
+        ..shadow
+      }
+      .padding(),
+      0
+    );
+  }
+
+  #[test]
+  fn contain_centres_product() {
+    assert_eq!(
+      options(EnvironmentFit::Contain).position(90, 180),
+      (255, 60)
+    );
+  }
+
+  #[test]
+  fn bottom_stands_on_margin() {
+    // 10px above the bottom edge of the box at y 250
+    assert_eq!(
+      options(EnvironmentFit::Bottom).position(90, 120),
+      (255, 120)
+    );
   }
 }
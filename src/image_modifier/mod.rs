@@ -2,15 +2,56 @@ use libvips::VipsImage;
 
 mod util;
 
+pub mod adjust;
 pub mod blackandwhite;
+pub mod blur;
+pub mod colour;
+pub mod duotone;
 pub mod environment;
 pub mod orientation;
+pub mod pixelate;
 pub mod resize;
 pub mod scale;
+pub mod sepia;
+pub mod sharpen;
+pub mod text;
+pub mod tint;
 pub mod trim;
+pub mod watermark;
+
+/// When a modifier is applied relative to the others, in order. Modifiers of
+/// the same stage are applied in the order they are given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+  /// Orientation and trimming of the source
+  Prepare,
+  /// Colour adjustments of the source
+  Adjust,
+  /// Effects like black and white, sepia, tints and duotones
+  Effect,
+  Scale,
+  Resize,
+  /// Placement of the product into an environment image
+  Compose,
+  /// Blurring, sharpening and pixelation of the image at the output size,
+  /// environment included
+  Finish,
+  /// Watermarks and text, relative to the output size
+  Overlay,
+}
 
 pub trait ImageModifier {
   fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>>;
+
+  fn stage(&self) -> Stage;
 }
 
-pub type ImageModifierEvaluator = fn(&str, &[&str]) -> Option<Box<dyn ImageModifier>>;
+/// Modifier of an option, `None` if the option isn't the modifier's and an
+/// error if its value is invalid
+pub type ImageModifierEvaluator =
+  fn(&str, &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>>;
+
+/// Message for a value outside of the range
+pub fn between<T: std::fmt::Display>(range: &std::ops::RangeInclusive<T>) -> String {
+  format!("must be between {} and {}", range.start(), range.end())
+}
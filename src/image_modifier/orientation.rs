@@ -1,17 +1,17 @@
 use libvips::{VipsImage, ops};
 
-use super::ImageModifier;
+use super::{ImageModifier, Stage};
 
 pub struct OrientationModifier {
   pub portrait: bool,
 }
 
 impl OrientationModifier {
-  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Box<dyn ImageModifier>> {
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
     if opt == "oportrait" || opt == "olandscape" {
-      return Some(Box::new(OrientationModifier {
+      return Some(Ok(Box::new(OrientationModifier {
         portrait: opt == "oportrait",
-      }));
+      })));
     }
 
     None
@@ -33,4 +33,8 @@ impl ImageModifier for OrientationModifier {
 
     Ok(None)
   }
+
+  fn stage(&self) -> Stage {
+    Stage::Prepare
+  }
 }
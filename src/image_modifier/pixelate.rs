@@ -0,0 +1,70 @@
+use std::sync::LazyLock;
+
+use libvips::{VipsImage, ops};
+use regex::Regex;
+
+use super::{ImageModifier, Stage, util};
+
+static PIXELATE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^px(\d+)$").unwrap());
+
+/// Range of the size of the blocks in pixels
+pub const SIZE_RANGE: std::ops::RangeInclusive<i32> = 2..=512;
+
+pub struct PixelateModifier {
+  size: i32,
+}
+
+impl PixelateModifier {
+  pub fn new(size: i32) -> PixelateModifier {
+    PixelateModifier { size }
+  }
+
+  /// `px<size>`, e.g. `px16`
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    let captures = PIXELATE_REGEX.captures(opt)?;
+    match captures[1].parse() {
+      Ok(size) if SIZE_RANGE.contains(&size) => Some(Ok(Box::new(PixelateModifier { size }))),
+      _ => Some(Err(format!("pixelate {}", super::between(&SIZE_RANGE)))),
+    }
+  }
+}
+
+impl ImageModifier for PixelateModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let (width, height) = (img.get_width(), img.get_height());
+    let size = self.size.min(width).min(height).max(1);
+
+    // Average each block, then scale the blocks back up without smoothing
+    let blocks = util::premultiplied(img, |img| ops::shrink(img, size as f64, size as f64))?;
+    let blocks = ops::zoom(&blocks, size, size)?;
+
+    // Blocks at the edges are cut off or repeated to the size of the image
+    Ok(Some(ops::gravity_with_opts(
+      &blocks,
+      ops::CompassDirection::NorthWest,
+      width,
+      height,
+      &ops::GravityOptions {
+        extend: ops::Extend::Copy,
+        ..ops::GravityOptions::default()
+      },
+    )?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Finish
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evaluate_pixelate_size() {
+    assert!(PixelateModifier::evaluate("px16", &[]).is_some_and(|m| m.is_ok()));
+    assert!(PixelateModifier::evaluate("px1", &[]).is_some_and(|m| m.is_err()));
+    assert!(PixelateModifier::evaluate("px513", &[]).is_some_and(|m| m.is_err()));
+    assert!(PixelateModifier::evaluate("px", &[]).is_none());
+  }
+}
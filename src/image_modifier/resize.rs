@@ -3,7 +3,8 @@ use std::sync::LazyLock;
 use libvips::{VipsImage, ops};
 use regex::Regex;
 
-use super::ImageModifier;
+use super::sharpen::Sharpen;
+use super::{ImageModifier, Stage};
 use crate::image_modifier::util;
 
 static RESIZE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^r(w|h)(\d+)$").unwrap());
@@ -11,15 +12,25 @@ static RESIZE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^r(w|h)(\d+
 pub struct ResizeModifier {
   height: bool,
   pixels: i32,
+  /// Sharpening of images scaled down by at least `sharpen::MIN_SHRINK`
+  sharpen: Option<Sharpen>,
 }
 
 impl ResizeModifier {
-  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Box<dyn ImageModifier>> {
+  pub fn evaluate(opt: &str, opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
     if let Some(matches) = RESIZE_REGEX.captures(opt) {
       let height = matches.get(1).unwrap().as_str() == "h";
       let pixels = matches.get(2).unwrap().as_str().parse::<i32>().ok()?;
+      let sharpen = match Sharpen::resize_token(opts).transpose() {
+        Ok(sharpen) => sharpen,
+        Err(e) => return Some(Err(e)),
+      };
 
-      return Some(Box::new(ResizeModifier { height, pixels }));
+      return Some(Ok(Box::new(ResizeModifier {
+        height,
+        pixels,
+        sharpen,
+      })));
     }
 
     None
@@ -28,8 +39,8 @@ impl ResizeModifier {
 
 impl ImageModifier for ResizeModifier {
   fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
-    if self.height {
-      return Ok(Some(ops::thumbnail_image_with_opts(
+    let resized = if self.height {
+      ops::thumbnail_image_with_opts(
         img,
         (self.pixels as f64 * util::aspect(img.get_width(), img.get_height())) as i32,
         &ops::ThumbnailImageOptions {
@@ -40,20 +51,30 @@ impl ImageModifier for ResizeModifier {
           input_profile: Some("sRGB".to_owned()),
           ..ops::ThumbnailImageOptions::default()
         },
-      )?));
-    }
+      )?
+    } else {
+      ops::thumbnail_image_with_opts(
+        img,
+        self.pixels,
+        &ops::ThumbnailImageOptions {
+          height: (self.pixels as f64 * util::aspect(img.get_width(), img.get_height())) as i32,
+          size: ops::Size::Both,
+          crop: ops::Interesting::None,
+          output_profile: Some("sRGB".to_owned()),
+          input_profile: Some("sRGB".to_owned()),
+          ..ops::ThumbnailImageOptions::default()
+        },
+      )?
+    };
 
-    Ok(Some(ops::thumbnail_image_with_opts(
+    Ok(Some(Sharpen::after_resize(
+      self.sharpen.as_ref(),
       img,
-      self.pixels,
-      &ops::ThumbnailImageOptions {
-        height: (self.pixels as f64 * util::aspect(img.get_width(), img.get_height())) as i32,
-        size: ops::Size::Both,
-        crop: ops::Interesting::None,
-        output_profile: Some("sRGB".to_owned()),
-        input_profile: Some("sRGB".to_owned()),
-        ..ops::ThumbnailImageOptions::default()
-      },
+      resized,
     )?))
   }
+
+  fn stage(&self) -> Stage {
+    Stage::Resize
+  }
 }
@@ -3,7 +3,8 @@ use std::sync::LazyLock;
 use libvips::{VipsImage, ops};
 use regex::Regex;
 
-use super::ImageModifier;
+use super::sharpen::Sharpen;
+use super::{ImageModifier, Stage};
 use crate::image_modifier::util;
 
 pub struct ScaleModifier {
@@ -11,49 +12,65 @@ pub struct ScaleModifier {
   margin_percentage: i32,
   size: Option<i32>,
   crop: bool,
+  /// Sharpening of images scaled down by at least `sharpen::MIN_SHRINK`
+  sharpen: Option<Sharpen>,
 }
 
 static SCALE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^s(\d+)x(\d+)$").unwrap());
 static MARGIN_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^m(\d+)$").unwrap());
 
 impl ScaleModifier {
-  pub fn new(aspect: f64, margin_percentage: i32, size: Option<i32>, crop: bool) -> ScaleModifier {
+  pub fn new(
+    aspect: f64,
+    margin_percentage: i32,
+    size: Option<i32>,
+    crop: bool,
+    sharpen: Option<Sharpen>,
+  ) -> ScaleModifier {
     ScaleModifier {
       aspect,
       margin_percentage,
       size,
       crop,
+      sharpen,
     }
   }
 
-  pub fn evaluate(opt: &str, opts: &[&str]) -> Option<Box<dyn ImageModifier>> {
+  pub fn evaluate(opt: &str, opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
     if let Some(captures) = SCALE_REGEX.captures(opt)
-      && let (Ok(width), Ok(height)) = (captures[1].parse(), captures[2].parse()) {
-        let mut sopt = ScaleModifier {
-          aspect: util::aspect(width, height),
-          margin_percentage: 0,
-          size: None,
-          crop: true,
-        };
-
-        // Check if there's a margin option
-        for o in opts {
-          if let Some(margin_captures) = MARGIN_REGEX.captures(o)
-            && let Ok(margin) = margin_captures[1].parse() {
-              sopt.margin_percentage = margin;
-              break;
-            }
+      && let (Ok(width), Ok(height)) = (captures[1].parse(), captures[2].parse())
+    {
+      let sharpen = match Sharpen::resize_token(opts).transpose() {
+        Ok(sharpen) => sharpen,
+        Err(e) => return Some(Err(e)),
+      };
+      let mut sopt = ScaleModifier {
+        aspect: util::aspect(width, height),
+        margin_percentage: 0,
+        size: None,
+        crop: true,
+        sharpen,
+      };
+
+      // Check if there's a margin option
+      for o in opts {
+        if let Some(margin_captures) = MARGIN_REGEX.captures(o)
+          && let Ok(margin) = margin_captures[1].parse()
+        {
+          sopt.margin_percentage = margin;
+          break;
         }
-
-        return Some(Box::new(sopt));
       }
 
+      return Some(Ok(Box::new(sopt)));
+    }
+
     None
   }
-}
 
-impl ImageModifier for ScaleModifier {
-  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+  /// Width and height of the image inside the margin, then of the area
+  /// including it
+  fn layout(&self, img: &VipsImage) -> (i32, i32, i32, i32) {
     let source_width = img.get_width() as f64;
     let source_height = img.get_height() as f64;
 
@@ -86,6 +103,24 @@ impl ImageModifier for ScaleModifier {
       area_height = base;
     }
 
+    (new_width, new_height, area_width, area_height)
+  }
+
+  /// Whether the image is scaled up to fill the area
+  pub fn upscales(&self, img: &VipsImage) -> bool {
+    let (width, height, _, _) = self.layout(img);
+    let x = width as f64 / img.get_width() as f64;
+    let y = height as f64 / img.get_height() as f64;
+
+    // Cropped images cover the area, others fit inside it
+    (if self.crop { x.max(y) } else { x.min(y) }) > 1.0
+  }
+}
+
+impl ImageModifier for ScaleModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let (new_width, new_height, area_width, area_height) = self.layout(img);
+
     let thumb = ops::thumbnail_image_with_opts(
       img,
       new_width,
@@ -101,6 +136,8 @@ impl ImageModifier for ScaleModifier {
         ..ops::ThumbnailImageOptions::default()
       },
     )?;
+    // Sharpened before the margin is added, so its edges aren't
+    let thumb = Sharpen::after_resize(self.sharpen.as_ref(), img, thumb)?;
 
     Ok(Some(ops::gravity_with_opts(
       &thumb,
@@ -113,4 +150,8 @@ impl ImageModifier for ScaleModifier {
       },
     )?))
   }
+
+  fn stage(&self) -> Stage {
+    Stage::Scale
+  }
 }
@@ -0,0 +1,37 @@
+use libvips::{VipsImage, ops};
+
+use super::{ImageModifier, Stage, util};
+
+/// Classic sepia recombination of the red, green and blue bands
+const SEPIA: [f64; 9] = [
+  0.393, 0.769, 0.189, //
+  0.349, 0.686, 0.168, //
+  0.272, 0.534, 0.131,
+];
+
+pub struct SepiaModifier;
+
+impl SepiaModifier {
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    if opt == "sepia" {
+      Some(Ok(Box::new(SepiaModifier)))
+    } else {
+      None
+    }
+  }
+}
+
+impl ImageModifier for SepiaModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let (colour, alpha) = util::split_alpha(&ops::colourspace(img, ops::Interpretation::Srgb)?)?;
+
+    let matrix = VipsImage::image_new_matrix_from_array(3, 3, &SEPIA)?;
+    let colour = ops::cast(&ops::recomb(&colour, &matrix)?, ops::BandFormat::Uchar)?;
+
+    Ok(Some(util::join_alpha(colour, alpha)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Effect
+  }
+}
@@ -0,0 +1,191 @@
+use std::sync::LazyLock;
+
+use libvips::{VipsImage, ops};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ImageModifier, Stage, util};
+
+static SHARPEN_REGEX: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r"^sh(?:(\d+(?:\.\d+)?)(?:_(\d+(?:\.\d+)?))?)?$").unwrap());
+
+/// Range of the sigma of the unsharp mask
+pub const SIGMA_RANGE: std::ops::RangeInclusive<f64> = 0.1..=10.0;
+
+/// Range of the amount of sharpening
+pub const AMOUNT_RANGE: std::ops::RangeInclusive<f64> = 0.0..=20.0;
+
+/// Images are sharpened after resizing when they were scaled down by at
+/// least this factor
+pub const MIN_SHRINK: f64 = 2.0;
+
+/// Unsharp mask applied to the lightness of the image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Sharpen {
+  /// Radius of the mask in pixels, from 0.1 to 10
+  #[serde(default = "default_sigma")]
+  pub sigma: f64,
+  /// Sharpening of edges, from 0 to 20
+  #[serde(default = "default_amount")]
+  pub amount: f64,
+  /// Sharpening of flat areas, from 0 to 20
+  #[serde(default)]
+  pub flat: f64,
+  /// Difference in lightness between flat areas and edges, from 0 to 100
+  #[serde(default = "default_threshold")]
+  pub threshold: f64,
+}
+
+fn default_sigma() -> f64 {
+  0.5
+}
+
+fn default_amount() -> f64 {
+  3.0
+}
+
+fn default_threshold() -> f64 {
+  2.0
+}
+
+impl Default for Sharpen {
+  fn default() -> Self {
+    Self {
+      sigma: default_sigma(),
+      amount: default_amount(),
+      flat: 0.0,
+      threshold: default_threshold(),
+    }
+  }
+}
+
+impl Sharpen {
+  /// Sharpening of a `sh`, `sh<sigma>` or `sh<sigma>_<amount>` option
+  pub fn token(opt: &str) -> Option<Result<Sharpen, String>> {
+    let captures = SHARPEN_REGEX.captures(opt)?;
+    let mut sharpen = Sharpen::default();
+
+    if let Some(sigma) = captures.get(1) {
+      sharpen.sigma = sigma.as_str().parse().ok()?;
+    }
+    if let Some(amount) = captures.get(2) {
+      sharpen.amount = amount.as_str().parse().ok()?;
+    }
+
+    Some(if !SIGMA_RANGE.contains(&sharpen.sigma) {
+      Err(format!("sharpen sigma {}", super::between(&SIGMA_RANGE)))
+    } else if !AMOUNT_RANGE.contains(&sharpen.amount) {
+      Err(format!("sharpen amount {}", super::between(&AMOUNT_RANGE)))
+    } else {
+      Ok(sharpen)
+    })
+  }
+
+  /// Sharpening after resizes of an `ash` option, which takes the same
+  /// parameters as `sh`, e.g. `ash1_4`
+  pub fn resize_token(opts: &[&str]) -> Option<Result<Sharpen, String>> {
+    opts
+      .iter()
+      .find_map(|opt| opt.strip_prefix('a').and_then(Sharpen::token))
+  }
+
+  /// Sharpen the colour bands of the image, leaving the alpha band as is
+  pub fn apply(&self, img: &VipsImage) -> Result<VipsImage, libvips::error::Error> {
+    let (colour, alpha) = util::split_alpha(img)?;
+    let colour = ops::sharpen_with_opts(
+      &colour,
+      &ops::SharpenOptions {
+        sigma: self.sigma,
+        x1: self.threshold,
+        m1: self.flat,
+        m2: self.amount,
+        ..ops::SharpenOptions::default()
+      },
+    )?;
+
+    util::join_alpha(colour, alpha)
+  }
+
+  /// Sharpen a resized image if it was scaled down by at least `MIN_SHRINK`
+  pub fn after_resize(
+    sharpen: Option<&Sharpen>,
+    source: &VipsImage,
+    resized: VipsImage,
+  ) -> Result<VipsImage, libvips::error::Error> {
+    match sharpen {
+      Some(sharpen)
+        if source.get_width() as f64 >= resized.get_width() as f64 * MIN_SHRINK
+          || source.get_height() as f64 >= resized.get_height() as f64 * MIN_SHRINK =>
+      {
+        sharpen.apply(&resized)
+      }
+      _ => Ok(resized),
+    }
+  }
+}
+
+pub struct SharpenModifier {
+  opts: Sharpen,
+}
+
+impl SharpenModifier {
+  pub fn new(opts: Sharpen) -> SharpenModifier {
+    SharpenModifier { opts }
+  }
+
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    Some(
+      Sharpen::token(opt)?.map(|opts| Box::new(SharpenModifier { opts }) as Box<dyn ImageModifier>),
+    )
+  }
+}
+
+impl ImageModifier for SharpenModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    Ok(Some(self.opts.apply(img)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Finish
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn token_sharpening() {
+    assert_eq!(Sharpen::token("sh"), Some(Ok(Sharpen::default())));
+    assert_eq!(
+      Sharpen::token("sh1.5_4"),
+      Some(Ok(Sharpen {
+        sigma: 1.5,
+        amount: 4.0,
+        ..Sharpen::default()
+      }))
+    );
+    assert_eq!(
+      Sharpen::token("sh0"),
+      Some(Err("sharpen sigma must be between 0.1 and 10".to_owned()))
+    );
+    assert_eq!(
+      Sharpen::token("sh1_21"),
+      Some(Err("sharpen amount must be between 0 and 20".to_owned()))
+    );
+    assert_eq!(Sharpen::token("s400x300"), None);
+  }
+
+  #[test]
+  fn resize_token_sharpening() {
+    assert_eq!(
+      Sharpen::resize_token(&["s400x300", "ash0.8"]),
+      Some(Ok(Sharpen {
+        sigma: 0.8,
+        ..Sharpen::default()
+      }))
+    );
+    assert_eq!(Sharpen::resize_token(&["s400x300", "sh"]), None);
+  }
+}
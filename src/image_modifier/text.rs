@@ -0,0 +1,278 @@
+use libvips::{VipsImage, ops};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::watermark::Gravity;
+use super::{ImageModifier, Stage, colour, util};
+
+/// Maximum length of the text of a `tx` option, in characters
+pub const MAX_TOKEN_TEXT_LENGTH: usize = 100;
+
+/// Caption, price tag or label rendered onto images
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TextOverlay {
+  /// Plain text, markup is escaped
+  pub text: String,
+  /// Font family, e.g. `sans` or `DejaVu Serif Bold`
+  #[serde(default = "default_font")]
+  pub font: String,
+  /// Font size in pixels
+  #[serde(default = "default_size")]
+  pub size: i32,
+  /// Hex colour of the text
+  #[serde(default = "default_colour")]
+  pub colour: String,
+  /// Hex colour of a box behind the text, none by default
+  pub background: Option<String>,
+  /// Space between the text and the edges of the background box in pixels
+  #[serde(default = "default_padding")]
+  pub padding: i32,
+  #[serde(default = "default_gravity")]
+  pub gravity: Gravity,
+  /// Horizontal distance from the edge of the gravity in pixels
+  #[serde(default)]
+  pub offset_x: i32,
+  /// Vertical distance from the edge of the gravity in pixels
+  #[serde(default)]
+  pub offset_y: i32,
+  /// Width lines wrap at, relative to the width of the image from 0 to 1
+  #[serde(default = "default_max_width")]
+  pub max_width: f64,
+}
+
+fn default_font() -> String {
+  "sans".to_owned()
+}
+
+fn default_size() -> i32 {
+  24
+}
+
+fn default_colour() -> String {
+  "#000000".to_owned()
+}
+
+fn default_padding() -> i32 {
+  8
+}
+
+fn default_gravity() -> Gravity {
+  Gravity::South
+}
+
+fn default_max_width() -> f64 {
+  0.9
+}
+
+impl TextOverlay {
+  /// Overlay with the default style for the text
+  pub fn new(text: String) -> Self {
+    Self {
+      text,
+      font: default_font(),
+      size: default_size(),
+      colour: default_colour(),
+      background: None,
+      padding: default_padding(),
+      gravity: default_gravity(),
+      offset_x: 0,
+      offset_y: 0,
+      max_width: default_max_width(),
+    }
+  }
+
+  /// Size, padding and offsets multiplied by the density
+  pub fn scaled(&self, factor: f64) -> Self {
+    let scale = |pixels: i32| (pixels as f64 * factor).round() as i32;
+
+    Self {
+      size: scale(self.size),
+      padding: scale(self.padding),
+      offset_x: scale(self.offset_x),
+      offset_y: scale(self.offset_y),
+      ..self.clone()
+    }
+  }
+
+  /// Lines are aligned to the side of the gravity
+  fn align(&self) -> ops::Align {
+    match self.gravity {
+      Gravity::West | Gravity::NorthWest | Gravity::SouthWest => ops::Align::Low,
+      Gravity::East | Gravity::NorthEast | Gravity::SouthEast => ops::Align::High,
+      Gravity::Centre | Gravity::North | Gravity::South => ops::Align::Centre,
+    }
+  }
+}
+
+/// Escape the characters Pango markup gives a meaning to
+fn escape_markup(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      c => escaped.push(c),
+    }
+  }
+
+  escaped
+}
+
+/// Text of a `tx` option, where every character other than an ASCII letter
+/// or digit is written as `~` and the hex value of each of its UTF-8 bytes,
+/// e.g. `SOLD~20OUT` for "SOLD OUT". `-` separates options and `%` is
+/// decoded from the URL, so neither can stand for themselves.
+fn unescape_token(token: &str) -> Option<String> {
+  let mut bytes = Vec::with_capacity(token.len());
+  let mut chars = token.bytes();
+  while let Some(byte) = chars.next() {
+    match byte {
+      b'~' => {
+        let digits = [chars.next()?, chars.next()?];
+        let digits = std::str::from_utf8(&digits).ok()?;
+        bytes.push(u8::from_str_radix(digits, 16).ok()?);
+      }
+      byte if byte.is_ascii_alphanumeric() => bytes.push(byte),
+      _ => return None,
+    }
+  }
+
+  String::from_utf8(bytes).ok()
+}
+
+fn hex([r, g, b]: [f64; 3]) -> String {
+  format!("#{:02x}{:02x}{:02x}", r as u8, g as u8, b as u8)
+}
+
+pub struct TextModifier {
+  opts: TextOverlay,
+}
+
+impl TextModifier {
+  pub fn new(opts: TextOverlay) -> TextModifier {
+    TextModifier { opts }
+  }
+
+  /// Overlay of a `tx<text>` option, `None` for other options
+  pub fn token(opt: &str) -> Option<Result<TextOverlay, String>> {
+    let text = unescape_token(opt.strip_prefix("tx")?);
+
+    Some(match text {
+      Some(text) if text.trim().is_empty() => Err("empty text".to_owned()),
+      Some(text) if text.chars().count() > MAX_TOKEN_TEXT_LENGTH => Err(format!(
+        "text longer than {} characters",
+        MAX_TOKEN_TEXT_LENGTH
+      )),
+      Some(text) => Ok(TextOverlay::new(text)),
+      None => Err(
+        "characters other than letters and digits must be escaped as ~XX UTF-8 bytes".to_owned(),
+      ),
+    })
+  }
+}
+
+impl ImageModifier for TextModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let opts = &self.opts;
+    let colour = colour::parse_hex(&opts.colour).ok_or("invalid text colour")?;
+    let background = match &opts.background {
+      Some(background) => Some(colour::parse_hex(background).ok_or("invalid background colour")?),
+      None => None,
+    };
+    let padding = if background.is_some() {
+      opts.padding
+    } else {
+      0
+    };
+
+    let max_width = (img.get_width() as f64 * opts.max_width) as i32 - 2 * padding;
+    let text = ops::text_with_opts(
+      &format!(
+        "<span foreground=\"{}\">{}</span>",
+        hex(colour),
+        escape_markup(&opts.text)
+      ),
+      &ops::TextOptions {
+        font: Some(format!("{} {}", opts.font, opts.size)),
+        width: max_width.max(1),
+        align: opts.align(),
+        // Points are pixels at 72 dpi
+        dpi: 72,
+        rgba: true,
+        ..ops::TextOptions::default()
+      },
+    )?;
+
+    // The box takes the sRGB interpretation of the text
+    let label = ops::embed(
+      &text,
+      padding,
+      padding,
+      text.get_width() + 2 * padding,
+      text.get_height() + 2 * padding,
+    )?;
+    let label = match background {
+      Some([r, g, b]) => {
+        let label_box = VipsImage::new_from_image(&label, &[r, g, b, 255.0])?;
+        util::stamp(&label_box, &label, 0, 0)?
+      }
+      None => label,
+    };
+
+    let (x, y) = opts.gravity.position(
+      img.get_width(),
+      img.get_height(),
+      (label.get_width(), label.get_height()),
+      (opts.offset_x, opts.offset_y),
+    );
+
+    Ok(Some(util::stamp(img, &label, x, y)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Overlay
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn escape_markup_characters() {
+    assert_eq!(
+      escape_markup("<b>Tom & \"Jerry's\"</b>"),
+      "&lt;b&gt;Tom &amp; &quot;Jerry&apos;s&quot;&lt;/b&gt;"
+    );
+  }
+
+  #[test]
+  fn token_unescapes_text() {
+    let overlay = TextModifier::token("txSOLD~20OUT~20~2D~2050~25~20~C3~A9")
+      .expect("text option")
+      .expect("valid text");
+    assert_eq!(overlay.text, "SOLD OUT - 50% é");
+
+    assert!(
+      TextModifier::token("txSOLD OUT")
+        .expect("text option")
+        .is_err()
+    );
+    assert!(
+      TextModifier::token("txSOLD~2")
+        .expect("text option")
+        .is_err()
+    );
+    assert!(TextModifier::token("tx~C3").expect("text option").is_err());
+    assert!(TextModifier::token("s400x300").is_none());
+  }
+
+  #[test]
+  fn token_limits_length() {
+    let token = format!("tx{}", "a".repeat(MAX_TOKEN_TEXT_LENGTH + 1));
+    assert!(TextModifier::token(&token).expect("text option").is_err());
+  }
+}
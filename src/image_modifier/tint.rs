@@ -0,0 +1,62 @@
+use libvips::{VipsImage, ops};
+
+use super::{ImageModifier, Stage, colour, util};
+
+pub struct TintModifier {
+  colour: String,
+}
+
+impl TintModifier {
+  pub fn new(colour: String) -> TintModifier {
+    TintModifier { colour }
+  }
+
+  /// `tint<hex>`, e.g. `tintc62828`
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
+    let hex = opt.strip_prefix("tint")?;
+    colour::parse_hex(hex)?;
+
+    Some(Ok(Box::new(TintModifier {
+      colour: hex.to_owned(),
+    })))
+  }
+}
+
+impl ImageModifier for TintModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let tint = colour::parse_hex(&self.colour).ok_or("invalid tint colour")?;
+    let [_, a, b] = colour::to_lab(tint);
+
+    let (colour, alpha) = util::split_alpha(&ops::colourspace(img, ops::Interpretation::Srgb)?)?;
+
+    // Keep the lightness of the image with the hue and chroma of the tint
+    let lab = ops::linear(
+      &ops::colourspace(&colour, ops::Interpretation::Lab)?,
+      &mut [1.0, 0.0, 0.0],
+      &mut [0.0, a, b],
+    )?;
+    let colour = ops::cast(
+      &ops::colourspace(&lab, ops::Interpretation::Srgb)?,
+      ops::BandFormat::Uchar,
+    )?;
+
+    Ok(Some(util::join_alpha(colour, alpha)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Effect
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evaluate_tint_colour() {
+    assert!(TintModifier::evaluate("tintc62828", &[]).is_some());
+    assert!(TintModifier::evaluate("tintfff", &[]).is_some());
+    assert!(TintModifier::evaluate("tint", &[]).is_none());
+    assert!(TintModifier::evaluate("tintred", &[]).is_none());
+  }
+}
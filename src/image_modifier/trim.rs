@@ -1,6 +1,6 @@
 use libvips::{VipsImage, ops};
 
-use super::ImageModifier;
+use super::{ImageModifier, Stage};
 
 pub struct TrimModifier {
   background: Vec<f64>,
@@ -11,11 +11,11 @@ impl TrimModifier {
     TrimModifier { background }
   }
 
-  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Box<dyn ImageModifier>> {
+  pub fn evaluate(opt: &str, _opts: &[&str]) -> Option<Result<Box<dyn ImageModifier>, String>> {
     if opt == "tr" {
-      Some(Box::new(TrimModifier {
+      Some(Ok(Box::new(TrimModifier {
         background: vec![255.0, 255.0, 255.0],
-      }))
+      })))
     } else {
       None
     }
@@ -36,4 +36,8 @@ impl ImageModifier for TrimModifier {
       img, area.0, area.1, area.2, area.3,
     )?))
   }
+
+  fn stage(&self) -> Stage {
+    Stage::Prepare
+  }
 }
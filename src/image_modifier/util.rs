@@ -1,3 +1,93 @@
+use libvips::{VipsImage, ops};
+
 pub fn aspect(width: i32, height: i32) -> f64 {
   width.max(height) as f64 / width.min(height) as f64
 }
+
+/// Add an opaque alpha band to images without one
+pub fn with_alpha(img: &VipsImage) -> Result<VipsImage, libvips::error::Error> {
+  if img.image_hasalpha() {
+    ops::copy(img)
+  } else {
+    ops::bandjoin_const(img, &mut [255.0])
+  }
+}
+
+/// Draw the overlay over the image at x, y. Opaque images stay opaque.
+pub fn stamp(
+  img: &VipsImage,
+  overlay: &VipsImage,
+  x: i32,
+  y: i32,
+) -> Result<VipsImage, libvips::error::Error> {
+  let output = ops::composite2_with_opts(
+    img,
+    overlay,
+    ops::BlendMode::Over,
+    &ops::Composite2Options {
+      x,
+      y,
+      ..ops::Composite2Options::default()
+    },
+  )?;
+
+  if img.image_hasalpha() {
+    Ok(output)
+  } else {
+    ops::extract_band_with_opts(
+      &output,
+      0,
+      &ops::ExtractBandOptions {
+        n: output.get_bands() - 1,
+      },
+    )
+  }
+}
+
+/// Split the image into its colour bands and its alpha band, if it has one
+pub fn split_alpha(
+  img: &VipsImage,
+) -> Result<(VipsImage, Option<VipsImage>), libvips::error::Error> {
+  if !img.image_hasalpha() {
+    return Ok((ops::copy(img)?, None));
+  }
+
+  let bands = img.get_bands();
+  let colour = ops::extract_band_with_opts(img, 0, &ops::ExtractBandOptions { n: bands - 1 })?;
+  let alpha = ops::extract_band(img, bands - 1)?;
+  Ok((colour, Some(alpha)))
+}
+
+/// Join the alpha band split off by `split_alpha` back onto the colour bands
+pub fn join_alpha(
+  colour: VipsImage,
+  alpha: Option<VipsImage>,
+) -> Result<VipsImage, libvips::error::Error> {
+  match alpha {
+    Some(alpha) => ops::bandjoin(&mut [colour, alpha]),
+    None => Ok(colour),
+  }
+}
+
+/// Largest value of a band of images with the interpretation
+pub fn max_value(interpretation: ops::Interpretation) -> f64 {
+  match interpretation {
+    ops::Interpretation::Rgb16 | ops::Interpretation::Grey16 => 65535.0,
+    _ => 255.0,
+  }
+}
+
+/// Apply the operation with the colour premultiplied by the alpha, so
+/// transparent pixels don't bleed into their neighbours
+pub fn premultiplied(
+  img: &VipsImage,
+  operation: impl FnOnce(&VipsImage) -> Result<VipsImage, libvips::error::Error>,
+) -> Result<VipsImage, libvips::error::Error> {
+  if !img.image_hasalpha() {
+    return operation(img);
+  }
+
+  let format = img.get_format()?;
+  let output = ops::unpremultiply(&operation(&ops::premultiply(img)?)?)?;
+  ops::cast(&output, format)
+}
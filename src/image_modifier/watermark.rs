@@ -0,0 +1,206 @@
+use std::sync::LazyLock;
+
+use libvips::{VipsImage, ops};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ImageModifier, Stage, util};
+
+static WATERMARK_REGEX: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r"^wm([A-Za-z0-9_]+)$").unwrap());
+
+/// Edge or corner of the image a watermark is placed against
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Gravity {
+  Centre,
+  North,
+  NorthEast,
+  East,
+  #[default]
+  SouthEast,
+  South,
+  SouthWest,
+  West,
+  NorthWest,
+}
+
+/// Overlay stamped onto rendered images, like a logo or a "SAMPLE" text
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Watermark {
+  /// Storage key of the overlay image
+  pub path: String,
+  #[serde(default)]
+  pub gravity: Gravity,
+  /// Horizontal distance from the edge of the gravity in pixels
+  #[serde(default)]
+  pub offset_x: i32,
+  /// Vertical distance from the edge of the gravity in pixels
+  #[serde(default)]
+  pub offset_y: i32,
+  /// Width of the overlay relative to the width of the image, from 0 to 1
+  #[serde(default = "default_scale")]
+  pub scale: f64,
+  /// From 0 (invisible) to 1
+  #[serde(default = "default_opacity")]
+  pub opacity: f64,
+  /// Repeat the overlay across the whole image, ignoring gravity and offsets
+  #[serde(default)]
+  pub tile: bool,
+}
+
+fn default_scale() -> f64 {
+  0.25
+}
+
+fn default_opacity() -> f64 {
+  1.0
+}
+
+impl Watermark {
+  /// Offsets multiplied by the density
+  pub fn scaled(&self, factor: f64) -> Self {
+    Self {
+      offset_x: (self.offset_x as f64 * factor).round() as i32,
+      offset_y: (self.offset_y as f64 * factor).round() as i32,
+      ..self.clone()
+    }
+  }
+}
+
+impl Gravity {
+  /// Position of an overlay of the given size on an image of `width` by
+  /// `height` pixels, `offset` pixels away from the edges of the gravity
+  pub fn position(
+    self,
+    width: i32,
+    height: i32,
+    overlay: (i32, i32),
+    offset: (i32, i32),
+  ) -> (i32, i32) {
+    let (overlay_width, overlay_height) = overlay;
+    let (offset_x, offset_y) = offset;
+    let left = offset_x;
+    let centre = (width - overlay_width) / 2 + offset_x;
+    let right = width - overlay_width - offset_x;
+    let top = offset_y;
+    let middle = (height - overlay_height) / 2 + offset_y;
+    let bottom = height - overlay_height - offset_y;
+
+    match self {
+      Gravity::Centre => (centre, middle),
+      Gravity::North => (centre, top),
+      Gravity::NorthEast => (right, top),
+      Gravity::East => (right, middle),
+      Gravity::SouthEast => (right, bottom),
+      Gravity::South => (centre, bottom),
+      Gravity::SouthWest => (left, bottom),
+      Gravity::West => (left, middle),
+      Gravity::NorthWest => (left, top),
+    }
+  }
+}
+
+pub struct WatermarkModifier {
+  overlay: VipsImage,
+  opts: Watermark,
+}
+
+impl WatermarkModifier {
+  pub fn new(overlay: VipsImage, opts: Watermark) -> WatermarkModifier {
+    WatermarkModifier { overlay, opts }
+  }
+
+  /// Name of the configured watermark of a `wm<name>` option
+  pub fn token(opt: &str) -> Option<&str> {
+    WATERMARK_REGEX
+      .captures(opt)
+      .and_then(|matches| matches.get(1))
+      .map(|name| name.as_str())
+  }
+}
+
+impl ImageModifier for WatermarkModifier {
+  fn apply(&self, img: &VipsImage) -> Result<Option<VipsImage>, Box<dyn std::error::Error>> {
+    let (width, height) = (img.get_width(), img.get_height());
+
+    let overlay = util::with_alpha(&ops::colourspace(&self.overlay, ops::Interpretation::Srgb)?)?;
+    let target_width = (width as f64 * self.opts.scale).round().max(1.0);
+    let overlay = ops::resize(&overlay, target_width / overlay.get_width() as f64)?;
+
+    // Fade the alpha band only
+    let bands = overlay.get_bands() as usize;
+    let mut factors = vec![1.0; bands];
+    factors[bands - 1] = self.opts.opacity;
+    let overlay = ops::linear_with_opts(
+      &overlay,
+      &mut factors,
+      &mut vec![0.0; bands],
+      &ops::LinearOptions { uchar: true },
+    )?;
+
+    let (overlay, x, y) = if self.opts.tile {
+      let across = width / overlay.get_width() + 1;
+      let down = height / overlay.get_height() + 1;
+      let tiled = ops::replicate(&overlay, across, down)?;
+      (ops::extract_area(&tiled, 0, 0, width, height)?, 0, 0)
+    } else {
+      let (x, y) = self.opts.gravity.position(
+        width,
+        height,
+        (overlay.get_width(), overlay.get_height()),
+        (self.opts.offset_x, self.opts.offset_y),
+      );
+      (overlay, x, y)
+    };
+
+    Ok(Some(util::stamp(img, &overlay, x, y)?))
+  }
+
+  fn stage(&self) -> Stage {
+    Stage::Overlay
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn watermark(gravity: Gravity) -> Watermark {
+    Watermark {
+      path: "watermarks/logo.png".to_owned(),
+      gravity,
+      offset_x: 10,
+      offset_y: 20,
+      scale: default_scale(),
+      opacity: default_opacity(),
+      tile: false,
+    }
+  }
+
+  #[test]
+  fn position_by_gravity() {
+    let position = |gravity| {
+      let watermark = watermark(gravity);
+      gravity.position(
+        400,
+        300,
+        (100, 50),
+        (watermark.offset_x, watermark.offset_y),
+      )
+    };
+
+    assert_eq!(position(Gravity::SouthEast), (290, 230));
+    assert_eq!(position(Gravity::NorthWest), (10, 20));
+    assert_eq!(position(Gravity::Centre), (160, 145));
+    assert_eq!(position(Gravity::South), (160, 230));
+  }
+
+  #[test]
+  fn token_names_watermark() {
+    assert_eq!(WatermarkModifier::token("wmsample"), Some("sample"));
+    assert_eq!(WatermarkModifier::token("wm"), None);
+    assert_eq!(WatermarkModifier::token("s400x300"), None);
+  }
+}
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::path_template::ExtensionPolicy;
+use super::report::FailureMode;
+use super::validation::FieldError;
+use super::{EnvironmentImage, ImageConfiguration, ImageProcessingRequest};
+
+#[derive(Deserialize, ToSchema)]
+#[allow(unused)]
+pub struct ProcessBatchForm {
+  details: BatchRequest,
+  /// Uploaded sources, in fields named by the `image` of each source
+  #[schema(format = Binary, content_media_type = "application/octet-stream")]
+  images: Vec<String>,
+}
+
+/// Configurations shared by every source of a batch
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct BatchRequest {
+  pub sources: Vec<BatchSource>,
+  pub min_size: Option<i32>,
+  #[serde(default)]
+  pub save_original: bool,
+  pub portrait_environment_image: Option<EnvironmentImage>,
+  pub landscape_environment_image: Option<EnvironmentImage>,
+  pub environment_images: Option<Vec<EnvironmentImage>>,
+  pub generate_alternative: Option<bool>,
+  pub max_age: Option<u32>,
+  pub cache_control: Option<String>,
+  pub content_disposition: Option<String>,
+  pub storage_class: Option<String>,
+  pub metadata: Option<HashMap<String, String>>,
+  /// Applies to every source on its own
+  #[serde(default)]
+  pub failure_mode: FailureMode,
+  pub extension: Option<ExtensionPolicy>,
+  pub configurations: Vec<ImageConfiguration>,
+}
+
+/// A source image of a batch, either uploaded or referenced
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct BatchSource {
+  pub id: String,
+  /// Prefix of the paths of every configuration, e.g. `products/1234`
+  pub path: String,
+  /// Name of the multipart field holding the uploaded image
+  pub image: Option<String>,
+  /// Storage key or allowed URL of the image, instead of an upload
+  pub source: Option<String>,
+  /// User metadata merged into the metadata of the batch
+  pub metadata: Option<HashMap<String, String>>,
+}
+
+impl BatchRequest {
+  /// The request processing a single source with the shared configurations.
+  /// Configuration paths are prefixed with the path of the source.
+  pub fn request_for(&self, source: &BatchSource) -> ImageProcessingRequest {
+    let mut metadata = self.metadata.clone();
+    if let Some(source_metadata) = &source.metadata {
+      metadata
+        .get_or_insert_with(HashMap::new)
+        .extend(source_metadata.clone());
+    }
+
+    let configurations = self
+      .configurations
+      .iter()
+      .map(|config| ImageConfiguration {
+        path: format!("{}/{}", source.path, config.path),
+        ..config.clone()
+      })
+      .collect();
+
+    ImageProcessingRequest {
+      id: source.id.clone(),
+      path: source.path.clone(),
+      source: source.source.clone(),
+      source_name: None,
+      min_size: self.min_size,
+      save_original: self.save_original,
+      portrait_environment_image: self.portrait_environment_image.clone(),
+      landscape_environment_image: self.landscape_environment_image.clone(),
+      environment_images: self.environment_images.clone(),
+      generate_alternative: self.generate_alternative,
+      max_age: self.max_age,
+      cache_control: self.cache_control.clone(),
+      content_disposition: self.content_disposition.clone(),
+      storage_class: self.storage_class.clone(),
+      metadata,
+      failure_mode: self.failure_mode,
+      dry_run: false,
+      extension: self.extension,
+      callback_url: None,
+      callback_secret: None,
+      configurations,
+    }
+  }
+
+  /// Validate the batch and the request of every source, reporting errors of
+  /// the shared fields once
+  pub fn validate(&self, max_sources: usize) -> Result<(), Vec<FieldError>> {
+    let mut errors = Vec::new();
+
+    if self.sources.is_empty() {
+      errors.push(FieldError::new("sources", "must not be empty"));
+    } else if self.sources.len() > max_sources {
+      errors.push(FieldError::new(
+        "sources",
+        format!("must not have more than {} entries", max_sources),
+      ));
+    }
+
+    let mut ids = HashSet::new();
+    for (i, source) in self.sources.iter().enumerate() {
+      let field = format!("sources[{}]", i);
+
+      if source.image.is_some() == source.source.is_some() {
+        errors.push(FieldError::new(
+          field.clone(),
+          "must have either image or source",
+        ));
+      }
+
+      if !source.id.is_empty() && !ids.insert(source.id.as_str()) {
+        errors.push(FieldError::new(format!("{}.id", field), "must be unique"));
+      }
+
+      if let Err(request_errors) = self.request_for(source).validate() {
+        for error in request_errors {
+          let error = match error.field.as_str() {
+            "id" | "path" | "source" => {
+              FieldError::new(format!("{}.{}", field, error.field), error.message)
+            }
+            _ => error,
+          };
+          if !errors.contains(&error) {
+            errors.push(error);
+          }
+        }
+      }
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BATCH: &str = r#"{
+    "max_age": 3600,
+    "metadata": { "origin": "import" },
+    "sources": [
+      { "id": "first", "path": "products/1", "image": "image-1" },
+      { "id": "second", "path": "products/2", "source": "originals/2.png", "metadata": { "origin": "feed" } }
+    ],
+    "configurations": [
+      {
+        "id": "config",
+        "path": "large",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 1024,
+        "quality": 80,
+        "conditions": {
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false,
+          "allow_vector": false
+        }
+      }
+    ]
+  }"#;
+
+  #[test]
+  fn request_for_source() {
+    let batch: BatchRequest = serde_json::from_str(BATCH).unwrap();
+    let request = batch.request_for(&batch.sources[1]);
+
+    assert_eq!(request.id, "second");
+    assert_eq!(request.source.as_deref(), Some("originals/2.png"));
+    assert_eq!(request.max_age, Some(3600));
+    assert_eq!(request.configurations[0].path, "products/2/large");
+    assert_eq!(request.metadata.unwrap()["origin"], "feed");
+  }
+
+  #[test]
+  fn validate_batch() {
+    let batch: BatchRequest = serde_json::from_str(BATCH).unwrap();
+    assert!(batch.validate(10).is_ok());
+
+    let errors = batch.validate(1).expect_err("too many sources");
+    assert_eq!(errors[0].field, "sources");
+  }
+
+  #[test]
+  fn validate_reports_shared_errors_once() {
+    let json = BATCH
+      .replace(r#""quality": 80"#, r#""quality": 0"#)
+      .replace(
+        r#""image": "image-1""#,
+        r#""image": "image-1", "source": "a.png""#,
+      )
+      .replace(r#""id": "second""#, r#""id": "first""#)
+      .replace(r#""originals/2.png""#, r#""../originals/2.png""#);
+    let batch: BatchRequest = serde_json::from_str(&json).unwrap();
+
+    let errors = batch.validate(10).expect_err("invalid batch");
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+      fields,
+      vec![
+        "sources[0]",
+        "configurations[0].quality",
+        "sources[1].id",
+        "sources[1].source"
+      ]
+    );
+  }
+}
@@ -0,0 +1,60 @@
+use sha2::{Digest, Sha256};
+
+use super::UploadImage;
+
+/// Hex encoded SHA-256 of the content
+pub fn sha256(data: &[u8]) -> String {
+  hex::encode(Sha256::digest(data))
+}
+
+impl UploadImage {
+  /// Hash the encoded image, filling `{hash}` and `{hash8}` in its path and
+  /// storing the hash with the object
+  pub fn addressed(mut self) -> Self {
+    let hash = sha256(&self.data);
+
+    self.content_addressed = self.path.contains("{hash}") || self.path.contains("{hash8}");
+    self.path = self
+      .path
+      .replace("{hash}", &hash)
+      .replace("{hash8}", &hash[..8]);
+    self.metadata.set_checksum(&hash);
+    self.hash = hash;
+
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::image_processing::metadata::ObjectMetadata;
+
+  #[test]
+  fn address_image() {
+    let image = UploadImage {
+      id: "config".to_owned(),
+      alternative_to: None,
+      mime: "image/jpeg".to_owned(),
+      path: "products/large-{hash8}.jpg".to_owned(),
+      data: Arc::new(b"abc".to_vec()),
+      hash: String::new(),
+      content_addressed: false,
+      descriptor: None,
+      width: 1,
+      height: 1,
+      metadata: ObjectMetadata::default(),
+    }
+    .addressed();
+
+    assert_eq!(
+      image.hash,
+      "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(image.path, "products/large-ba7816bf.jpg");
+    assert!(image.content_addressed);
+    assert_eq!(image.metadata.checksum(), Some(image.hash.as_str()));
+  }
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{EnvironmentImage, ImageProcessingRequest};
+use crate::image_modifier::environment::EnvironmentOptions;
+
+/// Ids of `portrait_environment_image` and `landscape_environment_image`
+pub const PORTRAIT_ID: &str = "portrait";
+pub const LANDSCAPE_ID: &str = "landscape";
+
+/// Orientation of the source image, by its aspect ratio
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+  /// Taller than wide
+  Portrait,
+  /// Wider than tall
+  Landscape,
+  /// As wide as tall
+  Square,
+}
+
+impl Orientation {
+  fn of(aspect: f64) -> Self {
+    if aspect < 1.0 {
+      Orientation::Portrait
+    } else if aspect > 1.0 {
+      Orientation::Landscape
+    } else {
+      Orientation::Square
+    }
+  }
+}
+
+impl EnvironmentImage {
+  fn has_rules(&self) -> bool {
+    self.min_aspect.is_some() || self.max_aspect.is_some() || self.orientation.is_some()
+  }
+
+  /// Placement of the product within the image
+  pub fn options(&self) -> EnvironmentOptions {
+    EnvironmentOptions {
+      width: self.width,
+      height: self.height,
+      x: self.x,
+      y: self.y,
+      margin_percent: self.margin_percent,
+      fit: self.fit,
+      blend: self.blend,
+      shadow: self.shadow.clone(),
+      corners: self.corners,
+    }
+  }
+
+  /// Whether the rules of the image match a source with the aspect ratio
+  fn matches(&self, aspect: f64) -> bool {
+    self.min_aspect.is_none_or(|min| aspect >= min)
+      && self.max_aspect.is_none_or(|max| aspect <= max)
+      && self
+        .orientation
+        .is_none_or(|orientation| orientation == Orientation::of(aspect))
+  }
+}
+
+impl ImageProcessingRequest {
+  /// Every environment image with an id, `environment_images` first, then
+  /// the portrait and landscape images with rules matching their orientation
+  pub fn environment_scenes(&self) -> Vec<EnvironmentImage> {
+    let mut scenes = self.environment_images.clone().unwrap_or_default();
+
+    if let Some(portrait) = &self.portrait_environment_image {
+      let has_rules = portrait.has_rules();
+      scenes.push(EnvironmentImage {
+        id: Some(portrait.id.clone().unwrap_or(PORTRAIT_ID.to_owned())),
+        orientation: portrait
+          .orientation
+          .or((!has_rules).then_some(Orientation::Portrait)),
+        ..portrait.clone()
+      });
+    }
+
+    // Square sources have always been placed into the landscape image
+    if let Some(landscape) = &self.landscape_environment_image {
+      let has_rules = landscape.has_rules();
+      scenes.push(EnvironmentImage {
+        id: Some(landscape.id.clone().unwrap_or(LANDSCAPE_ID.to_owned())),
+        min_aspect: landscape.min_aspect.or((!has_rules).then_some(1.0)),
+        ..landscape.clone()
+      });
+    }
+
+    scenes
+  }
+}
+
+/// The matching scene with the highest priority for a source of the given
+/// size, the first one listed on a tie
+pub fn select(scenes: &[EnvironmentImage], width: i32, height: i32) -> Option<&EnvironmentImage> {
+  let aspect = width as f64 / height as f64;
+
+  scenes.iter().filter(|scene| scene.matches(aspect)).fold(
+    None,
+    |best: Option<&EnvironmentImage>, scene| match best {
+      Some(best) if best.priority >= scene.priority => Some(best),
+      _ => Some(scene),
+    },
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REQUEST: &str = r#"{
+    "id": "source",
+    "path": "output",
+    "save_original": false,
+    "portrait_environment_image": { "path": "portrait.png", "width": 100, "height": 200, "x": 0, "y": 0, "margin_percent": 0 },
+    "landscape_environment_image": { "path": "landscape.png", "width": 200, "height": 100, "x": 0, "y": 0, "margin_percent": 0 },
+    "environment_images": [
+      { "id": "square", "path": "square.png", "width": 100, "height": 100, "x": 0, "y": 0, "margin_percent": 0, "min_aspect": 0.9, "max_aspect": 1.1, "priority": 1 },
+      { "id": "panorama", "path": "panorama.png", "width": 300, "height": 100, "x": 0, "y": 0, "margin_percent": 0, "min_aspect": 2.5 }
+    ],
+    "configurations": []
+  }"#;
+
+  fn selected(width: i32, height: i32) -> Option<String> {
+    let request: ImageProcessingRequest = serde_json::from_str(REQUEST).unwrap();
+    let scenes = request.environment_scenes();
+
+    select(&scenes, width, height).and_then(|scene| scene.id.clone())
+  }
+
+  #[test]
+  fn select_by_orientation() {
+    assert_eq!(selected(300, 600).as_deref(), Some(PORTRAIT_ID));
+    assert_eq!(selected(600, 400).as_deref(), Some(LANDSCAPE_ID));
+  }
+
+  #[test]
+  fn select_by_priority() {
+    // Matches square and landscape, square has the higher priority
+    assert_eq!(selected(500, 500).as_deref(), Some("square"));
+  }
+
+  #[test]
+  fn select_first_on_tie() {
+    // Matches panorama and landscape with the same priority
+    assert_eq!(selected(1200, 400).as_deref(), Some("panorama"));
+  }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{ImageConfiguration, ImageProcessingRequest};
+
+pub const DEFAULT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// User metadata key the SHA-256 checksum of an uploaded object is stored under
+pub const CHECKSUM_KEY: &str = "content-sha256";
+
+/// Metadata stored with an uploaded object
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ObjectMetadata {
+  pub cache_control: Option<String>,
+  pub content_disposition: Option<String>,
+  pub storage_class: Option<String>,
+  pub user_metadata: HashMap<String, String>,
+}
+
+impl ObjectMetadata {
+  pub fn cache_control(&self) -> &str {
+    self
+      .cache_control
+      .as_deref()
+      .unwrap_or(DEFAULT_CACHE_CONTROL)
+  }
+
+  /// Store the hex encoded SHA-256 checksum of the content with the object
+  pub fn set_checksum(&mut self, checksum: &str) {
+    self
+      .user_metadata
+      .insert(CHECKSUM_KEY.to_owned(), checksum.to_owned());
+  }
+
+  pub fn checksum(&self) -> Option<&str> {
+    self.user_metadata.get(CHECKSUM_KEY).map(String::as_str)
+  }
+
+  /// Whether an object stored with `stored` has the metadata it would get
+  /// with these, including the checksum of its content
+  pub fn matches(&self, stored: &ObjectMetadata) -> bool {
+    self.cache_control() == stored.cache_control()
+      && self.content_disposition == stored.content_disposition
+      && self.storage_class == stored.storage_class
+      && self.user_metadata == stored.user_metadata
+  }
+}
+
+fn cache_control(cache_control: &Option<String>, max_age: Option<u32>) -> Option<String> {
+  cache_control
+    .clone()
+    .or_else(|| max_age.map(|max_age| format!("public, max-age={}", max_age)))
+}
+
+impl ImageProcessingRequest {
+  /// Metadata for an object uploaded for the given configuration, or for the
+  /// original image when there is none. Configuration values take precedence.
+  pub fn object_metadata(&self, config: Option<&ImageConfiguration>) -> ObjectMetadata {
+    let mut user_metadata = self.metadata.clone().unwrap_or_default();
+    user_metadata.insert("source-id".to_owned(), self.id.clone());
+
+    let mut metadata = ObjectMetadata {
+      cache_control: cache_control(&self.cache_control, self.max_age),
+      content_disposition: self.content_disposition.clone(),
+      storage_class: self.storage_class.clone(),
+      user_metadata,
+    };
+
+    if let Some(config) = config {
+      if let Some(cache_control) = cache_control(&config.cache_control, config.max_age) {
+        metadata.cache_control = Some(cache_control);
+      }
+      if config.content_disposition.is_some() {
+        metadata.content_disposition = config.content_disposition.clone();
+      }
+      if config.storage_class.is_some() {
+        metadata.storage_class = config.storage_class.clone();
+      }
+      if let Some(user_metadata) = &config.metadata {
+        metadata.user_metadata.extend(user_metadata.clone());
+      }
+      metadata
+        .user_metadata
+        .insert("config-id".to_owned(), config.id.clone());
+    }
+
+    metadata
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REQUEST: &str = r#"{
+    "id": "source",
+    "path": "output",
+    "save_original": true,
+    "max_age": 3600,
+    "storage_class": "STANDARD_IA",
+    "metadata": { "origin": "import" },
+    "configurations": [
+      {
+        "id": "config",
+        "path": "output_config",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 1024,
+        "quality": 80,
+        "cache_control": "no-cache",
+        "content_disposition": "inline",
+        "metadata": { "origin": "config" },
+        "conditions": {
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false,
+          "allow_vector": false
+        }
+      }
+    ]
+  }"#;
+
+  #[test]
+  fn object_metadata_for_original() {
+    let request: ImageProcessingRequest = serde_json::from_str(REQUEST).unwrap();
+    let metadata = request.object_metadata(None);
+
+    assert_eq!(metadata.cache_control(), "public, max-age=3600");
+    assert_eq!(metadata.storage_class.as_deref(), Some("STANDARD_IA"));
+    assert_eq!(metadata.content_disposition, None);
+    assert_eq!(metadata.user_metadata["origin"], "import");
+    assert_eq!(metadata.user_metadata["source-id"], "source");
+    assert!(!metadata.user_metadata.contains_key("config-id"));
+  }
+
+  #[test]
+  fn object_metadata_configuration_overrides() {
+    let request: ImageProcessingRequest = serde_json::from_str(REQUEST).unwrap();
+    let metadata = request.object_metadata(request.configurations.first());
+
+    assert_eq!(metadata.cache_control(), "no-cache");
+    assert_eq!(metadata.storage_class.as_deref(), Some("STANDARD_IA"));
+    assert_eq!(metadata.content_disposition.as_deref(), Some("inline"));
+    assert_eq!(metadata.user_metadata["origin"], "config");
+    assert_eq!(metadata.user_metadata["config-id"], "config");
+  }
+
+  #[test]
+  fn object_metadata_checksum() {
+    let mut metadata = ObjectMetadata::default();
+    assert_eq!(metadata.checksum(), None);
+
+    metadata.set_checksum("ba7816bf");
+    assert_eq!(metadata.checksum(), Some("ba7816bf"));
+    assert_eq!(metadata.user_metadata[CHECKSUM_KEY], "ba7816bf");
+  }
+
+  #[test]
+  fn object_metadata_matches_stored() {
+    let request: ImageProcessingRequest = serde_json::from_str(REQUEST).unwrap();
+    let mut metadata = request.object_metadata(request.configurations.first());
+    metadata.set_checksum("ba7816bf");
+
+    // The default cache control is stored explicitly
+    let mut stored = metadata.clone();
+    assert!(metadata.matches(&stored));
+
+    stored.cache_control = None;
+    assert!(!metadata.matches(&stored));
+    assert!(ObjectMetadata::default().matches(&ObjectMetadata {
+      cache_control: Some(DEFAULT_CACHE_CONTROL.to_owned()),
+      ..ObjectMetadata::default()
+    }));
+
+    let mut stored = metadata.clone();
+    stored.set_checksum("00000000");
+    assert!(!metadata.matches(&stored));
+
+    let mut stored = metadata.clone();
+    stored.content_disposition = Some("attachment".to_owned());
+    assert!(!metadata.matches(&stored));
+  }
+
+  #[test]
+  fn object_metadata_default_cache_control() {
+    let metadata = ObjectMetadata::default();
+    assert_eq!(metadata.cache_control(), DEFAULT_CACHE_CONTROL);
+  }
+}
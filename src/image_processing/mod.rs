@@ -1,8 +1,34 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
+pub mod batch;
+pub mod content_hash;
+pub mod environment;
+pub mod metadata;
+pub mod output;
+pub mod path_template;
+pub mod render;
+pub mod report;
+pub mod srcset;
+pub mod validation;
+
+use crate::image_modifier::adjust::ColourAdjustments;
+use crate::image_modifier::duotone::Duotone;
+use crate::image_modifier::environment::{
+  EnvironmentBlend, EnvironmentCorners, EnvironmentFit, EnvironmentShadow,
+};
+use crate::image_modifier::sharpen::Sharpen;
+use crate::image_modifier::text::TextOverlay;
+use crate::image_modifier::watermark::Watermark;
+use environment::Orientation;
+use metadata::ObjectMetadata;
+use output::OutputFormat;
+use path_template::ExtensionPolicy;
+use report::FailureMode;
+
 #[derive(Deserialize, ToSchema)]
 #[allow(unused)]
 pub struct ProcessImageForm {
@@ -15,14 +41,46 @@ pub struct ProcessImageForm {
 pub struct ImageProcessingRequest {
   pub id: String,
   pub path: String,
+  /// Storage key or allowed URL of the source image, instead of an upload
+  pub source: Option<String>,
+  /// File name of the uploaded source image, set from the multipart field
+  #[serde(skip)]
+  pub source_name: Option<String>,
   pub min_size: Option<i32>,
   pub save_original: bool,
   pub portrait_environment_image: Option<EnvironmentImage>,
   pub landscape_environment_image: Option<EnvironmentImage>,
+  /// Environment images chosen by the aspect ratio of the source, in
+  /// addition to the portrait and landscape images
+  pub environment_images: Option<Vec<EnvironmentImage>>,
+  /// Default for `generate_alternative` in the conditions of each configuration
+  pub generate_alternative: Option<bool>,
+  /// `max-age` in seconds for the `Cache-Control` of uploaded objects
+  pub max_age: Option<u32>,
+  /// `Cache-Control` of uploaded objects, takes precedence over `max_age`
+  pub cache_control: Option<String>,
+  pub content_disposition: Option<String>,
+  /// Storage class of uploaded objects, e.g. `STANDARD_IA`
+  pub storage_class: Option<String>,
+  /// User metadata stored with every uploaded object
+  pub metadata: Option<HashMap<String, String>>,
+  /// Whether a failing configuration fails the whole request
+  #[serde(default)]
+  pub failure_mode: FailureMode,
+  /// Render without uploading, returning the images inline
+  #[serde(default)]
+  pub dry_run: bool,
+  /// Default for `extension` of each configuration and of the original
+  pub extension: Option<ExtensionPolicy>,
+  /// URL to POST the outcome to when processing as a job
+  pub callback_url: Option<String>,
+  /// Secret to sign callbacks with, see `X-Rusty-Pixel-Signature`
+  #[serde(skip_serializing)]
+  pub callback_secret: Option<String>,
   pub configurations: Vec<ImageConfiguration>,
 }
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct ImageConfiguration {
   pub id: String,
   pub path: String,
@@ -30,26 +88,100 @@ pub struct ImageConfiguration {
   pub margin_percent: i32,
   pub size: i32,
   pub quality: i32,
+  /// Format of the primary image, takes precedence over the `mime` condition
+  pub format: Option<OutputFormat>,
+  /// Formats to generate alternatives in, defaults to WebP
+  pub alternatives: Option<Vec<OutputFormat>>,
+  /// Overrides `extension` of the request for this configuration
+  pub extension: Option<ExtensionPolicy>,
+  /// Smaller widths to scale the image rendered at `size` down to for a srcset
+  pub widths: Option<Vec<i32>>,
+  /// Pixel densities relative to `size` for a srcset, e.g. `[1, 2, 3]`
+  pub densities: Option<Vec<f64>>,
+  /// Overrides `max_age` of the request for this configuration
+  pub max_age: Option<u32>,
+  /// Overrides `cache_control` of the request for this configuration
+  pub cache_control: Option<String>,
+  pub content_disposition: Option<String>,
+  pub storage_class: Option<String>,
+  /// User metadata merged into the metadata of the request
+  pub metadata: Option<HashMap<String, String>>,
+  /// Id of the environment image to use instead of the one chosen for the
+  /// source
+  pub environment: Option<String>,
+  /// Brightness, contrast, saturation, gamma and normalization of the source
+  pub adjustments: Option<ColourAdjustments>,
+  /// Sharpening of sources scaled down to `size` by at least half
+  pub sharpen: Option<Sharpen>,
+  /// Sigma of a gaussian blur in pixels, e.g. for placeholders
+  pub blur: Option<f64>,
+  /// Size of the blocks to pixelate the image with in pixels
+  pub pixelate: Option<i32>,
+  /// Overlay stamped onto the rendered images
+  pub watermark: Option<Watermark>,
+  /// Text rendered onto the rendered images, above the watermark
+  pub text: Option<TextOverlay>,
   pub conditions: ImageConditions,
 }
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct ImageConditions {
   pub transparent: bool,
   pub trim: bool,
   pub black_and_white: bool,
+  #[serde(default)]
+  pub sepia: bool,
+  /// Hex colour to tint the image with, keeping its lightness
+  pub tint: Option<String>,
+  /// Colours to map the lightness of the image between
+  pub duotone: Option<Duotone>,
   pub use_environment_image: bool,
   pub allow_vector: bool,
+  /// Mime type of the primary image, e.g. `image/webp`
+  pub mime: Option<String>,
+  /// Use the format of the uploaded image for the primary image
+  #[serde(default)]
+  pub use_original_mime: bool,
+  pub generate_alternative: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct EnvironmentImage {
+  /// Id configurations refer to in `environment`, required in
+  /// `environment_images`. Defaults to `portrait` and `landscape` for those.
+  pub id: Option<String>,
   pub path: String,
+  /// Placement box of the product, not needed with `corners`
+  #[serde(default)]
   pub width: i32,
+  #[serde(default)]
   pub height: i32,
+  #[serde(default)]
   pub x: i32,
+  #[serde(default)]
   pub y: i32,
+  /// Corners the product is mapped onto with perspective, instead of the
+  /// placement box
+  pub corners: Option<EnvironmentCorners>,
+  /// Storage key of a greyscale image the size of the environment image,
+  /// the product shows where it's white
+  pub mask_path: Option<String>,
+  /// Margin inside the placement box, percentage of its shortest side
   pub margin_percent: i32,
+  #[serde(default)]
+  pub fit: EnvironmentFit,
+  #[serde(default)]
+  pub blend: EnvironmentBlend,
+  /// Drop shadow beneath the product, none by default
+  pub shadow: Option<EnvironmentShadow>,
+  /// Smallest aspect ratio (width / height) of sources to use the image for
+  pub min_aspect: Option<f64>,
+  /// Largest aspect ratio (width / height) of sources to use the image for
+  pub max_aspect: Option<f64>,
+  pub orientation: Option<Orientation>,
+  /// The image with the highest priority is used when several match
+  #[serde(default)]
+  pub priority: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
@@ -59,10 +191,23 @@ pub struct ProcessedImage {
   pub path: String,
   pub url: String,
   pub mime: String,
+  /// Hex encoded SHA-256 of the content
   pub hash: String,
   pub size: u64,
   pub width: i32,
   pub height: i32,
+  /// Width or density descriptor of a srcset entry, e.g. `640w` or `2x`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub descriptor: Option<String>,
+  /// Every entry of the configuration in this format, set on the primary image
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub srcset: Option<String>,
+  /// An identical object already existed at the path and wasn't uploaded again
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub deduplicated: bool,
+  /// Base64 encoded image, only set for dry runs
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub data: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -72,8 +217,15 @@ pub struct UploadImage {
   pub mime: String,
   pub path: String,
   pub data: Arc<Vec<u8>>,
+  /// SHA-256 of `data`, set by `addressed`
+  pub hash: String,
+  /// Whether the path includes the hash, set by `addressed`
+  pub content_addressed: bool,
+  /// Width or density descriptor when part of a srcset
+  pub descriptor: Option<String>,
   pub width: i32,
   pub height: i32,
+  pub metadata: ObjectMetadata,
 }
 
 pub fn loader_to_mime_ext(loader: &str) -> (&'static str, &'static str) {
@@ -0,0 +1,266 @@
+use anyhow::{Result, anyhow};
+use libvips::{VipsImage, ops};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ImageConfiguration, alternative_possible};
+
+/// Output format of a rendered image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+  Jpeg,
+  Png,
+  Webp,
+  Avif,
+  Jxl,
+  /// Same format as the uploaded image, if it can be encoded
+  Original,
+}
+
+impl OutputFormat {
+  pub fn from_mime(mime: &str) -> Option<OutputFormat> {
+    match mime {
+      "image/jpeg" | "image/jpg" => Some(OutputFormat::Jpeg),
+      "image/png" => Some(OutputFormat::Png),
+      "image/webp" => Some(OutputFormat::Webp),
+      "image/avif" => Some(OutputFormat::Avif),
+      "image/jxl" => Some(OutputFormat::Jxl),
+      _ => None,
+    }
+  }
+
+  /// The encodable format matching the loader used for the source image
+  pub fn from_loader(loader: &str) -> Option<OutputFormat> {
+    match loader {
+      "jpegload_buffer" => Some(OutputFormat::Jpeg),
+      "pngload_buffer" => Some(OutputFormat::Png),
+      "webpload_buffer" => Some(OutputFormat::Webp),
+      "jxlload_buffer" => Some(OutputFormat::Jxl),
+      _ => None,
+    }
+  }
+
+  pub fn mime(&self) -> &'static str {
+    match self {
+      OutputFormat::Jpeg => "image/jpeg",
+      OutputFormat::Png => "image/png",
+      OutputFormat::Webp => "image/webp",
+      OutputFormat::Avif => "image/avif",
+      OutputFormat::Jxl => "image/jxl",
+      OutputFormat::Original => "application/octet-stream",
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      OutputFormat::Jpeg => "jpg",
+      OutputFormat::Png => "png",
+      OutputFormat::Webp => "webp",
+      OutputFormat::Avif => "avif",
+      OutputFormat::Jxl => "jxl",
+      OutputFormat::Original => "bin",
+    }
+  }
+
+  /// Replace `Original` with the format of the source image, or `fallback`
+  /// when the source format can't be encoded
+  fn resolve(self, loader: &str, fallback: OutputFormat) -> OutputFormat {
+    match self {
+      OutputFormat::Original => OutputFormat::from_loader(loader).unwrap_or(fallback),
+      format => format,
+    }
+  }
+}
+
+impl ImageConfiguration {
+  /// The format of the primary image, in order of precedence: `format`, the
+  /// `mime` and `use_original_mime` conditions, PNG if `transparent`, JPEG
+  pub fn output_format(&self, loader: &str) -> OutputFormat {
+    let fallback = if self.conditions.transparent {
+      OutputFormat::Png
+    } else {
+      OutputFormat::Jpeg
+    };
+
+    let requested = self
+      .format
+      .or_else(|| {
+        self
+          .conditions
+          .mime
+          .as_deref()
+          .and_then(OutputFormat::from_mime)
+      })
+      .or(
+        self
+          .conditions
+          .use_original_mime
+          .then_some(OutputFormat::Original),
+      );
+
+    requested.map_or(fallback, |format| format.resolve(loader, fallback))
+  }
+
+  /// Formats to generate as alternatives to the primary image, WebP unless
+  /// `alternatives` says otherwise
+  pub fn alternative_formats(
+    &self,
+    loader: &str,
+    generate_alternative: Option<bool>,
+  ) -> Vec<OutputFormat> {
+    let generate = self
+      .conditions
+      .generate_alternative
+      .or(generate_alternative)
+      .unwrap_or(true);
+
+    if !generate || !alternative_possible(loader, self.conditions.allow_vector) {
+      return Vec::new();
+    }
+
+    let primary = self.output_format(loader);
+    let mut formats = Vec::new();
+    for format in self
+      .alternatives
+      .as_deref()
+      .unwrap_or(&[OutputFormat::Webp])
+    {
+      let format = format.resolve(loader, primary);
+      if format != primary && !formats.contains(&format) {
+        formats.push(format);
+      }
+    }
+
+    formats
+  }
+}
+
+/// Encode the image in the given format
+pub fn encode(image: &VipsImage, format: OutputFormat, quality: i32) -> Result<Vec<u8>> {
+  let data = match format {
+    OutputFormat::Jpeg => ops::jpegsave_buffer_with_opts(
+      image,
+      &ops::JpegsaveBufferOptions {
+        q: quality,
+        background: vec![255.0, 255.0, 255.0],
+        profile: Some("sRGB".to_owned()),
+        ..ops::JpegsaveBufferOptions::default()
+      },
+    )?,
+    OutputFormat::Png => ops::pngsave_buffer_with_opts(
+      image,
+      &ops::PngsaveBufferOptions {
+        profile: Some("sRGB".to_owned()),
+        ..ops::PngsaveBufferOptions::default()
+      },
+    )?,
+    OutputFormat::Webp => ops::webpsave_buffer_with_opts(
+      image,
+      &ops::WebpsaveBufferOptions {
+        q: quality,
+        background: vec![255.0, 255.0, 255.0],
+        profile: Some("sRGB".to_owned()),
+        ..ops::WebpsaveBufferOptions::default()
+      },
+    )?,
+    OutputFormat::Avif => ops::heifsave_buffer_with_opts(
+      image,
+      &ops::HeifsaveBufferOptions {
+        q: quality,
+        bitdepth: 8,
+        compression: ops::ForeignHeifCompression::Av1,
+        profile: Some("sRGB".to_owned()),
+        ..ops::HeifsaveBufferOptions::default()
+      },
+    )?,
+    // The bindings don't include jxlsave, go through the generic saver instead
+    OutputFormat::Jxl => image.image_write_to_buffer(&format!(".jxl[Q={}]", quality))?,
+    OutputFormat::Original => return Err(anyhow!("original format must be resolved")),
+  };
+
+  Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn configuration(transparent: bool) -> ImageConfiguration {
+    serde_json::from_value(serde_json::json!({
+      "id": "config",
+      "path": "config",
+      "aspect": 1.0,
+      "margin_percent": 0,
+      "size": 100,
+      "quality": 80,
+      "conditions": {
+        "transparent": transparent,
+        "trim": false,
+        "black_and_white": false,
+        "use_environment_image": false,
+        "allow_vector": false
+      }
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn output_format_legacy() {
+    assert_eq!(
+      configuration(true).output_format("jpegload_buffer"),
+      OutputFormat::Png
+    );
+    assert_eq!(
+      configuration(false).output_format("pngload_buffer"),
+      OutputFormat::Jpeg
+    );
+  }
+
+  #[test]
+  fn output_format_precedence() {
+    let mut config = configuration(false);
+    config.conditions.use_original_mime = true;
+    assert_eq!(config.output_format("webpload_buffer"), OutputFormat::Webp);
+    // Falls back when the source format can't be encoded
+    assert_eq!(config.output_format("svgload_buffer"), OutputFormat::Jpeg);
+
+    config.conditions.mime = Some("image/png".to_owned());
+    assert_eq!(config.output_format("webpload_buffer"), OutputFormat::Png);
+
+    config.format = Some(OutputFormat::Avif);
+    assert_eq!(config.output_format("webpload_buffer"), OutputFormat::Avif);
+  }
+
+  #[test]
+  fn alternative_formats() {
+    let mut config = configuration(false);
+    assert_eq!(
+      config.alternative_formats("pngload_buffer", None),
+      vec![OutputFormat::Webp]
+    );
+    assert!(
+      config
+        .alternative_formats("pngload_buffer", Some(false))
+        .is_empty()
+    );
+
+    config.format = Some(OutputFormat::Webp);
+    config.alternatives = Some(vec![
+      OutputFormat::Avif,
+      OutputFormat::Webp,
+      OutputFormat::Avif,
+    ]);
+    assert_eq!(
+      config.alternative_formats("pngload_buffer", None),
+      vec![OutputFormat::Avif]
+    );
+
+    config.conditions.generate_alternative = Some(false);
+    assert!(
+      config
+        .alternative_formats("pngload_buffer", Some(true))
+        .is_empty()
+    );
+  }
+}
@@ -0,0 +1,202 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::ImageProcessingRequest;
+
+/// Placeholders that may be used in paths. `{hash}` and `{hash8}` are filled
+/// once the image is encoded.
+const PLACEHOLDERS: &[&str] = &[
+  "id",
+  "config_id",
+  "width",
+  "height",
+  "format",
+  "hash",
+  "hash8",
+  "descriptor",
+  "date",
+  "source_basename",
+];
+
+/// Whether the extension of the format is appended to a path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtensionPolicy {
+  /// Append `.<extension>`, e.g. `.jpg`
+  #[default]
+  Append,
+  /// Use the path as is, it must contain `{format}`
+  None,
+}
+
+/// Values shared by every path of a request
+pub struct PathContext {
+  id: String,
+  source_basename: String,
+  date: String,
+  extension: Option<ExtensionPolicy>,
+}
+
+/// Values of a single rendered image
+pub struct PathImage<'a> {
+  pub config_id: &'a str,
+  /// File extension of the format, e.g. `jpg`
+  pub format: &'a str,
+  pub width: i32,
+  pub height: i32,
+  /// Srcset descriptor, e.g. `640w` or `2x`
+  pub descriptor: Option<&'a str>,
+  pub extension: Option<ExtensionPolicy>,
+}
+
+impl PathContext {
+  pub fn new(request: &ImageProcessingRequest) -> Self {
+    let source_basename = request
+      .source
+      .as_deref()
+      .or(request.source_name.as_deref())
+      .map(basename)
+      .filter(|name| !name.is_empty())
+      .unwrap_or(&request.id)
+      .to_owned();
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs())
+      .unwrap_or_default();
+
+    Self {
+      id: request.id.clone(),
+      source_basename,
+      date: utc_date(now),
+      extension: request.extension,
+    }
+  }
+
+  /// Fill the placeholders of the template, appending the extension unless
+  /// the image or request says otherwise
+  pub fn render(&self, template: &str, image: &PathImage) -> String {
+    let mut path = template
+      .replace("{id}", &self.id)
+      .replace("{config_id}", image.config_id)
+      .replace("{width}", &image.width.to_string())
+      .replace("{height}", &image.height.to_string())
+      .replace("{format}", image.format)
+      .replace("{descriptor}", image.descriptor.unwrap_or_default())
+      .replace("{date}", &self.date)
+      .replace("{source_basename}", &self.source_basename);
+
+    if image.extension.or(self.extension).unwrap_or_default() == ExtensionPolicy::Append {
+      path.push('.');
+      path.push_str(image.format);
+    }
+
+    path
+  }
+}
+
+/// Check that every placeholder of the template is known
+pub fn validate(template: &str) -> Result<(), String> {
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    let Some(end) = rest[start..].find('}') else {
+      return Err("has an unclosed placeholder".to_owned());
+    };
+    let name = &rest[start + 1..start + end];
+    if !PLACEHOLDERS.contains(&name) {
+      return Err(format!("has an unknown placeholder {{{}}}", name));
+    }
+    rest = &rest[start + end + 1..];
+  }
+
+  Ok(())
+}
+
+/// File name of a storage key, URL or upload without its extension
+fn basename(name: &str) -> &str {
+  let name = name.split(['?', '#']).next().unwrap_or(name);
+  let file = name.rsplit(['/', '\\']).next().unwrap_or(name);
+
+  match file.rfind('.') {
+    Some(dot) if dot > 0 => &file[..dot],
+    _ => file,
+  }
+}
+
+/// `YYYY-MM-DD` of the unix timestamp in UTC
+fn utc_date(unix_secs: u64) -> String {
+  // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+  let z = (unix_secs / 86_400) as i64 + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn context() -> PathContext {
+    PathContext {
+      id: "source".to_owned(),
+      source_basename: "photo".to_owned(),
+      date: "2026-06-08".to_owned(),
+      extension: None,
+    }
+  }
+
+  fn image(extension: Option<ExtensionPolicy>) -> PathImage<'static> {
+    PathImage {
+      config_id: "large",
+      format: "webp",
+      width: 1024,
+      height: 768,
+      descriptor: None,
+      extension,
+    }
+  }
+
+  #[test]
+  fn render_template() {
+    let path = context().render(
+      "{date}/{id}/{source_basename}-{config_id}-{width}x{height}",
+      &image(None),
+    );
+    assert_eq!(path, "2026-06-08/source/photo-large-1024x768.webp");
+
+    let path = context().render(
+      "{config_id}/{hash8}.{format}",
+      &image(Some(ExtensionPolicy::None)),
+    );
+    assert_eq!(path, "large/{hash8}.webp");
+  }
+
+  #[test]
+  fn validate_template() {
+    assert!(validate("products/{id}-{hash8}").is_ok());
+    assert!(validate("products/{name}").is_err());
+    assert!(validate("products/{id").is_err());
+  }
+
+  #[test]
+  fn basename_of_source() {
+    assert_eq!(basename("originals/2024/photo.final.png"), "photo.final");
+    assert_eq!(basename("https://example.com/a/photo.jpg?v=2"), "photo");
+    assert_eq!(basename(".hidden"), ".hidden");
+  }
+
+  #[test]
+  fn date_of_timestamp() {
+    assert_eq!(utc_date(0), "1970-01-01");
+    assert_eq!(utc_date(951_782_400), "2000-02-29");
+    assert_eq!(utc_date(1_780_876_800), "2026-06-08");
+  }
+}
@@ -0,0 +1,383 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use libvips::{VipsImage, ops};
+use uuid::Uuid;
+
+use super::metadata::ObjectMetadata;
+use super::path_template::{PathContext, PathImage};
+use super::{ImageConfiguration, UploadImage, output, srcset};
+use crate::image_modifier::{self, environment::EnvironmentOptions};
+
+/// Image decoded into memory, shared by the configurations rendered in
+/// parallel and, for environment images, across requests
+pub struct SharedImage(VipsImage);
+
+impl SharedImage {
+  /// Decode the whole image into memory, so it no longer reads from its
+  /// source when shared
+  pub fn new(image: VipsImage) -> Result<Self, libvips::error::Error> {
+    Ok(Self(VipsImage::image_copy_memory(image)?))
+  }
+
+  /// Operations on the image create new images and never modify it, see
+  /// `copy` for a copy a thread can own
+  pub fn image(&self) -> &VipsImage {
+    &self.0
+  }
+}
+
+// SAFETY: libvips images are immutable once built and libvips operations
+// are thread-safe: its "How it works" documentation describes images being
+// read by many worker threads at once, each operation only taking references
+// on its inputs. `SharedImage` never hands out a mutable reference, and the
+// `VipsImage` wrapper's only other state is the GObject reference count,
+// which GObject updates atomically. The pixels live in a memory buffer owned
+// by the image since `image_copy_memory`, so no thread triggers lazy decoding
+// from a source buffer that another thread might be reading or dropping.
+unsafe impl Send for SharedImage {}
+unsafe impl Sync for SharedImage {}
+
+/// The decoded source image with its encoded data
+pub struct Source<'a> {
+  pub image: &'a VipsImage,
+  pub data: &'a Arc<Vec<u8>>,
+  /// libvips loader used to decode the image, e.g. `pngload_buffer`
+  pub loader: &'a str,
+}
+
+/// Environment image placed behind configurations using it
+pub struct Environment {
+  pub image: Arc<SharedImage>,
+  pub mask: Option<Arc<SharedImage>>,
+  pub opts: EnvironmentOptions,
+}
+
+/// Lightweight copy of a shared image for a modifier of this thread
+fn copy(image: &SharedImage) -> Result<VipsImage> {
+  ops::copy(image.image()).map_err(|e| anyhow!("failed to copy image: {}", e))
+}
+
+/// A size of the configuration to encode, with its srcset descriptor
+struct Rendition {
+  image: VipsImage,
+  descriptor: Option<String>,
+  /// Scaled down for a srcset rather than the primary image
+  variant: bool,
+}
+
+/// Render the primary image of the configuration and its alternatives, and
+/// the sizes of its srcset in each of their formats
+pub fn render_configuration(
+  source: &Source,
+  config: &ImageConfiguration,
+  metadata: ObjectMetadata,
+  environment: Option<&Environment>,
+  watermark: Option<&SharedImage>,
+  generate_alternative: Option<bool>,
+  paths: &PathContext,
+) -> Result<Vec<UploadImage>> {
+  let path = |format: &str, image: &VipsImage, descriptor: Option<&str>, variant: bool| {
+    // Variants need paths of their own when the template doesn't tell them apart
+    let template = match descriptor {
+      Some(descriptor)
+        if variant && !config.path.contains("{width}") && !config.path.contains("{descriptor}") =>
+      {
+        match descriptor.strip_suffix('x') {
+          Some(density) => format!("{}@{}x", config.path, density),
+          None => format!("{}-{}", config.path, descriptor),
+        }
+      }
+      _ => config.path.clone(),
+    };
+
+    paths.render(
+      &template,
+      &PathImage {
+        config_id: &config.id,
+        format,
+        width: image.get_width(),
+        height: image.get_height(),
+        descriptor,
+        extension: config.extension,
+      },
+    )
+  };
+
+  // Pass the image as is
+  if config.conditions.allow_vector && source.loader == "svgload_buffer" {
+    return Ok(vec![UploadImage {
+      path: path("svg", source.image, None, false),
+      mime: "image/svg+xml".to_string(),
+      id: config.id.clone(),
+      data: source.data.clone(),
+      hash: String::new(),
+      content_addressed: false,
+      descriptor: None,
+      alternative_to: None,
+      width: source.image.get_width(),
+      height: source.image.get_height(),
+      metadata,
+    }]);
+  }
+
+  // Create a lightweight copy of the decoded image for this configuration
+  let mut output_image =
+    ops::copy(source.image).map_err(|e| anyhow!("failed to copy source image: {}", e))?;
+
+  // Build a vector of modifiers to apply to the image
+  let mut modifiers: Vec<Box<dyn image_modifier::ImageModifier>> = Vec::new();
+
+  if config.conditions.black_and_white {
+    modifiers.push(Box::new(
+      image_modifier::blackandwhite::BlackAndWhiteModifier,
+    ));
+  }
+
+  if config.conditions.sepia {
+    modifiers.push(Box::new(image_modifier::sepia::SepiaModifier));
+  }
+
+  if let Some(tint) = &config.conditions.tint {
+    modifiers.push(Box::new(image_modifier::tint::TintModifier::new(
+      tint.clone(),
+    )));
+  }
+
+  if let Some(duotone) = &config.conditions.duotone {
+    modifiers.push(Box::new(image_modifier::duotone::DuotoneModifier::new(
+      duotone.clone(),
+    )));
+  }
+
+  if config.conditions.trim {
+    modifiers.push(Box::new(image_modifier::trim::TrimModifier::new(vec![
+      255.0, 255.0, 255.0,
+    ])));
+  }
+
+  if let Some(adjustments) = &config.adjustments {
+    modifiers.push(Box::new(image_modifier::adjust::AdjustModifier::new(
+      adjustments.clone(),
+    )));
+  }
+
+  // Modify the source first, the densities it can be rendered at depend on
+  // its size after trimming
+  output_image = apply(modifiers, output_image)?;
+  let mut modifiers: Vec<Box<dyn image_modifier::ImageModifier>> = Vec::new();
+
+  // If we are trimming, don't crop the resulting image
+  let scale = |density: f64| {
+    image_modifier::scale::ScaleModifier::new(
+      config.aspect,
+      config.margin_percent,
+      Some((config.size as f64 * density).round() as i32),
+      !config.conditions.trim,
+      config.sharpen.clone(),
+    )
+  };
+
+  // Render at the largest density the source allows, every other size is
+  // scaled down from it
+  let densities = config.densities_within(|density| scale(density).upscales(&output_image));
+  let max_density = srcset::max_density(densities.as_deref());
+  modifiers.push(Box::new(scale(max_density)));
+
+  // Blurs and blocks are in pixels of the image at `size`
+  if let Some(sigma) = config.blur {
+    modifiers.push(Box::new(image_modifier::blur::BlurModifier::new(
+      sigma * max_density,
+    )));
+  }
+
+  if let Some(size) = config.pixelate {
+    modifiers.push(Box::new(image_modifier::pixelate::PixelateModifier::new(
+      (size as f64 * max_density).round() as i32,
+    )));
+  }
+
+  if config.conditions.use_environment_image
+    && let Some(environment) = environment
+  {
+    modifiers.push(Box::new(
+      image_modifier::environment::EnvironmentModifier::new(
+        copy(&environment.image)?,
+        environment.mask.as_deref().map(copy).transpose()?,
+        environment.opts.clone(),
+      ),
+    ));
+  }
+
+  // Stamped last with the text so they are relative to the rendered size,
+  // and scaled with the srcset sizes
+  if let (Some(opts), Some(overlay)) = (&config.watermark, watermark) {
+    modifiers.push(Box::new(image_modifier::watermark::WatermarkModifier::new(
+      copy(overlay)?,
+      opts.scaled(max_density),
+    )));
+  }
+
+  if let Some(text) = &config.text {
+    modifiers.push(Box::new(image_modifier::text::TextModifier::new(
+      text.scaled(max_density),
+    )));
+  }
+
+  let output_image = apply(modifiers, output_image)?;
+  let renditions = renditions(config, densities.as_deref(), output_image, max_density)?;
+
+  let format = config.output_format(source.loader);
+  let alternatives = config.alternative_formats(source.loader, generate_alternative);
+
+  let mut images = Vec::new();
+  for rendition in &renditions {
+    let descriptor = rendition.descriptor.as_deref();
+
+    let image_data = output::encode(&rendition.image, format, config.quality)
+      .map_err(|e| anyhow!("failed to save image: {}", e))?;
+    images.push(UploadImage {
+      path: path(
+        format.extension(),
+        &rendition.image,
+        descriptor,
+        rendition.variant,
+      ),
+      mime: format.mime().to_owned(),
+      // The primary image keeps the id of the configuration
+      id: if rendition.variant {
+        Uuid::new_v4().into()
+      } else {
+        config.id.clone()
+      },
+      data: Arc::new(image_data),
+      hash: String::new(),
+      content_addressed: false,
+      descriptor: rendition.descriptor.clone(),
+      alternative_to: rendition.variant.then(|| config.id.clone()),
+      width: rendition.image.get_width(),
+      height: rendition.image.get_height(),
+      metadata: metadata.clone(),
+    });
+
+    // Generate alternative formats if possible
+    for alternative in &alternatives {
+      let alternative_data = output::encode(&rendition.image, *alternative, config.quality)
+        .map_err(|e| anyhow!("failed to save image: {}", e))?;
+
+      images.push(UploadImage {
+        path: path(
+          alternative.extension(),
+          &rendition.image,
+          descriptor,
+          rendition.variant,
+        ),
+        id: Uuid::new_v4().into(),
+        data: Arc::new(alternative_data),
+        hash: String::new(),
+        content_addressed: false,
+        descriptor: rendition.descriptor.clone(),
+        mime: alternative.mime().to_owned(),
+        alternative_to: Some(config.id.clone()),
+        width: rendition.image.get_width(),
+        height: rendition.image.get_height(),
+        metadata: metadata.clone(),
+      });
+    }
+  }
+
+  Ok(images)
+}
+
+/// Apply the modifiers to the image in the order of their stages
+fn apply(
+  mut modifiers: Vec<Box<dyn image_modifier::ImageModifier>>,
+  mut image: VipsImage,
+) -> Result<VipsImage> {
+  modifiers.sort_by_key(|modifier| modifier.stage());
+  for opt in modifiers {
+    match opt.apply(&image) {
+      Err(e) => return Err(anyhow!("failed to apply modifier: {}", e)),
+      Ok(Some(m)) => image = m,
+      Ok(None) => {}
+    }
+  }
+
+  Ok(image)
+}
+
+/// The primary image at `size` followed by the sizes of the srcset, largest
+/// first. Images are never scaled up.
+fn renditions(
+  config: &ImageConfiguration,
+  densities: Option<&[f64]>,
+  rendered: VipsImage,
+  max_density: f64,
+) -> Result<Vec<Rendition>> {
+  let scale = |image: &VipsImage, factor: f64| {
+    ops::resize(image, factor).map_err(|e| anyhow!("failed to scale srcset image: {}", e))
+  };
+
+  if let Some(densities) = densities {
+    let mut densities = densities.to_vec();
+    densities.sort_by(|a, b| b.total_cmp(a));
+    densities.dedup();
+
+    let primary = if max_density > 1.0 {
+      scale(&rendered, 1.0 / max_density)?
+    } else {
+      ops::copy(&rendered).map_err(|e| anyhow!("failed to copy image: {}", e))?
+    };
+    let mut renditions = vec![Rendition {
+      image: primary,
+      descriptor: Some(srcset::density_descriptor(1.0)),
+      variant: false,
+    }];
+    for density in densities.into_iter().filter(|density| *density != 1.0) {
+      let image = if density == max_density {
+        ops::copy(&rendered).map_err(|e| anyhow!("failed to copy image: {}", e))?
+      } else {
+        scale(&rendered, density / max_density)?
+      };
+      renditions.push(Rendition {
+        image,
+        descriptor: Some(srcset::density_descriptor(density)),
+        variant: true,
+      });
+    }
+
+    return Ok(renditions);
+  }
+
+  let Some(widths) = &config.widths else {
+    return Ok(vec![Rendition {
+      image: rendered,
+      descriptor: None,
+      variant: false,
+    }]);
+  };
+
+  let width = rendered.get_width();
+  let mut widths: Vec<i32> = widths.iter().copied().filter(|w| *w < width).collect();
+  widths.sort_by(|a, b| b.cmp(a));
+  widths.dedup();
+
+  let mut renditions = Vec::new();
+  for target in widths {
+    renditions.push(Rendition {
+      image: scale(&rendered, target as f64 / width as f64)?,
+      descriptor: Some(srcset::width_descriptor(target)),
+      variant: true,
+    });
+  }
+  renditions.insert(
+    0,
+    Rendition {
+      image: rendered,
+      descriptor: Some(srcset::width_descriptor(width)),
+      variant: false,
+    },
+  );
+
+  Ok(renditions)
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::ProcessedImage;
+
+/// What to do with the other images when a configuration fails
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureMode {
+  /// Remove every uploaded image and fail the request
+  #[default]
+  AllOrNothing,
+  /// Keep the images that could be uploaded and report the failures
+  BestEffort,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigurationOutcome {
+  Succeeded,
+  Failed,
+  /// Not processed, or removed again, because another configuration failed
+  Cancelled,
+}
+
+/// Outcome of a configuration, or of the original when `save_original` is set
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ConfigurationStatus {
+  /// Id of the configuration, or of the request for the original
+  pub id: String,
+  pub status: ConfigurationOutcome,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+impl ConfigurationStatus {
+  pub fn new(id: &str) -> Self {
+    Self {
+      id: id.to_owned(),
+      status: ConfigurationOutcome::Cancelled,
+      error: None,
+    }
+  }
+
+  pub fn fail(&mut self, error: &str) {
+    self.status = ConfigurationOutcome::Failed;
+    self.error = Some(error.to_owned());
+  }
+}
+
+/// Uploaded images and the outcome of every configuration
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ProcessReport {
+  pub images: Vec<ProcessedImage>,
+  pub configurations: Vec<ConfigurationStatus>,
+}
+
+impl ProcessReport {
+  pub fn has_failures(&self) -> bool {
+    self
+      .configurations
+      .iter()
+      .any(|status| status.status == ConfigurationOutcome::Failed)
+  }
+}
+
+/// Response of a synchronous process-image request, the list of images with
+/// `all_or_nothing` or the report with `best_effort`
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(untagged)]
+pub enum ProcessResponse {
+  Images(Vec<ProcessedImage>),
+  Report(ProcessReport),
+}
+
+impl ProcessResponse {
+  /// Respond with the report only in best effort mode, keeping the response
+  /// unchanged for clients relying on all or nothing
+  pub fn new(report: ProcessReport, failure_mode: FailureMode) -> Self {
+    match failure_mode {
+      FailureMode::AllOrNothing => ProcessResponse::Images(report.images),
+      FailureMode::BestEffort => ProcessResponse::Report(report),
+    }
+  }
+}
@@ -0,0 +1,165 @@
+use super::{ImageConfiguration, ProcessedImage};
+
+/// Maximum number of widths or densities of a configuration
+pub const MAX_ENTRIES: usize = 10;
+/// Maximum pixel density, bounding the size rendered for a ladder
+pub const MAX_DENSITY: f64 = 4.0;
+
+impl ImageConfiguration {
+  /// Whether the configuration renders a srcset of several sizes
+  pub fn has_srcset(&self) -> bool {
+    self.widths.is_some() || self.densities.is_some()
+  }
+
+  /// The densities the source can be rendered at, dropping those `upscales`
+  /// the source for like widths wider than the image. 1 is always kept, it's
+  /// the primary image.
+  pub fn densities_within(&self, upscales: impl Fn(f64) -> bool) -> Option<Vec<f64>> {
+    self.densities.as_ref().map(|densities| {
+      densities
+        .iter()
+        .copied()
+        .filter(|density| *density == 1.0 || !upscales(*density))
+        .collect()
+    })
+  }
+}
+
+/// The largest pixel density, at least 1. The image is rendered at `size`
+/// times it and every other size is scaled down from there.
+pub fn max_density(densities: Option<&[f64]>) -> f64 {
+  densities.into_iter().flatten().copied().fold(1.0, f64::max)
+}
+
+pub fn width_descriptor(width: i32) -> String {
+  format!("{}w", width)
+}
+
+pub fn density_descriptor(density: f64) -> String {
+  format!("{}x", density)
+}
+
+/// Set the `srcset` of the first image of every configuration and format,
+/// listing the images of that configuration and format with a descriptor
+pub fn link(images: &mut [ProcessedImage]) {
+  let entries: Vec<(String, String, String)> = images
+    .iter()
+    .filter_map(|image| {
+      let descriptor = image.descriptor.as_deref()?;
+      // Local storage has no urls, dry runs nothing uploaded
+      let location = if image.url.is_empty() {
+        &image.path
+      } else {
+        &image.url
+      };
+
+      Some((
+        configuration_of(image).to_owned(),
+        image.mime.clone(),
+        format!("{} {}", location, descriptor),
+      ))
+    })
+    .collect();
+
+  let mut linked: Vec<(String, String)> = Vec::new();
+  for image in images.iter_mut() {
+    if image.descriptor.is_none() {
+      continue;
+    }
+    let key = (configuration_of(image).to_owned(), image.mime.clone());
+    if linked.contains(&key) {
+      continue;
+    }
+
+    let srcset: Vec<&str> = entries
+      .iter()
+      .filter(|(configuration, mime, _)| *configuration == key.0 && *mime == key.1)
+      .map(|(_, _, entry)| entry.as_str())
+      .collect();
+    image.srcset = Some(srcset.join(", "));
+    linked.push(key);
+  }
+}
+
+/// Id of the configuration an image was rendered for
+fn configuration_of(image: &ProcessedImage) -> &str {
+  image.alternative_to.as_deref().unwrap_or(&image.id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(id: &str, alternative_to: Option<&str>, mime: &str, descriptor: &str) -> ProcessedImage {
+    ProcessedImage {
+      id: id.to_owned(),
+      alternative_to: alternative_to.map(str::to_owned),
+      path: format!("{}-{}", id, descriptor),
+      url: String::new(),
+      mime: mime.to_owned(),
+      hash: String::new(),
+      size: 0,
+      width: 0,
+      height: 0,
+      descriptor: Some(descriptor.to_owned()),
+      srcset: None,
+      deduplicated: false,
+      data: None,
+    }
+  }
+
+  #[test]
+  fn link_srcsets() {
+    let mut images = vec![
+      image("large", None, "image/jpeg", "1x"),
+      image("a", Some("large"), "image/webp", "1x"),
+      image("b", Some("large"), "image/jpeg", "2x"),
+      image("c", Some("large"), "image/webp", "2x"),
+    ];
+    link(&mut images);
+
+    assert_eq!(images[0].srcset.as_deref(), Some("large-1x 1x, b-2x 2x"));
+    assert_eq!(images[1].srcset.as_deref(), Some("a-1x 1x, c-2x 2x"));
+    assert!(images[2].srcset.is_none());
+    assert!(images[3].srcset.is_none());
+  }
+
+  #[test]
+  fn drop_densities_scaling_up() {
+    let config: ImageConfiguration = serde_json::from_value(serde_json::json!({
+      "id": "srcset",
+      "path": "srcset",
+      "aspect": 1.0,
+      "margin_percent": 0,
+      "size": 400,
+      "quality": 80,
+      "densities": [3, 2, 1.5, 1],
+      "conditions": {
+        "transparent": false,
+        "trim": false,
+        "black_and_white": false,
+        "use_environment_image": false,
+        "allow_vector": false
+      }
+    }))
+    .unwrap();
+
+    // A source of 800 pixels
+    let densities = config.densities_within(|density| 400.0 * density > 800.0);
+    assert_eq!(densities.as_deref(), Some(&[2.0, 1.5, 1.0][..]));
+    assert_eq!(max_density(densities.as_deref()), 2.0);
+
+    // 1 is kept for sources smaller than `size`
+    let densities = config.densities_within(|_| true);
+    assert_eq!(densities.as_deref(), Some(&[1.0][..]));
+    assert_eq!(max_density(densities.as_deref()), 1.0);
+    assert_eq!(max_density(None), 1.0);
+  }
+
+  #[test]
+  fn descriptors() {
+    assert_eq!(width_descriptor(640), "640w");
+    assert_eq!(density_descriptor(2.0), "2x");
+    assert_eq!(density_descriptor(1.5), "1.5x");
+  }
+}
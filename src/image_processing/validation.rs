@@ -0,0 +1,985 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Serialize, de::DeserializeOwned};
+use utoipa::ToSchema;
+
+use super::path_template::{self, ExtensionPolicy};
+use super::srcset;
+use super::{EnvironmentImage, ImageConfiguration, ImageProcessingRequest, OutputFormat};
+use crate::image_modifier::adjust::{self, ColourAdjustments};
+use crate::image_modifier::colour;
+use crate::image_modifier::environment::MAX_CORNER_COORDINATE;
+use crate::image_modifier::sharpen::{self, Sharpen};
+use crate::image_modifier::text::TextOverlay;
+use crate::image_modifier::watermark::Watermark;
+use crate::image_modifier::{between, blur, pixelate};
+
+/// Maximum length of the text of a text overlay, in characters
+const MAX_TEXT_LENGTH: usize = 500;
+
+/// A validation error for a single field, addressed by its path in the request
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FieldError {
+  /// Path of the field, e.g. `configurations[1].quality`
+  pub field: String,
+  pub message: String,
+}
+
+impl FieldError {
+  pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+    Self {
+      field: field.into(),
+      message: message.into(),
+    }
+  }
+}
+
+/// Parse an `ImageProcessingRequest` from JSON, reporting type errors and, if
+/// `deny_unknown_fields` is set, unknown fields by their path
+pub fn parse_request(
+  bytes: &[u8],
+  deny_unknown_fields: bool,
+) -> Result<ImageProcessingRequest, Vec<FieldError>> {
+  parse(bytes, deny_unknown_fields)
+}
+
+/// Parse any request body from JSON, see `parse_request`
+pub fn parse<T: DeserializeOwned>(
+  bytes: &[u8],
+  deny_unknown_fields: bool,
+) -> Result<T, Vec<FieldError>> {
+  let mut unknown_fields = Vec::new();
+  let mut on_ignored = |path: serde_ignored::Path| unknown_fields.push(ignored_path(&path));
+
+  let mut json = serde_json::Deserializer::from_slice(bytes);
+  let request: T =
+    serde_path_to_error::deserialize(serde_ignored::Deserializer::new(&mut json, &mut on_ignored))
+      .map_err(|e| {
+        let field = e.path().to_string();
+        vec![FieldError::new(field, e.into_inner().to_string())]
+      })?;
+  json
+    .end()
+    .map_err(|e| vec![FieldError::new(".", e.to_string())])?;
+
+  if deny_unknown_fields && !unknown_fields.is_empty() {
+    return Err(
+      unknown_fields
+        .into_iter()
+        .map(|field| FieldError::new(field, "unknown field"))
+        .collect(),
+    );
+  }
+
+  Ok(request)
+}
+
+// Format a path the same way as serde_path_to_error, e.g. `configurations[0].mime`
+fn ignored_path(path: &serde_ignored::Path) -> String {
+  match path {
+    serde_ignored::Path::Root => String::new(),
+    serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", ignored_path(parent), index),
+    serde_ignored::Path::Map { parent, key } => match ignored_path(parent) {
+      parent if parent.is_empty() => key.clone(),
+      parent => format!("{}.{}", parent, key),
+    },
+    serde_ignored::Path::Some { parent }
+    | serde_ignored::Path::NewtypeStruct { parent }
+    | serde_ignored::Path::NewtypeVariant { parent } => ignored_path(parent),
+  }
+}
+
+impl ImageProcessingRequest {
+  /// Check the request for values that can't be processed, before any decoding
+  pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+    let mut errors = Vec::new();
+
+    if self.id.is_empty() {
+      errors.push(FieldError::new("id", "must not be empty"));
+    }
+
+    if self.path.is_empty() {
+      errors.push(FieldError::new("path", "must not be empty"));
+    } else if let Err(message) = path_template::validate(&self.path) {
+      errors.push(FieldError::new("path", message));
+    }
+
+    if let Some(source) = self.source.as_deref() {
+      if source.is_empty() {
+        errors.push(FieldError::new("source", "must not be empty"));
+      } else if !is_url(source) && !is_relative_key(source) {
+        errors.push(FieldError::new(
+          "source",
+          "must be an http(s) URL or a storage key without a leading '/', '..' or '\\'",
+        ));
+      }
+    }
+
+    if let Some(min_size) = self.min_size
+      && min_size < 0
+    {
+      errors.push(FieldError::new("min_size", "must not be negative"));
+    }
+
+    if let Some(env) = &self.portrait_environment_image {
+      env.validate("portrait_environment_image", &mut errors);
+    }
+
+    if let Some(env) = &self.landscape_environment_image {
+      env.validate("landscape_environment_image", &mut errors);
+    }
+
+    for (i, env) in self.environment_images.iter().flatten().enumerate() {
+      let field = format!("environment_images[{}]", i);
+      env.validate(&field, &mut errors);
+
+      if env.id.as_deref().is_none_or(str::is_empty) {
+        errors.push(FieldError::new(format!("{}.id", field), "must be set"));
+      }
+    }
+
+    let scenes = self.environment_scenes();
+    let mut scene_ids = HashSet::new();
+    for scene in &scenes {
+      if let Some(id) = scene.id.as_deref()
+        && !id.is_empty()
+        && !scene_ids.insert(id)
+      {
+        errors.push(FieldError::new(
+          "environment_images",
+          format!("has more than one image with id {}", id),
+        ));
+      }
+    }
+
+    validate_object_options(
+      "",
+      &self.cache_control,
+      &self.content_disposition,
+      &self.storage_class,
+      &self.metadata,
+      &mut errors,
+    );
+
+    if let Some(callback_url) = &self.callback_url
+      && !matches!(
+        url::Url::parse(callback_url).map(|url| url.scheme().to_owned()),
+        Ok(scheme) if scheme == "http" || scheme == "https"
+      )
+    {
+      errors.push(FieldError::new(
+        "callback_url",
+        "must be an absolute http or https URL",
+      ));
+    }
+
+    if self.callback_secret.is_some() && self.callback_url.is_none() {
+      errors.push(FieldError::new("callback_secret", "requires callback_url"));
+    }
+
+    let mut ids = HashSet::new();
+    for (i, config) in self.configurations.iter().enumerate() {
+      let field = format!("configurations[{}]", i);
+      config.validate(&field, self.extension, &mut errors);
+
+      if !config.id.is_empty() && !ids.insert(config.id.as_str()) {
+        errors.push(FieldError::new(format!("{}.id", field), "must be unique"));
+      }
+
+      if let Some(environment) = config.environment.as_deref()
+        && !scene_ids.contains(environment)
+      {
+        errors.push(FieldError::new(
+          format!("{}.environment", field),
+          "must be the id of an environment image",
+        ));
+      }
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+}
+
+/// Check the named watermarks of the configuration file, which `/scale`
+/// stamps as they are
+pub fn validate_watermarks(watermarks: &HashMap<String, Watermark>) -> Result<(), Vec<FieldError>> {
+  let mut names: Vec<&String> = watermarks.keys().collect();
+  names.sort();
+
+  let mut errors = Vec::new();
+  for name in names {
+    watermarks[name].validate(&format!("watermarks.{}", name), &mut errors);
+  }
+
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(errors)
+  }
+}
+
+impl ImageConfiguration {
+  fn validate(
+    &self,
+    field: &str,
+    default_extension: Option<ExtensionPolicy>,
+    errors: &mut Vec<FieldError>,
+  ) {
+    if self.id.is_empty() {
+      errors.push(FieldError::new(
+        format!("{}.id", field),
+        "must not be empty",
+      ));
+    }
+
+    if self.path.is_empty() {
+      errors.push(FieldError::new(
+        format!("{}.path", field),
+        "must not be empty",
+      ));
+    } else if let Err(message) = path_template::validate(&self.path) {
+      errors.push(FieldError::new(format!("{}.path", field), message));
+    } else if self.extension.or(default_extension) == Some(ExtensionPolicy::None) {
+      // Without these the images of the configuration share a path
+      if !self.path.contains("{format}") {
+        errors.push(FieldError::new(
+          format!("{}.path", field),
+          "must contain {format} when extension is none",
+        ));
+      } else if self.has_srcset()
+        && !self.path.contains("{width}")
+        && !self.path.contains("{descriptor}")
+      {
+        errors.push(FieldError::new(
+          format!("{}.path", field),
+          "must contain {width} or {descriptor} for a srcset when extension is none",
+        ));
+      }
+    }
+
+    if self.widths.is_some() && self.densities.is_some() {
+      errors.push(FieldError::new(
+        format!("{}.widths", field),
+        "can't be combined with densities",
+      ));
+    }
+
+    if let Some(widths) = &self.widths {
+      if widths.is_empty() || widths.len() > srcset::MAX_ENTRIES {
+        errors.push(FieldError::new(
+          format!("{}.widths", field),
+          format!("must have between 1 and {} entries", srcset::MAX_ENTRIES),
+        ));
+      }
+      if widths.iter().any(|width| *width <= 0) {
+        errors.push(FieldError::new(
+          format!("{}.widths", field),
+          "must be greater than 0",
+        ));
+      }
+    }
+
+    if let Some(densities) = &self.densities {
+      if densities.is_empty() || densities.len() > srcset::MAX_ENTRIES {
+        errors.push(FieldError::new(
+          format!("{}.densities", field),
+          format!("must have between 1 and {} entries", srcset::MAX_ENTRIES),
+        ));
+      }
+      if densities
+        .iter()
+        .any(|density| !(*density > 0.0 && *density <= srcset::MAX_DENSITY))
+      {
+        errors.push(FieldError::new(
+          format!("{}.densities", field),
+          format!("must be greater than 0 and at most {}", srcset::MAX_DENSITY),
+        ));
+      }
+      // Environment images are composited at their own size, whatever the density
+      if self.conditions.use_environment_image {
+        errors.push(FieldError::new(
+          format!("{}.densities", field),
+          "can't be combined with use_environment_image",
+        ));
+      }
+    }
+
+    if !self.aspect.is_finite() || self.aspect <= 0.0 {
+      errors.push(FieldError::new(
+        format!("{}.aspect", field),
+        "must be greater than 0",
+      ));
+    }
+
+    if !(0..100).contains(&self.margin_percent) {
+      errors.push(FieldError::new(
+        format!("{}.margin_percent", field),
+        "must be between 0 and 99",
+      ));
+    }
+
+    if self.size <= 0 {
+      errors.push(FieldError::new(
+        format!("{}.size", field),
+        "must be greater than 0",
+      ));
+    }
+
+    if !(1..=100).contains(&self.quality) {
+      errors.push(FieldError::new(
+        format!("{}.quality", field),
+        "must be between 1 and 100",
+      ));
+    }
+
+    validate_object_options(
+      &format!("{}.", field),
+      &self.cache_control,
+      &self.content_disposition,
+      &self.storage_class,
+      &self.metadata,
+      errors,
+    );
+
+    if let Some(watermark) = &self.watermark {
+      watermark.validate(&format!("{}.watermark", field), errors);
+    }
+
+    if let Some(adjustments) = &self.adjustments {
+      adjustments.validate(&format!("{}.adjustments", field), errors);
+    }
+
+    if let Some(sharpen) = &self.sharpen {
+      sharpen.validate(&format!("{}.sharpen", field), errors);
+    }
+
+    if let Some(sigma) = self.blur
+      && !blur::SIGMA_RANGE.contains(&sigma)
+    {
+      errors.push(FieldError::new(
+        format!("{}.blur", field),
+        between(&blur::SIGMA_RANGE),
+      ));
+    }
+
+    if let Some(size) = self.pixelate
+      && !pixelate::SIZE_RANGE.contains(&size)
+    {
+      errors.push(FieldError::new(
+        format!("{}.pixelate", field),
+        between(&pixelate::SIZE_RANGE),
+      ));
+    }
+
+    if let Some(text) = &self.text {
+      text.validate(&format!("{}.text", field), errors);
+    }
+
+    for (name, value) in [
+      ("tint", self.conditions.tint.as_ref()),
+      (
+        "duotone.shadows",
+        self.conditions.duotone.as_ref().map(|d| &d.shadows),
+      ),
+      (
+        "duotone.highlights",
+        self.conditions.duotone.as_ref().map(|d| &d.highlights),
+      ),
+    ] {
+      if let Some(value) = value
+        && colour::parse_hex(value).is_none()
+      {
+        errors.push(FieldError::new(
+          format!("{}.conditions.{}", field, name),
+          "must be a hex colour like #000000",
+        ));
+      }
+    }
+
+    if let Some(mime) = &self.conditions.mime
+      && OutputFormat::from_mime(mime).is_none()
+    {
+      errors.push(FieldError::new(
+        format!("{}.conditions.mime", field),
+        "unsupported mime type",
+      ));
+    }
+  }
+}
+
+impl EnvironmentImage {
+  fn validate(&self, field: &str, errors: &mut Vec<FieldError>) {
+    if self.path.is_empty() {
+      errors.push(FieldError::new(
+        format!("{}.path", field),
+        "must not be empty",
+      ));
+    }
+
+    if let Some(mask_path) = &self.mask_path
+      && mask_path.is_empty()
+    {
+      errors.push(FieldError::new(
+        format!("{}.mask_path", field),
+        "must not be empty",
+      ));
+    }
+
+    match &self.corners {
+      Some(corners) => {
+        if !corners.in_range() {
+          errors.push(FieldError::new(
+            format!("{}.corners", field),
+            format!("must be between 0 and {} pixels", MAX_CORNER_COORDINATE),
+          ));
+        } else if !corners.is_convex() {
+          errors.push(FieldError::new(
+            format!("{}.corners", field),
+            "must form a convex quadrilateral",
+          ));
+        }
+      }
+      None => {
+        if self.width <= 0 {
+          errors.push(FieldError::new(
+            format!("{}.width", field),
+            "must be greater than 0",
+          ));
+        }
+
+        if self.height <= 0 {
+          errors.push(FieldError::new(
+            format!("{}.height", field),
+            "must be greater than 0",
+          ));
+        }
+      }
+    }
+
+    if self.x < 0 {
+      errors.push(FieldError::new(
+        format!("{}.x", field),
+        "must not be negative",
+      ));
+    }
+
+    if self.y < 0 {
+      errors.push(FieldError::new(
+        format!("{}.y", field),
+        "must not be negative",
+      ));
+    }
+
+    if !(0..100).contains(&self.margin_percent) {
+      errors.push(FieldError::new(
+        format!("{}.margin_percent", field),
+        "must be between 0 and 99",
+      ));
+    }
+
+    for (name, aspect) in [
+      ("min_aspect", self.min_aspect),
+      ("max_aspect", self.max_aspect),
+    ] {
+      if let Some(aspect) = aspect
+        && !(aspect.is_finite() && aspect > 0.0)
+      {
+        errors.push(FieldError::new(
+          format!("{}.{}", field, name),
+          "must be greater than 0",
+        ));
+      }
+    }
+
+    if let (Some(min), Some(max)) = (self.min_aspect, self.max_aspect)
+      && min > max
+    {
+      errors.push(FieldError::new(
+        format!("{}.max_aspect", field),
+        "must not be less than min_aspect",
+      ));
+    }
+
+    if let Some(shadow) = &self.shadow {
+      if !(0.0..=100.0).contains(&shadow.blur) {
+        errors.push(FieldError::new(
+          format!("{}.shadow.blur", field),
+          "must be between 0 and 100",
+        ));
+      }
+
+      if !(0.0..=1.0).contains(&shadow.opacity) {
+        errors.push(FieldError::new(
+          format!("{}.shadow.opacity", field),
+          "must be between 0 and 1",
+        ));
+      }
+
+      if colour::parse_hex(&shadow.colour).is_none() {
+        errors.push(FieldError::new(
+          format!("{}.shadow.colour", field),
+          "must be a hex colour like #000000",
+        ));
+      }
+    }
+  }
+}
+
+impl Watermark {
+  fn validate(&self, field: &str, errors: &mut Vec<FieldError>) {
+    if self.path.is_empty() {
+      errors.push(FieldError::new(
+        format!("{}.path", field),
+        "must not be empty",
+      ));
+    }
+
+    if !(self.scale > 0.0 && self.scale <= 1.0) {
+      errors.push(FieldError::new(
+        format!("{}.scale", field),
+        "must be greater than 0 and at most 1",
+      ));
+    }
+
+    if !(0.0..=1.0).contains(&self.opacity) {
+      errors.push(FieldError::new(
+        format!("{}.opacity", field),
+        "must be between 0 and 1",
+      ));
+    }
+  }
+}
+
+impl ColourAdjustments {
+  fn validate(&self, field: &str, errors: &mut Vec<FieldError>) {
+    for (name, level) in [
+      ("brightness", self.brightness),
+      ("contrast", self.contrast),
+      ("saturation", self.saturation),
+    ] {
+      if let Some(level) = level
+        && !adjust::LEVEL_RANGE.contains(&level)
+      {
+        errors.push(FieldError::new(
+          format!("{}.{}", field, name),
+          between(&adjust::LEVEL_RANGE),
+        ));
+      }
+    }
+
+    if let Some(gamma) = self.gamma
+      && !adjust::GAMMA_RANGE.contains(&gamma)
+    {
+      errors.push(FieldError::new(
+        format!("{}.gamma", field),
+        between(&adjust::GAMMA_RANGE),
+      ));
+    }
+  }
+}
+
+impl Sharpen {
+  fn validate(&self, field: &str, errors: &mut Vec<FieldError>) {
+    if !sharpen::SIGMA_RANGE.contains(&self.sigma) {
+      errors.push(FieldError::new(
+        format!("{}.sigma", field),
+        between(&sharpen::SIGMA_RANGE),
+      ));
+    }
+
+    for (name, amount) in [("amount", self.amount), ("flat", self.flat)] {
+      if !sharpen::AMOUNT_RANGE.contains(&amount) {
+        errors.push(FieldError::new(
+          format!("{}.{}", field, name),
+          between(&sharpen::AMOUNT_RANGE),
+        ));
+      }
+    }
+
+    if !(0.0..=100.0).contains(&self.threshold) {
+      errors.push(FieldError::new(
+        format!("{}.threshold", field),
+        "must be between 0 and 100",
+      ));
+    }
+  }
+}
+
+impl TextOverlay {
+  fn validate(&self, field: &str, errors: &mut Vec<FieldError>) {
+    if self.text.trim().is_empty() {
+      errors.push(FieldError::new(
+        format!("{}.text", field),
+        "must not be empty",
+      ));
+    } else if self.text.chars().count() > MAX_TEXT_LENGTH {
+      errors.push(FieldError::new(
+        format!("{}.text", field),
+        format!("must be at most {} characters", MAX_TEXT_LENGTH),
+      ));
+    }
+
+    // The font is part of a Pango font description
+    if self.font.is_empty()
+      || !self
+        .font
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | ','))
+    {
+      errors.push(FieldError::new(
+        format!("{}.font", field),
+        "must be a font name of letters, digits, spaces, dashes and commas",
+      ));
+    }
+
+    if !(1..=1000).contains(&self.size) {
+      errors.push(FieldError::new(
+        format!("{}.size", field),
+        "must be between 1 and 1000",
+      ));
+    }
+
+    for (name, value) in [
+      ("colour", Some(&self.colour)),
+      ("background", self.background.as_ref()),
+    ] {
+      if let Some(value) = value
+        && colour::parse_hex(value).is_none()
+      {
+        errors.push(FieldError::new(
+          format!("{}.{}", field, name),
+          "must be a hex colour like #000000",
+        ));
+      }
+    }
+
+    if self.padding < 0 {
+      errors.push(FieldError::new(
+        format!("{}.padding", field),
+        "must not be negative",
+      ));
+    }
+
+    if !(self.max_width > 0.0 && self.max_width <= 1.0) {
+      errors.push(FieldError::new(
+        format!("{}.max_width", field),
+        "must be greater than 0 and at most 1",
+      ));
+    }
+  }
+}
+
+// Values end up in object headers, so they must be valid header values
+fn is_header_value(value: &str) -> bool {
+  value.chars().all(|c| c.is_ascii() && !c.is_ascii_control())
+}
+
+/// Whether the source is an `http` or `https` URL rather than a storage key
+fn is_url(source: &str) -> bool {
+  url::Url::parse(source).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Whether the storage key stays below the root of the storage
+fn is_relative_key(key: &str) -> bool {
+  !key.starts_with('/') && !key.contains('\\') && key.split('/').all(|segment| segment != "..")
+}
+
+/// Message of a value outside of the range
+fn validate_object_options(
+  prefix: &str,
+  cache_control: &Option<String>,
+  content_disposition: &Option<String>,
+  storage_class: &Option<String>,
+  metadata: &Option<HashMap<String, String>>,
+  errors: &mut Vec<FieldError>,
+) {
+  if let Some(cache_control) = cache_control
+    && (cache_control.is_empty() || !is_header_value(cache_control))
+  {
+    errors.push(FieldError::new(
+      format!("{}cache_control", prefix),
+      "must be a non-empty header value",
+    ));
+  }
+
+  if let Some(content_disposition) = content_disposition
+    && (content_disposition.is_empty() || !is_header_value(content_disposition))
+  {
+    errors.push(FieldError::new(
+      format!("{}content_disposition", prefix),
+      "must be a non-empty header value",
+    ));
+  }
+
+  if let Some(storage_class) = storage_class
+    && (storage_class.is_empty()
+      || !storage_class
+        .chars()
+        .all(|c| c.is_ascii_uppercase() || c == '_'))
+  {
+    errors.push(FieldError::new(
+      format!("{}storage_class", prefix),
+      "must be a storage class such as STANDARD_IA",
+    ));
+  }
+
+  if let Some(metadata) = metadata {
+    let mut keys: Vec<&String> = metadata.keys().collect();
+    keys.sort();
+
+    for key in keys {
+      let field = format!("{}metadata.{}", prefix, key);
+      if key.is_empty()
+        || !key
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+      {
+        errors.push(FieldError::new(
+          field,
+          "key must only contain letters, digits, '-' and '_'",
+        ));
+      } else if !is_header_value(&metadata[key]) {
+        errors.push(FieldError::new(field, "must be a header value"));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REQUEST: &str = r#"{
+    "id": "original",
+    "path": "output",
+    "save_original": false,
+    "legacy_flag": true,
+    "configurations": [
+      {
+        "id": "config",
+        "path": "output_config",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 1024,
+        "quality": 80,
+        "conditions": {
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false,
+          "allow_vector": false,
+          "option_id": "uuid"
+        }
+      }
+    ]
+  }"#;
+
+  #[test]
+  fn parse_request_ignores_unknown_fields() {
+    let request = parse_request(REQUEST.as_bytes(), false).expect("request should parse");
+    assert!(request.validate().is_ok());
+  }
+
+  #[test]
+  fn parse_request_denies_unknown_fields() {
+    let errors = parse_request(REQUEST.as_bytes(), true).expect_err("unknown fields");
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+      fields,
+      vec!["legacy_flag", "configurations[0].conditions.option_id"]
+    );
+  }
+
+  #[test]
+  fn parse_request_reports_type_error_path() {
+    let json = REQUEST.replace(r#""quality": 80"#, r#""quality": "high""#);
+    let errors = parse_request(json.as_bytes(), false).expect_err("type error");
+    assert_eq!(errors[0].field, "configurations[0].quality");
+  }
+
+  #[test]
+  fn validate_reports_field_errors() {
+    let json = REQUEST
+      .replace(r#""aspect": 1.33"#, r#""aspect": 0"#)
+      .replace(r#""quality": 80"#, r#""quality": 101"#)
+      .replace(r#""size": 1024"#, r#""size": -1"#)
+      .replace(r#""path": "output""#, r#""path": """#)
+      .replace(
+        r#""save_original": false"#,
+        r#""save_original": false, "callback_url": "ftp://example.com""#,
+      );
+    let request = parse_request(json.as_bytes(), false).expect("request should parse");
+
+    let errors = request.validate().expect_err("invalid values");
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+      fields,
+      vec![
+        "path",
+        "callback_url",
+        "configurations[0].aspect",
+        "configurations[0].size",
+        "configurations[0].quality"
+      ]
+    );
+  }
+
+  #[test]
+  fn validate_reports_option_errors() {
+    let cases = [
+      (
+        vec![
+          (
+            r#""save_original": false"#,
+            r#""save_original": false, "environment_images": [
+              { "path": "a.png", "width": 10, "height": 10, "x": 0, "y": 0, "margin_percent": 0, "min_aspect": 2, "max_aspect": 1 }
+            ]"#,
+          ),
+          (
+            r#""quality": 80"#,
+            r#""quality": 80, "environment": "shelf""#,
+          ),
+        ],
+        vec![
+          "environment_images[0].max_aspect",
+          "environment_images[0].id",
+          "configurations[0].environment",
+        ],
+      ),
+      (
+        vec![(
+          r#""quality": 80"#,
+          r#""quality": 80, "adjustments": { "brightness": 10, "saturation": -150, "gamma": 0 }"#,
+        )],
+        vec![
+          "configurations[0].adjustments.saturation",
+          "configurations[0].adjustments.gamma",
+        ],
+      ),
+      (
+        vec![(
+          r#""black_and_white": false,"#,
+          r##""black_and_white": false, "tint": "brand", "duotone": { "shadows": "#1a237e", "highlights": "#ffca2" },"##,
+        )],
+        vec![
+          "configurations[0].conditions.tint",
+          "configurations[0].conditions.duotone.highlights",
+        ],
+      ),
+      (
+        vec![(
+          r#""quality": 80"#,
+          r#""quality": 80, "sharpen": { "sigma": 0 }, "blur": 4, "pixelate": 1"#,
+        )],
+        vec![
+          "configurations[0].sharpen.sigma",
+          "configurations[0].pixelate",
+        ],
+      ),
+      (
+        vec![(
+          r#""save_original": false"#,
+          r#""save_original": false, "portrait_environment_image": {
+            "path": "a.png", "margin_percent": 0, "corners": {
+              "top_left": { "x": 0, "y": 0 }, "top_right": { "x": 100, "y": 0 },
+              "bottom_right": { "x": 1e9, "y": 100 }, "bottom_left": { "x": 0, "y": 100 }
+            }
+          }"#,
+        )],
+        vec!["portrait_environment_image.corners"],
+      ),
+      (
+        vec![
+          (r#""quality": 80"#, r#""quality": 80, "densities": [1, 2]"#),
+          (
+            r#""use_environment_image": false"#,
+            r#""use_environment_image": true"#,
+          ),
+        ],
+        vec!["configurations[0].densities"],
+      ),
+      (
+        vec![(
+          r#""save_original": false"#,
+          r#""save_original": false, "source": "../../secret.png""#,
+        )],
+        vec!["source"],
+      ),
+      (
+        vec![(
+          r#""save_original": false"#,
+          r#""save_original": false, "source": "/etc/passwd""#,
+        )],
+        vec!["source"],
+      ),
+      (
+        vec![(
+          r#""save_original": false"#,
+          r#""save_original": false, "source": "originals\\..\\secret.png""#,
+        )],
+        vec!["source"],
+      ),
+    ];
+
+    for (replacements, expected) in cases {
+      let json = replacements
+        .iter()
+        .fold(REQUEST.to_owned(), |json, (from, to)| {
+          json.replace(from, to)
+        });
+      let request = parse_request(json.as_bytes(), false).expect("request should parse");
+
+      let errors = request.validate().expect_err("invalid values");
+      let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+      assert_eq!(fields, expected, "{}", json);
+    }
+  }
+
+  #[test]
+  fn validate_reports_ranges() {
+    let json = REQUEST.replace(
+      r#""quality": 80"#,
+      r#""quality": 80, "sharpen": { "sigma": 0, "amount": 30 }, "blur": 200, "pixelate": 1"#,
+    );
+    let request = parse_request(json.as_bytes(), false).expect("request should parse");
+
+    let errors = request.validate().expect_err("invalid values");
+    let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+      messages,
+      vec![
+        "must be between 0.1 and 10",
+        "must be between 0 and 20",
+        "must be between 0.3 and 100",
+        "must be between 2 and 512"
+      ]
+    );
+  }
+
+  #[test]
+  fn validate_configured_watermarks() {
+    let watermark = |scale: f64, opacity: f64| Watermark {
+      path: "watermarks/logo.png".to_owned(),
+      gravity: Default::default(),
+      offset_x: 0,
+      offset_y: 0,
+      scale,
+      opacity,
+      tile: false,
+    };
+    let mut watermarks = HashMap::from([("logo".to_owned(), watermark(0.3, 0.6))]);
+    assert!(validate_watermarks(&watermarks).is_ok());
+
+    watermarks.insert("sample".to_owned(), watermark(0.0, 1.5));
+    let errors = validate_watermarks(&watermarks).expect_err("invalid values");
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+      fields,
+      vec!["watermarks.sample.scale", "watermarks.sample.opacity"]
+    );
+  }
+}
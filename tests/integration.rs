@@ -2,8 +2,10 @@ use axum::{
   body::Body,
   http::{Request, StatusCode},
 };
+use base64::prelude::*;
 use http_body_util::BodyExt;
 use rusty_pixel::config;
+use std::collections::HashMap;
 use std::sync::OnceLock;
 use tokio::{fs, net::TcpListener};
 use tower::ServiceExt;
@@ -11,15 +13,18 @@ use tower::ServiceExt;
 static TEST_BOOSTRAP: OnceLock<axum::Router> = OnceLock::new();
 
 fn bootstrap() -> &'static axum::Router {
-  let router = TEST_BOOSTRAP.get_or_init(|| {
+  TEST_BOOSTRAP.get_or_init(|| {
     let cfg = config::Config {
       app: config::AppConfig {
         api_key: "test".to_string(),
         vips_concurrency: 1,
         max_body_size_mb: 10,
         enable_openapi: Some(false),
+        deny_unknown_fields: None,
         listen: "0.0.0.0:0".to_string(),
         metrics_listen: "0.0.0.0:0".to_string(),
+        timeouts: None,
+        allowed_source_hosts: Some(vec!["127.0.0.1".to_string()]),
       },
       storage: config::StorageConfig {
         storage_type: config::StorageType::Local,
@@ -28,12 +33,42 @@ fn bootstrap() -> &'static axum::Router {
         }),
         s3: None,
       },
+      jobs: Some(config::JobsConfig {
+        store_type: None,
+        ttl_secs: None,
+        max_concurrent: Some(2),
+      }),
+      callbacks: Some(config::CallbackConfig {
+        max_attempts: Some(3),
+        initial_backoff_ms: Some(10),
+        max_backoff_ms: Some(100),
+        timeout_secs: Some(5),
+        allowed_hosts: Some(vec!["127.0.0.1".to_string()]),
+      }),
+      batch: None,
+      processing: Some(config::ProcessingConfig {
+        render_concurrency: Some(2),
+        upload_concurrency: Some(2),
+        dry_run_max_mb: Some(1),
+        overlay_cache_mb: Some(64),
+        deduplicate_uploads: None,
+      }),
+      watermarks: Some(HashMap::from([(
+        "sample".to_string(),
+        serde_json::from_value(serde_json::json!({
+          "path": "env.png",
+          "gravity": "south_east",
+          "offset_x": 10,
+          "offset_y": 10,
+          "scale": 0.3,
+          "opacity": 0.6
+        }))
+        .unwrap(),
+      )])),
     };
 
     rusty_pixel::http::bootstrap(&cfg).expect("failed creating router")
-  });
-
-  router
+  })
 }
 
 #[tokio::test]
@@ -59,6 +94,117 @@ async fn scale_image() {
     .expect("failed saving image");
 }
 
+#[tokio::test]
+async fn scale_image_watermark() {
+  let router = bootstrap().clone();
+
+  let response = router
+    .clone()
+    .oneshot(
+      Request::builder()
+        .uri("/scale/s400x400-wmsample/skaune-portrait.png")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(response.status(), StatusCode::OK);
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  fs::write("tests/output/scale_watermark.jpg", body)
+    .await
+    .expect("failed saving image");
+
+  // Only configured watermarks can be used
+  let response = router
+    .oneshot(
+      Request::builder()
+        .uri("/scale/s400x400-wmother/skaune-portrait.png")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn scale_image_text() {
+  let router = bootstrap().clone();
+
+  let response = router
+    .oneshot(
+      Request::builder()
+        .uri("/scale/s400x400-txSOLD~20OUT/skaune-portrait.png")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(response.status(), StatusCode::OK);
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  fs::write("tests/output/scale_text.jpg", body)
+    .await
+    .expect("failed saving image");
+}
+
+#[tokio::test]
+async fn scale_image_adjustments() {
+  let router = bootstrap().clone();
+
+  let response = router
+    .oneshot(
+      Request::builder()
+        .uri("/scale/s400x400-br10-ct15-sat-20-gm1.2-norm/skaune-portrait.png")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(response.status(), StatusCode::OK);
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  fs::write("tests/output/scale_adjustments.jpg", body)
+    .await
+    .expect("failed saving image");
+}
+
+#[tokio::test]
+async fn scale_image_effects() {
+  for (options, output) in [
+    ("s400x400-sepia", "scale_sepia"),
+    ("s400x400-tintc62828", "scale_tint"),
+    ("s400x400-duo1a237e_ffca28", "scale_duotone"),
+    ("s400x400-blur8", "scale_blur"),
+    ("s400x400-m10-ash", "scale_sharpen"),
+    ("rw300-sh1_4", "scale_resize_sharpen"),
+    ("s400x400-px16", "scale_pixelate"),
+  ] {
+    let response = bootstrap()
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri(format!("/scale/{}/skaune-portrait.png", options))
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK, "{}", options);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    fs::write(format!("tests/output/{}.jpg", output), body)
+      .await
+      .expect("failed saving image");
+  }
+}
+
 #[tokio::test]
 async fn process_image() {
   let router = bootstrap().clone();
@@ -140,7 +286,7 @@ async fn process_image() {
   let client = reqwest::Client::new();
 
   let response = client
-    .post(&format!(
+    .post(format!(
       "http://{}:{}/api/v1/process-image",
       addr.ip(),
       addr.port()
@@ -170,46 +316,125 @@ async fn process_image() {
     );
   }
 
-  // A PNG upload should produce a WebP alternative for each configuration,
-  // linked back to its primary image via `alternative_to`.
-  let primary_ids: Vec<&str> = images
-    .iter()
-    .filter(|image| image["alternative_to"].is_null())
-    .filter_map(|image| image["id"].as_str())
-    .collect();
+  // Alternatives are only generated on request
+  assert!(
+    images.iter().all(|image| image["alternative_to"].is_null()),
+    "expected no alternatives without generate_alternative, got {images:?}"
+  );
+}
+
+#[tokio::test]
+async fn process_image_generate_alternative() {
+  let router = bootstrap().clone();
+
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  let json_request = r#"{
+    "id": "alternative-original",
+    "path": "output",
+    "save_original": false,
+    "generate_alternative": true,
+    "max_age": 31536000,
+    "configurations": [
+      {
+        "id": "alternative-jpeg",
+        "path": "output_alternative_jpeg",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {
+          "use_original_mime": false,
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }
+      },
+      {
+        "id": "alternative-png",
+        "path": "output_alternative_png",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {
+          "use_original_mime": true,
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }
+      }
+    ]
+  }"#;
+
+  let json_part = reqwest::multipart::Part::text(json_request);
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let file_part = reqwest::multipart::Part::bytes(file)
+    .file_name("skaune-portrait.png")
+    .mime_str("image/png")
+    .unwrap();
+
+  let form = reqwest::multipart::Form::new()
+    .part("image", file_part)
+    .part("details", json_part);
+
+  let client = reqwest::Client::new();
+
+  let response = client
+    .post(format!(
+      "http://{}:{}/api/v1/process-image",
+      addr.ip(),
+      addr.port()
+    ))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+
+  assert_eq!(response.status(), reqwest::StatusCode::OK);
 
-  let alternatives: Vec<&serde_json::Value> = images
+  let body = response.text().await.expect("failed to read response");
+  let body: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+  let images = body
+    .as_array()
+    .expect("expected an array of processed images");
+
+  // Each configuration gets a WebP alternative, linked back to its primary
+  // image via `alternative_to`
+  let mimes: Vec<(&str, bool, &str)> = images
     .iter()
-    .filter(|image| !image["alternative_to"].is_null())
+    .map(|image| {
+      let alternative_to = image["alternative_to"].as_str();
+      (
+        alternative_to.or(image["id"].as_str()).unwrap_or(""),
+        alternative_to.is_some(),
+        image["mime"].as_str().unwrap_or(""),
+      )
+    })
     .collect();
 
   assert_eq!(
-    alternatives.len(),
-    2,
-    "expected one WebP alternative per configuration, got {alternatives:?}"
+    mimes,
+    vec![
+      ("alternative-jpeg", false, "image/jpeg"),
+      ("alternative-jpeg", true, "image/webp"),
+      ("alternative-png", false, "image/png"),
+      ("alternative-png", true, "image/webp"),
+    ]
   );
-
-  for alternative in alternatives {
-    assert_eq!(
-      alternative["mime"].as_str(),
-      Some("image/webp"),
-      "expected alternative to be a WebP for {alternative:?}"
-    );
-    assert!(
-      alternative["path"]
-        .as_str()
-        .is_some_and(|path| path.ends_with(".webp")),
-      "expected alternative path to end with .webp for {alternative:?}"
-    );
-
-    let parent = alternative["alternative_to"]
-      .as_str()
-      .expect("alternative_to should be a string");
-    assert!(
-      primary_ids.contains(&parent),
-      "alternative_to {parent:?} does not reference a primary image id in {primary_ids:?}"
-    );
-  }
 }
 
 #[tokio::test]
@@ -227,6 +452,12 @@ async fn scale_image_not_found() {
     .unwrap();
 
   assert_eq!(response.status(), StatusCode::NOT_FOUND);
+  assert!(response.headers().contains_key("x-request-id"));
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  let body: serde_json::Value = serde_json::from_slice(&body).expect("failed to parse error");
+  assert_eq!(body["code"], "source_not_found");
+  assert!(body["request_id"].is_string());
 }
 
 #[tokio::test]
@@ -244,7 +475,50 @@ async fn scale_image_invalid_options() {
     .unwrap();
 
   // Invalid options produce an error since no modifiers are parsed
-  assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+  assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  let body: serde_json::Value = serde_json::from_slice(&body).expect("failed to parse error");
+  assert_eq!(body["code"], "invalid_option");
+}
+
+#[tokio::test]
+async fn scale_image_out_of_range_options() {
+  let router = bootstrap().clone();
+
+  // Recognised options with a value out of range aren't dropped
+  for options in [
+    "s400x300-blur999",
+    "px1",
+    "sh20",
+    "rw300-ash1_21",
+    "br101",
+    "s400x300-sat-101",
+    "gm11",
+  ] {
+    let response = router
+      .clone()
+      .oneshot(
+        Request::builder()
+          .uri(format!("/scale/{options}/skaune-portrait.png"))
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{options}");
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("failed to parse error");
+    assert_eq!(body["code"], "invalid_option", "{options}");
+    assert!(
+      body["message"]
+        .as_str()
+        .is_some_and(|message| message.contains("must be between")),
+      "{options}: {body}"
+    );
+  }
 }
 
 #[tokio::test]
@@ -262,7 +536,7 @@ async fn process_image_unauthorized() {
 
   // No API key
   let response = client
-    .post(&format!(
+    .post(format!(
       "http://{}:{}/api/v1/process-image",
       addr.ip(),
       addr.port()
@@ -275,7 +549,7 @@ async fn process_image_unauthorized() {
 
   // Wrong API key
   let response = client
-    .post(&format!(
+    .post(format!(
       "http://{}:{}/api/v1/process-image",
       addr.ip(),
       addr.port()
@@ -313,7 +587,7 @@ async fn process_image_missing_fields() {
   let form = reqwest::multipart::Form::new().part("image", file_part);
 
   let response = client
-    .post(&format!(
+    .post(format!(
       "http://{}:{}/api/v1/process-image",
       addr.ip(),
       addr.port()
@@ -338,8 +612,7 @@ async fn process_svg_without_allow_vector() {
     axum::serve(listener, router).await.unwrap();
   });
 
-  // An SVG uploaded without `allow_vector` should be rasterized and still
-  // produce a WebP alternative for the configuration.
+  // An SVG uploaded without `allow_vector` should be rasterized.
   let json_request = r#"{
     "id": "svg-original",
     "path": "output",
@@ -384,7 +657,7 @@ async fn process_svg_without_allow_vector() {
   let client = reqwest::Client::new();
 
   let response = client
-    .post(&format!(
+    .post(format!(
       "http://{}:{}/api/v1/process-image",
       addr.ip(),
       addr.port()
@@ -436,41 +709,1453 @@ async fn process_svg_without_allow_vector() {
     );
   }
 
-  let primary_ids: Vec<&str> = primaries
-    .iter()
-    .filter_map(|image| image["id"].as_str())
-    .collect();
+  assert!(
+    images.iter().all(|image| image["alternative_to"].is_null()),
+    "expected no alternatives without generate_alternative, got {images:?}"
+  );
+}
+
+#[tokio::test]
+async fn process_image_invalid_details() {
+  let router = bootstrap().clone();
+
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
 
-  let alternatives: Vec<&serde_json::Value> = images
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  let json_request = r#"{
+    "id": "invalid",
+    "path": "output",
+    "save_original": false,
+    "configurations": [
+      {
+        "id": "invalid-config",
+        "path": "output_invalid",
+        "aspect": 0,
+        "margin_percent": 10,
+        "size": 1024,
+        "quality": 101,
+        "conditions": {
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }
+      }
+    ]
+  }"#;
+
+  let json_part = reqwest::multipart::Part::text(json_request);
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let file_part = reqwest::multipart::Part::bytes(file)
+    .file_name("skaune-portrait.png")
+    .mime_str("image/png")
+    .unwrap();
+
+  let form = reqwest::multipart::Form::new()
+    .part("image", file_part)
+    .part("details", json_part);
+
+  let client = reqwest::Client::new();
+
+  let response = client
+    .post(format!(
+      "http://{}:{}/api/v1/process-image",
+      addr.ip(),
+      addr.port()
+    ))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+
+  assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+  let body = response.text().await.expect("failed to read response");
+  let body: serde_json::Value = serde_json::from_str(&body).expect("failed to parse error");
+  assert_eq!(body["code"], "validation_failed");
+
+  let fields: Vec<&str> = body["details"]["errors"]
+    .as_array()
+    .expect("expected a list of field errors")
     .iter()
-    .filter(|image| !image["alternative_to"].is_null())
+    .filter_map(|error| error["field"].as_str())
     .collect();
-
   assert_eq!(
-    alternatives.len(),
-    1,
-    "expected one WebP alternative for the SVG configuration, got {alternatives:?}"
+    fields,
+    vec!["configurations[0].aspect", "configurations[0].quality"]
   );
+}
 
-  for alternative in alternatives {
-    assert_eq!(
-      alternative["mime"].as_str(),
-      Some("image/webp"),
-      "expected alternative to be a WebP for {alternative:?}"
-    );
-    assert!(
-      alternative["path"]
-        .as_str()
-        .is_some_and(|path| path.ends_with(".webp")),
-      "expected alternative path to end with .webp for {alternative:?}"
-    );
+#[tokio::test]
+async fn process_image_output_formats() {
+  let router = bootstrap().clone();
 
-    let parent = alternative["alternative_to"]
-      .as_str()
-      .expect("alternative_to should be a string");
-    assert!(
-      primary_ids.contains(&parent),
-      "alternative_to {parent:?} does not reference a primary image id in {primary_ids:?}"
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  // The first configuration picks its own format and alternatives, the second
+  // opts out of alternatives altogether.
+  let json_request = r#"{
+    "id": "formats-original",
+    "path": "output",
+    "save_original": false,
+    "configurations": [
+      {
+        "id": "formats-webp",
+        "path": "output_formats_webp",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "format": "webp",
+        "alternatives": ["png", "webp"],
+        "conditions": {
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }
+      },
+      {
+        "id": "formats-original-mime",
+        "path": "output_formats_original",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false,
+          "use_original_mime": true,
+          "generate_alternative": false
+        }
+      }
+    ]
+  }"#;
+
+  let json_part = reqwest::multipart::Part::text(json_request);
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let file_part = reqwest::multipart::Part::bytes(file)
+    .file_name("skaune-portrait.png")
+    .mime_str("image/png")
+    .unwrap();
+
+  let form = reqwest::multipart::Form::new()
+    .part("image", file_part)
+    .part("details", json_part);
+
+  let client = reqwest::Client::new();
+
+  let response = client
+    .post(format!(
+      "http://{}:{}/api/v1/process-image",
+      addr.ip(),
+      addr.port()
+    ))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+
+  assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+  let body = response.text().await.expect("failed to read response");
+  let body: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+  let images = body
+    .as_array()
+    .expect("expected an array of processed images");
+
+  // Alternatives are reported by the id of the configuration they belong to
+  let mimes: Vec<(&str, bool, &str)> = images
+    .iter()
+    .map(|image| {
+      let alternative_to = image["alternative_to"].as_str();
+      (
+        alternative_to.or(image["id"].as_str()).unwrap_or(""),
+        alternative_to.is_some(),
+        image["mime"].as_str().unwrap_or(""),
+      )
+    })
+    .collect();
+
+  assert_eq!(
+    mimes,
+    vec![
+      ("formats-webp", false, "image/webp"),
+      ("formats-webp", true, "image/png"),
+      ("formats-original-mime", false, "image/png"),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn process_image_async_job() {
+  let router = bootstrap().clone();
+
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  let json_request = r#"{
+    "id": "async-original",
+    "path": "output",
+    "save_original": false,
+    "configurations": [
+      {
+        "id": "async-config",
+        "path": "output_async",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }
+      }
+    ]
+  }"#;
+
+  let json_part = reqwest::multipart::Part::text(json_request);
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let file_part = reqwest::multipart::Part::bytes(file)
+    .file_name("skaune-portrait.png")
+    .mime_str("image/png")
+    .unwrap();
+
+  let form = reqwest::multipart::Form::new()
+    .part("image", file_part)
+    .part("details", json_part);
+
+  let client = reqwest::Client::new();
+  let base_url = format!("http://{}:{}", addr.ip(), addr.port());
+
+  let response = client
+    .post(format!("{}/api/v1/process-image?async=true", base_url))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+
+  assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+
+  let location = response
+    .headers()
+    .get("location")
+    .and_then(|value| value.to_str().ok())
+    .map(ToOwned::to_owned);
+  let body = response.text().await.expect("failed to read response");
+  let accepted: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+  let status_url = accepted["status_url"]
+    .as_str()
+    .expect("expected a status url");
+  assert_eq!(location.as_deref(), Some(status_url));
+
+  // Poll until the job has finished
+  let mut job = serde_json::Value::Null;
+  for _ in 0..100 {
+    let response = client
+      .get(format!("{}{}", base_url, status_url))
+      .header("X-API-Key", "test")
+      .send()
+      .await
+      .expect("failed to send request");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    let body = response.text().await.expect("failed to read response");
+    job = serde_json::from_str(&body).expect("failed to parse response");
+    if !matches!(job["status"].as_str(), Some("queued") | Some("running")) {
+      break;
+    }
+
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+  }
+
+  assert_eq!(job["status"], "completed", "unexpected job {job:?}");
+  assert_eq!(job["id"], accepted["job_id"]);
+  let images = job["result"].as_array().expect("expected a result");
+  assert_eq!(images.len(), 2);
+  assert_eq!(job["uploaded"], 2);
+
+  // A finished job can't be cancelled
+  let response = client
+    .post(format!("{}{}/cancel", base_url, status_url))
+    .header("X-API-Key", "test")
+    .send()
+    .await
+    .expect("failed to send request");
+  assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn process_image_async_job_cancel() {
+  let router = bootstrap().clone();
+
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  // Enough configurations that the job is still rendering when cancelled
+  let configurations: Vec<serde_json::Value> = (0..12)
+    .map(|i| {
+      serde_json::json!({
+        "id": format!("cancel-config-{}", i),
+        "path": "output_cancel/{config_id}",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 2048,
+        "quality": 95,
+        "conditions": {
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }
+      })
+    })
+    .collect();
+  let json_request = serde_json::json!({
+    "id": "cancel-original",
+    "path": "output_cancel/{id}",
+    "save_original": false,
+    "configurations": configurations
+  });
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let form = reqwest::multipart::Form::new()
+    .part(
+      "image",
+      reqwest::multipart::Part::bytes(file)
+        .file_name("skaune-portrait.png")
+        .mime_str("image/png")
+        .unwrap(),
+    )
+    .part(
+      "details",
+      reqwest::multipart::Part::text(json_request.to_string()),
+    );
+
+  let client = reqwest::Client::new();
+  let base_url = format!("http://{}:{}", addr.ip(), addr.port());
+
+  let response = client
+    .post(format!("{}/api/v1/process-image?async=true", base_url))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+  assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+
+  let body = response.text().await.expect("failed to read response");
+  let accepted: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+  let status_url = accepted["status_url"]
+    .as_str()
+    .expect("expected a status url");
+
+  let response = client
+    .post(format!("{}{}/cancel", base_url, status_url))
+    .header("X-API-Key", "test")
+    .send()
+    .await
+    .expect("failed to send request");
+  assert_eq!(response.status(), reqwest::StatusCode::OK);
+  let body = response.text().await.expect("failed to read response");
+  let job: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+  assert_eq!(job["status"], "cancelled");
+
+  // The job stops rendering and removes what it uploaded, without
+  // overwriting the status
+  let mut removed = false;
+  for attempt in 0..100 {
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let response = client
+      .get(format!("{}{}", base_url, status_url))
+      .header("X-API-Key", "test")
+      .send()
+      .await
+      .expect("failed to send request");
+    let body = response.text().await.expect("failed to read response");
+    let job: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+    assert_eq!(job["status"], "cancelled", "unexpected job {job:?}");
+    assert!(job["result"].is_null());
+
+    let mut entries = match fs::read_dir("tests/testdata/output_cancel").await {
+      Ok(entries) => Some(entries),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+      Err(e) => panic!("failed to read output directory: {e}"),
+    };
+    removed = match &mut entries {
+      Some(entries) => entries.next_entry().await.unwrap().is_none(),
+      None => true,
+    };
+    // Give uploads in flight when the job was cancelled time to land first
+    if removed && attempt >= 20 {
+      break;
+    }
+  }
+  assert!(removed, "uploads of the cancelled job were not removed");
+}
+
+#[tokio::test]
+async fn job_not_found() {
+  let router = bootstrap().clone();
+
+  let response = router
+    .oneshot(
+      Request::builder()
+        .uri("/api/v1/jobs/does-not-exist")
+        .header("X-API-Key", "test")
+        .body(Body::empty())
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(body["code"], "job_not_found");
+}
+
+#[tokio::test]
+async fn process_image_async_callback() {
+  use hmac::{Hmac, Mac};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  let router = bootstrap().clone();
+
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  // Receiver failing the first delivery so it has to be retried
+  let (received_tx, mut received_rx) = tokio::sync::mpsc::channel(1);
+  let attempts = Arc::new(AtomicUsize::new(0));
+  let receiver = axum::Router::new().route(
+    "/hook",
+    axum::routing::post(
+      move |headers: axum::http::HeaderMap, body: axum::body::Bytes| {
+        let received_tx = received_tx.clone();
+        let attempts = attempts.clone();
+        async move {
+          if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            return StatusCode::SERVICE_UNAVAILABLE;
+          }
+          let header = |name: &str| {
+            headers
+              .get(name)
+              .and_then(|value| value.to_str().ok())
+              .map(ToOwned::to_owned)
+          };
+          let signature = header("x-rusty-pixel-signature");
+          let timestamp = header("x-rusty-pixel-timestamp");
+          let _ = received_tx.send((signature, timestamp, body)).await;
+          StatusCode::NO_CONTENT
+        }
+      },
+    ),
+  );
+  let receiver_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let receiver_addr = receiver_listener.local_addr().unwrap();
+  tokio::spawn(async move {
+    axum::serve(receiver_listener, receiver).await.unwrap();
+  });
+
+  let json_request = format!(
+    r#"{{
+    "id": "callback-original",
+    "path": "output",
+    "save_original": false,
+    "generate_alternative": false,
+    "callback_url": "http://{}/hook",
+    "callback_secret": "s3cret",
+    "configurations": [
+      {{
+        "id": "callback-config",
+        "path": "output_callback",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {{
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }}
+      }}
+    ]
+  }}"#,
+    receiver_addr
+  );
+
+  let json_part = reqwest::multipart::Part::text(json_request);
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let file_part = reqwest::multipart::Part::bytes(file)
+    .file_name("skaune-portrait.png")
+    .mime_str("image/png")
+    .unwrap();
+
+  let form = reqwest::multipart::Form::new()
+    .part("image", file_part)
+    .part("details", json_part);
+
+  let client = reqwest::Client::new();
+  let base_url = format!("http://{}:{}", addr.ip(), addr.port());
+
+  let response = client
+    .post(format!("{}/api/v1/process-image?async=true", base_url))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+  assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+
+  let body = response.text().await.expect("failed to read response");
+  let accepted: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+
+  let (signature, timestamp, body) =
+    tokio::time::timeout(std::time::Duration::from_secs(30), received_rx.recv())
+      .await
+      .expect("callback not received in time")
+      .expect("receiver closed");
+
+  // The signature is an HMAC-SHA256 of the timestamp and the body with the
+  // callback secret
+  let timestamp = timestamp.expect("expected a timestamp");
+  let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"s3cret").unwrap();
+  mac.update(format!("{}.", timestamp).as_bytes());
+  mac.update(&body);
+  let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+  assert_eq!(signature.as_deref(), Some(expected.as_str()));
+
+  let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(payload["job_id"], accepted["job_id"]);
+  assert_eq!(payload["id"], "callback-original");
+  assert_eq!(payload["status"], "completed");
+  assert_eq!(payload["images"].as_array().map(Vec::len), Some(1));
+
+  // The delivery is recorded on the job once the receiver has responded
+  let status_url = accepted["status_url"].as_str().unwrap();
+  let mut job = serde_json::Value::Null;
+  for _ in 0..50 {
+    let response = client
+      .get(format!("{}{}", base_url, status_url))
+      .header("X-API-Key", "test")
+      .send()
+      .await
+      .expect("failed to send request");
+    let body = response.text().await.expect("failed to read response");
+    job = serde_json::from_str(&body).expect("failed to parse response");
+    if job["callback"]["status"] != "pending" {
+      break;
+    }
+
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+  }
+
+  assert_eq!(
+    job["callback"]["status"], "delivered",
+    "unexpected job {job:?}"
+  );
+  assert_eq!(job["callback"]["attempts"], 2);
+}
+
+fn source_request(source: &str) -> String {
+  format!(
+    r#"{{
+    "id": "source-original",
+    "path": "output",
+    "source": "{}",
+    "save_original": false,
+    "generate_alternative": false,
+    "configurations": [
+      {{
+        "id": "source-config",
+        "path": "output_source",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {{
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }}
+      }}
+    ]
+  }}"#,
+    source
+  )
+}
+
+async fn post_source(body: String) -> (StatusCode, serde_json::Value) {
+  let router = bootstrap().clone();
+
+  let response = router
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/api/v1/process-image/source")
+        .header("X-API-Key", "test")
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  let status = response.status();
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+
+  (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn process_image_from_storage_key() {
+  let (status, body) = post_source(source_request("skaune-portrait.png")).await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  let images = body.as_array().expect("expected processed images");
+  assert_eq!(images.len(), 1);
+  assert_eq!(images[0]["id"], "source-config");
+}
+
+#[tokio::test]
+async fn callback_host_not_allowed() {
+  let router = bootstrap().clone();
+  let body = source_request("skaune-portrait.png").replace(
+    r#""save_original": false,"#,
+    r#""save_original": false, "callback_url": "http://169.254.169.254/latest","#,
+  );
+
+  let response = router
+    .oneshot(
+      Request::builder()
+        .method("POST")
+        .uri("/api/v1/process-image/source?async=true")
+        .header("X-API-Key", "test")
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+
+  assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+  let body = response.into_body().collect().await.unwrap().to_bytes();
+  let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+  assert_eq!(body["details"]["errors"][0]["field"], "callback_url");
+}
+
+#[tokio::test]
+async fn process_image_dry_run() {
+  let body = source_request("skaune-portrait.png")
+    .replace(
+      r#""save_original": false,"#,
+      r#""save_original": false, "dry_run": true,"#,
+    )
+    .replace("output_source", "output_dry_run");
+  let (status, body) = post_source(body).await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  let images = body.as_array().expect("expected processed images");
+  assert_eq!(images.len(), 1);
+  assert!(
+    !images[0]["data"].as_str().unwrap_or_default().is_empty(),
+    "expected inline data {body:?}"
+  );
+
+  // Nothing was uploaded
+  assert!(
+    !fs::try_exists("tests/testdata/output_dry_run.jpg")
+      .await
+      .unwrap()
+  );
+}
+
+/// Dry run of a single lossless configuration of the source at 32 pixels,
+/// with the fields of `request` and `config` merged into it, returning the
+/// rendered image
+async fn render_png(
+  source: &str,
+  request: serde_json::Value,
+  config: serde_json::Value,
+) -> libvips::VipsImage {
+  let mut body = serde_json::json!({
+    "id": "pixels",
+    "path": "output",
+    "source": source,
+    "save_original": false,
+    "generate_alternative": false,
+    "dry_run": true,
+  });
+  let mut configuration = serde_json::json!({
+    "id": "pixels-config",
+    "path": "output_pixels",
+    "aspect": 1.0,
+    "margin_percent": 0,
+    "size": 32,
+    "quality": 100,
+    "format": "png",
+    "conditions": {
+      "allow_vector": false,
+      "transparent": true,
+      "trim": false,
+      "black_and_white": false,
+      "use_environment_image": false
+    }
+  });
+  merge(&mut body, request);
+  merge(&mut configuration, config);
+  body["configurations"] = serde_json::json!([configuration]);
+
+  let (status, body) = post_source(body.to_string()).await;
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+
+  let data = body[0]["data"].as_str().expect("expected inline data");
+  let data = BASE64_STANDARD.decode(data).unwrap();
+  libvips::VipsImage::new_from_buffer(&data, "").expect("failed decoding image")
+}
+
+/// Merge the fields of `patch` into `target`, recursing into objects
+fn merge(target: &mut serde_json::Value, patch: serde_json::Value) {
+  match (target, patch) {
+    (serde_json::Value::Object(target), serde_json::Value::Object(patch)) => {
+      for (key, value) in patch {
+        merge(target.entry(key).or_insert(serde_json::Value::Null), value);
+      }
+    }
+    (target, patch) => *target = patch,
+  }
+}
+
+/// Bands of the pixel at x, y of an 8-bit image
+fn pixel(image: &libvips::VipsImage, x: i32, y: i32) -> Vec<u8> {
+  let bands = image.get_bands() as usize;
+  let offset = (y * image.get_width() + x) as usize * bands;
+
+  image.image_write_to_memory()[offset..offset + bands].to_vec()
+}
+
+/// Assert the bands of the pixel at x, y of an 8-bit image, within 2 levels
+fn assert_pixel(image: &libvips::VipsImage, x: i32, y: i32, expected: &[u8]) {
+  let pixel = pixel(image, x, y);
+
+  assert!(
+    pixel.len() >= expected.len()
+      && pixel
+        .iter()
+        .zip(expected)
+        .all(|(actual, expected)| actual.abs_diff(*expected) <= 2),
+    "pixel at {x},{y} is {pixel:?}, expected {expected:?}"
+  );
+}
+
+#[tokio::test]
+async fn process_image_environment_composite() {
+  // A red product multiplied onto a grey environment image, over a hard
+  // shadow at half opacity offset by 8 pixels
+  let image = render_png(
+    "solid-red.png",
+    serde_json::json!({
+      "environment_images": [
+        {
+          "id": "grey",
+          "path": "solid-grey.png",
+          "x": 16,
+          "y": 16,
+          "width": 32,
+          "height": 32,
+          "margin_percent": 0,
+          "blend": "multiply",
+          "shadow": {
+            "offset_x": 8,
+            "offset_y": 8,
+            "blur": 0,
+            "opacity": 0.5,
+            "colour": "#000000"
+          }
+        }
+      ]
+    }),
+    serde_json::json!({
+      "environment": "grey",
+      "conditions": { "use_environment_image": true }
+    }),
+  )
+  .await;
+
+  assert_eq!((image.get_width(), image.get_height()), (64, 64));
+  // Environment image
+  assert_pixel(&image, 4, 4, &[128, 128, 128]);
+  // Product multiplied with the environment image
+  assert_pixel(&image, 20, 20, &[128, 0, 0]);
+  // Product over its shadow
+  assert_pixel(&image, 40, 40, &[64, 0, 0]);
+  // Shadow beyond the product
+  assert_pixel(&image, 52, 52, &[64, 64, 64]);
+}
+
+#[tokio::test]
+async fn process_image_environment_pixelated() {
+  // Pixelation covers the environment image, a single block averages the
+  // red product multiplied onto a quarter of the grey environment image
+  let image = render_png(
+    "solid-red.png",
+    serde_json::json!({
+      "environment_images": [
+        {
+          "id": "grey",
+          "path": "solid-grey.png",
+          "x": 16,
+          "y": 16,
+          "width": 32,
+          "height": 32,
+          "margin_percent": 0,
+          "blend": "multiply"
+        }
+      ]
+    }),
+    serde_json::json!({
+      "environment": "grey",
+      "pixelate": 64,
+      "conditions": { "use_environment_image": true }
+    }),
+  )
+  .await;
+
+  assert_eq!((image.get_width(), image.get_height()), (64, 64));
+  assert_pixel(&image, 4, 4, &[128, 96, 96]);
+  assert_pixel(&image, 20, 20, &[128, 96, 96]);
+}
+
+#[tokio::test]
+async fn process_image_adjustment_pixels() {
+  for (source, adjustments, expected) in [
+    // Halves every band
+    (
+      "solid-red.png",
+      serde_json::json!({ "brightness": -50 }),
+      &[128, 0, 0][..],
+    ),
+    // Flat grey in the middle of the range
+    (
+      "solid-red.png",
+      serde_json::json!({ "contrast": -100 }),
+      &[128, 128, 128],
+    ),
+    // Grey of the lightness of red
+    (
+      "solid-red.png",
+      serde_json::json!({ "saturation": -100 }),
+      &[127, 127, 127],
+    ),
+    // 255 * (128 / 255)^(1 / 2)
+    (
+      "solid-grey.png",
+      serde_json::json!({ "gamma": 2 }),
+      &[181, 181, 181],
+    ),
+    // The alpha band is left as is
+    (
+      "solid-red-alpha.png",
+      serde_json::json!({ "brightness": -50 }),
+      &[128, 0, 0, 128],
+    ),
+  ] {
+    let image = render_png(
+      source,
+      serde_json::json!({}),
+      serde_json::json!({ "adjustments": adjustments }),
+    )
+    .await;
+
+    assert_eq!(
+      image.get_bands() as usize,
+      expected.len(),
+      "{source} {adjustments}"
+    );
+    assert_pixel(&image, 16, 16, expected);
+  }
+}
+
+#[tokio::test]
+async fn process_image_effect_pixels() {
+  for (source, conditions, expected) in [
+    // The first column of the sepia matrix times 255
+    (
+      "solid-red.png",
+      serde_json::json!({ "sepia": true }),
+      &[100, 89, 69][..],
+    ),
+    (
+      "solid-red-alpha.png",
+      serde_json::json!({ "sepia": true }),
+      &[100, 89, 69, 128],
+    ),
+    // The lightness of red with the hue of a neutral grey
+    (
+      "solid-red.png",
+      serde_json::json!({ "tint": "#808080" }),
+      &[127, 127, 127],
+    ),
+    (
+      "solid-red-alpha.png",
+      serde_json::json!({ "tint": "#808080" }),
+      &[127, 127, 127, 128],
+    ),
+    // The grey of red, 127, between blue shadows and red highlights
+    (
+      "solid-red.png",
+      serde_json::json!({ "duotone": { "shadows": "#0000ff", "highlights": "#ff0000" } }),
+      &[127, 0, 128],
+    ),
+    (
+      "solid-red-alpha.png",
+      serde_json::json!({ "duotone": { "shadows": "#0000ff", "highlights": "#ff0000" } }),
+      &[127, 0, 128, 128],
+    ),
+  ] {
+    let image = render_png(
+      source,
+      serde_json::json!({}),
+      serde_json::json!({ "conditions": conditions }),
+    )
+    .await;
+
+    assert_eq!(
+      image.get_bands() as usize,
+      expected.len(),
+      "{source} {conditions}"
+    );
+    assert_pixel(&image, 16, 16, expected);
+  }
+}
+
+#[tokio::test]
+async fn process_image_filter_pixels() {
+  // A 16 pixel red square in the middle of a white margin, blurred
+  let image = render_png(
+    "solid-red.png",
+    serde_json::json!({}),
+    serde_json::json!({ "margin_percent": 50, "blur": 2 }),
+  )
+  .await;
+  assert_pixel(&image, 1, 1, &[255, 255, 255]);
+  assert_pixel(&image, 16, 16, &[255, 0, 0]);
+  // The edge of the square is blurred into the margin
+  let edge = pixel(&image, 8, 16);
+  assert!(
+    (32..224).contains(&edge[1]) && edge[1] == edge[2],
+    "unexpected edge {edge:?}"
+  );
+
+  // Each 16 pixel block is a quarter red
+  let image = render_png(
+    "solid-red.png",
+    serde_json::json!({}),
+    serde_json::json!({ "margin_percent": 50, "pixelate": 16 }),
+  )
+  .await;
+  for (x, y) in [(0, 0), (31, 0), (8, 8), (31, 31)] {
+    assert_pixel(&image, x, y, &[255, 191, 191]);
+  }
+
+  // Transparent pixels don't darken their neighbours, and the sharpening of
+  // the 32 pixel source scaled down to 16 keeps a flat colour as it is
+  for config in [
+    serde_json::json!({ "blur": 2 }),
+    serde_json::json!({ "pixelate": 8 }),
+    serde_json::json!({ "size": 16, "sharpen": { "sigma": 1, "amount": 4 } }),
+  ] {
+    for (source, expected) in [
+      ("solid-red.png", &[255, 0, 0][..]),
+      ("solid-red-alpha.png", &[255, 0, 0, 128]),
+    ] {
+      let image = render_png(source, serde_json::json!({}), config.clone()).await;
+
+      assert_eq!(
+        image.get_bands() as usize,
+        expected.len(),
+        "{source} {config}"
+      );
+      assert_pixel(&image, 8, 8, expected);
+    }
+  }
+}
+
+#[tokio::test]
+async fn process_image_content_addressed() {
+  let body =
+    source_request("skaune-portrait.png").replace("output_source", "output_hashed-{hash8}");
+
+  let (status, first) = post_source(body.clone()).await;
+  assert_eq!(status, StatusCode::OK, "unexpected response {first:?}");
+  let hash = first[0]["hash"].as_str().expect("expected a hash");
+  assert_eq!(hash.len(), 64);
+  assert_eq!(
+    first[0]["path"],
+    format!("output_hashed-{}.jpg", &hash[..8])
+  );
+
+  // The identical image isn't uploaded again
+  let (status, second) = post_source(body.clone()).await;
+  assert_eq!(status, StatusCode::OK, "unexpected response {second:?}");
+  assert_eq!(second[0]["hash"], hash);
+  assert_eq!(second[0]["deduplicated"], true);
+
+  // Different metadata has to be stored, so the image is uploaded again
+  let (status, third) = post_source(body.replace(
+    r#""save_original": false,"#,
+    r#""save_original": false, "cache_control": "no-cache","#,
+  ))
+  .await;
+  assert_eq!(status, StatusCode::OK, "unexpected response {third:?}");
+  assert_eq!(third[0]["hash"], hash);
+  assert!(third[0]["deduplicated"].is_null());
+}
+
+#[tokio::test]
+async fn process_image_path_template() {
+  let body = source_request("skaune-portrait.png").replace(
+    r#""path": "output_source","#,
+    r#""path": "templated/{source_basename}-{config_id}-{width}.{format}", "extension": "none","#,
+  );
+  let (status, body) = post_source(body).await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  let path = body[0]["path"].as_str().expect("expected a path");
+  assert!(
+    path.starts_with("templated/skaune-portrait-source-config-") && path.ends_with(".jpg"),
+    "unexpected path {path}"
+  );
+  assert!(
+    fs::try_exists(format!("tests/testdata/{path}"))
+      .await
+      .unwrap()
+  );
+}
+
+#[tokio::test]
+async fn process_image_srcset() {
+  let body = source_request("skaune-portrait.png").replace(
+    r#""path": "output_source","#,
+    r#""path": "output_srcset", "densities": [1, 2],"#,
+  );
+  let (status, body) = post_source(body).await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  let images = body.as_array().expect("expected processed images");
+  assert_eq!(images.len(), 2);
+
+  assert_eq!(images[0]["path"], "output_srcset.jpg");
+  assert_eq!(images[0]["descriptor"], "1x");
+  assert_eq!(
+    images[0]["srcset"],
+    "output_srcset.jpg 1x, output_srcset@2x.jpg 2x"
+  );
+
+  assert_eq!(images[1]["path"], "output_srcset@2x.jpg");
+  assert_eq!(images[1]["alternative_to"], "source-config");
+  // Scaling rounds to whole pixels
+  let width = images[0]["width"].as_i64().unwrap();
+  assert!((images[1]["width"].as_i64().unwrap() - width * 2).abs() <= 1);
+}
+
+#[tokio::test]
+async fn process_image_srcset_small_source() {
+  // The 3x image of a 32 pixel source would be scaled up to 48 pixels
+  let body = source_request("solid-red.png")
+    .replace(
+      r#""save_original": false,"#,
+      r#""save_original": false, "dry_run": true,"#,
+    )
+    .replace(
+      r#""path": "output_source","#,
+      r#""path": "output_srcset_small", "densities": [1, 2, 3],"#,
+    )
+    .replace(r#""aspect": 1.33,"#, r#""aspect": 1.0,"#)
+    .replace(r#""margin_percent": 10,"#, r#""margin_percent": 0,"#)
+    .replace(r#""size": 512,"#, r#""size": 16,"#);
+  let (status, body) = post_source(body).await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  let images = body.as_array().expect("expected processed images");
+  assert_eq!(images.len(), 2, "unexpected images {images:?}");
+  assert_eq!(
+    images[0]["srcset"],
+    "output_srcset_small.jpg 1x, output_srcset_small@2x.jpg 2x"
+  );
+  assert_eq!(images[0]["width"], 16);
+  assert_eq!(images[1]["width"], 32);
+}
+
+#[tokio::test]
+async fn process_image_srcset_environment() {
+  let body = source_request("skaune-portrait.png")
+    .replace(
+      r#""save_original": false,"#,
+      r#""save_original": false, "portrait_environment_image": {
+        "path": "env.png", "width": 172, "height": 235, "x": 164, "y": 32, "margin_percent": 20
+      },"#,
+    )
+    .replace(
+      r#""path": "output_source","#,
+      r#""path": "output_srcset_env", "densities": [1, 2],"#,
+    )
+    .replace(
+      r#""use_environment_image": false"#,
+      r#""use_environment_image": true"#,
     );
+  let (status, body) = post_source(body).await;
+
+  assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+  assert_eq!(
+    body["details"]["errors"][0]["field"],
+    "configurations[0].densities"
+  );
+}
+
+#[tokio::test]
+async fn process_image_from_storage_key_not_found() {
+  let (status, body) = post_source(source_request("does-not-exist.png")).await;
+
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body["code"], "source_not_found");
+}
+
+#[tokio::test]
+async fn process_image_from_storage_key_outside_root() {
+  for source in ["../../Cargo.toml", "/etc/hostname"] {
+    let (status, body) = post_source(source_request(source)).await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{source}");
+    assert_eq!(body["details"]["errors"][0]["field"], "source");
   }
 }
+
+#[tokio::test]
+async fn process_image_from_url() {
+  let origin = axum::Router::new().route(
+    "/skaune-portrait.png",
+    axum::routing::get(|| async {
+      fs::read("tests/testdata/skaune-portrait.png")
+        .await
+        .expect("failed to read file")
+    }),
+  );
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+  tokio::spawn(async move {
+    axum::serve(listener, origin).await.unwrap();
+  });
+
+  let (status, body) = post_source(source_request(&format!(
+    "http://{}/skaune-portrait.png",
+    addr
+  )))
+  .await;
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  assert_eq!(body.as_array().map(Vec::len), Some(1));
+
+  let (status, body) = post_source(source_request(&format!("http://{}/missing.png", addr))).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body["code"], "source_not_found");
+}
+
+#[tokio::test]
+async fn process_image_from_url_host_not_allowed() {
+  let (status, body) = post_source(source_request("https://example.com/image.png")).await;
+
+  assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+  assert_eq!(body["details"]["errors"][0]["field"], "source");
+}
+
+#[tokio::test]
+async fn process_batch() {
+  let router = bootstrap().clone();
+
+  let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+
+  tokio::spawn(async move {
+    axum::serve(listener, router).await.unwrap();
+  });
+
+  // One uploaded source, one from storage and one missing from storage
+  let json_request = r#"{
+    "generate_alternative": false,
+    "portrait_environment_image": {
+      "path": "env.png",
+      "width": 172,
+      "height": 235,
+      "x": 164,
+      "y": 32,
+      "margin_percent": 20
+    },
+    "sources": [
+      { "id": "batch-upload", "path": "output_batch/upload", "image": "image-1" },
+      { "id": "batch-storage", "path": "output_batch/storage", "source": "skaune-portrait.png" },
+      { "id": "batch-missing", "path": "output_batch/missing", "source": "does-not-exist.png" }
+    ],
+    "configurations": [
+      {
+        "id": "batch-env",
+        "path": "env",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 512,
+        "quality": 80,
+        "conditions": {
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": true
+        }
+      }
+    ]
+  }"#;
+
+  let json_part = reqwest::multipart::Part::text(json_request);
+
+  let file = fs::read("tests/testdata/skaune-portrait.png")
+    .await
+    .expect("failed to read file");
+  let file_part = reqwest::multipart::Part::bytes(file)
+    .file_name("skaune-portrait.png")
+    .mime_str("image/png")
+    .unwrap();
+
+  let form = reqwest::multipart::Form::new()
+    .part("image-1", file_part)
+    .part("details", json_part);
+
+  let client = reqwest::Client::new();
+
+  let response = client
+    .post(format!(
+      "http://{}:{}/api/v1/process-batch",
+      addr.ip(),
+      addr.port()
+    ))
+    .header("X-API-Key", "test")
+    .multipart(form)
+    .send()
+    .await
+    .expect("failed to send request");
+
+  assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+  let body = response.text().await.expect("failed to read response");
+  let body: serde_json::Value = serde_json::from_str(&body).expect("failed to parse response");
+  let results = body["results"].as_array().expect("expected results");
+
+  let outcomes: Vec<(&str, &str)> = results
+    .iter()
+    .map(|result| {
+      (
+        result["id"].as_str().unwrap_or(""),
+        result["status"].as_str().unwrap_or(""),
+      )
+    })
+    .collect();
+  assert_eq!(
+    outcomes,
+    vec![
+      ("batch-upload", "succeeded"),
+      ("batch-storage", "succeeded"),
+      ("batch-missing", "failed"),
+    ]
+  );
+
+  assert_eq!(
+    results[0]["images"][0]["path"],
+    "output_batch/upload/env.jpg"
+  );
+  assert_eq!(results[2]["error"]["code"], "source_not_found");
+}
+
+/// A request with one configuration that succeeds and one whose upload fails,
+/// as its target path is taken by a directory
+async fn post_partial_failure(
+  failure_mode: &str,
+  ok_path: &str,
+) -> (StatusCode, serde_json::Value) {
+  fs::create_dir_all("tests/testdata/output_blocked.jpg")
+    .await
+    .expect("failed to create directory");
+
+  let body = format!(
+    r#"{{
+    "id": "partial-original",
+    "path": "output",
+    "source": "skaune-portrait.png",
+    "save_original": false,
+    "generate_alternative": false,
+    "failure_mode": "{}",
+    "configurations": [
+      {{
+        "id": "partial-ok",
+        "path": "{}",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 256,
+        "quality": 80,
+        "conditions": {{
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }}
+      }},
+      {{
+        "id": "partial-blocked",
+        "path": "output_blocked",
+        "aspect": 1.33,
+        "margin_percent": 10,
+        "size": 256,
+        "quality": 80,
+        "conditions": {{
+          "allow_vector": false,
+          "transparent": false,
+          "trim": false,
+          "black_and_white": false,
+          "use_environment_image": false
+        }}
+      }}
+    ]
+  }}"#,
+    failure_mode, ok_path
+  );
+
+  post_source(body).await
+}
+
+#[tokio::test]
+async fn process_image_best_effort() {
+  let (status, body) = post_partial_failure("best_effort", "output_best_effort").await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+
+  let images = body["images"].as_array().expect("expected images");
+  assert_eq!(images.len(), 1);
+  assert_eq!(images[0]["id"], "partial-ok");
+
+  let configurations = body["configurations"]
+    .as_array()
+    .expect("expected configurations");
+  assert_eq!(configurations[0]["status"], "succeeded");
+  assert_eq!(configurations[1]["id"], "partial-blocked");
+  assert_eq!(configurations[1]["status"], "failed");
+  assert_eq!(configurations[1]["error"], "upload failed");
+}
+
+#[tokio::test]
+async fn process_image_best_effort_partial_configuration() {
+  // The WebP alternative can't be written, the JPEG can
+  fs::create_dir_all("tests/testdata/output_half_blocked.webp")
+    .await
+    .expect("failed to create directory");
+
+  let body = source_request("skaune-portrait.png")
+    .replace(
+      r#""save_original": false,"#,
+      r#""save_original": false, "failure_mode": "best_effort","#,
+    )
+    .replace(
+      r#""generate_alternative": false"#,
+      r#""generate_alternative": true"#,
+    )
+    .replace(
+      r#""path": "output_source","#,
+      r#""path": "output_half_blocked", "format": "jpeg", "alternatives": ["webp"],"#,
+    );
+  let (status, body) = post_source(body).await;
+
+  assert_eq!(status, StatusCode::OK, "unexpected response {body:?}");
+  assert_eq!(body["configurations"][0]["status"], "failed");
+  assert_eq!(body["images"].as_array().map(Vec::len), Some(0));
+
+  // The JPEG of the failed configuration was removed again
+  assert!(
+    !fs::try_exists("tests/testdata/output_half_blocked.jpg")
+      .await
+      .unwrap()
+  );
+}
+
+#[tokio::test]
+async fn process_image_all_or_nothing() {
+  let (status, body) = post_partial_failure("all_or_nothing", "output_all_or_nothing").await;
+
+  assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+  assert_eq!(body["code"], "processing_failed");
+
+  let configurations = body["details"]["configurations"]
+    .as_array()
+    .expect("expected configurations");
+  assert_eq!(configurations[0]["status"], "cancelled");
+  assert_eq!(configurations[1]["status"], "failed");
+
+  // The image of the successful configuration was removed again
+  assert!(
+    !fs::try_exists("tests/testdata/output_all_or_nothing.jpg")
+      .await
+      .unwrap()
+  );
+}